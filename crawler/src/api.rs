@@ -0,0 +1,384 @@
+use crate::metrics::Metrics;
+use anyhow::Result;
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use danmu_client::{AccountInfo, ClientPool};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+// crawler 运行状态, 由持有 ClientPool 的任务更新
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CrawlerStatus {
+    pub accounts: Vec<AccountInfo>,
+    pub rooms: BTreeMap<u64, String>,
+}
+
+pub type SharedStatus = Arc<RwLock<CrawlerStatus>>;
+
+impl CrawlerStatus {
+    pub fn update(status: &SharedStatus, pool: &ClientPool) {
+        let rooms = pool
+            .states()
+            .into_iter()
+            .map(|(room_id, state)| (room_id, state.to_string()))
+            .collect();
+        *status.write().unwrap() = CrawlerStatus {
+            accounts: pool.accounts(),
+            rooms,
+        };
+    }
+
+    // 输出账号状态, 健康检查后调用
+    pub fn log_accounts(&self) {
+        for account in &self.accounts {
+            if account.status.is_usable() {
+                info!(
+                    "账号 {} ({}) {:?}, 房间 {:?}",
+                    account.uid, account.uname, account.status, account.rooms
+                );
+            } else {
+                warn!(
+                    "账号 {} ({}) {:?}, 房间 {:?}",
+                    account.uid, account.uname, account.status, account.rooms
+                );
+            }
+        }
+    }
+}
+
+// 房间运行状态, 由持有 ClientPool 的任务在收到 ListRooms 时生成
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomStatus {
+    pub room_id: u64,
+    pub label: String,
+    pub state: String,
+    pub paused: bool,
+    pub buffered: usize, // 尚未写入存储的消息数
+    pub dropped: u64,    // 队列满时丢弃的消息数
+    pub spilled: u64,    // 队列满时写入磁盘的消息数
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    AddRoom { room_id: u64, label: String },
+    RemoveRoom { room_id: u64 },
+    Pause { room_id: u64 },
+    Resume { room_id: u64 },
+    Flush { room_id: Option<u64> }, // None 表示所有房间
+}
+
+// 控制接口发给持有 ClientPool 的任务的命令
+#[derive(Debug)]
+pub enum Command {
+    ListRooms(oneshot::Sender<Vec<RoomStatus>>),
+    Control(Action, oneshot::Sender<Result<(), String>>),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlResponse {
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddRoomRequest {
+    pub room_id: u64,
+    #[serde(default)]
+    pub label: String,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    status: SharedStatus,
+    commands: mpsc::Sender<Command>,
+}
+
+pub fn router(status: SharedStatus, commands: mpsc::Sender<Command>) -> Router {
+    Router::new()
+        .route("/status", get(get_status))
+        .route("/rooms", get(list_rooms).post(add_room))
+        .route("/rooms/:room_id", delete(remove_room))
+        .route("/rooms/:room_id/pause", post(pause_room))
+        .route("/rooms/:room_id/resume", post(resume_room))
+        .route("/rooms/:room_id/flush", post(flush_room))
+        .route("/flush", post(flush_all))
+        .with_state(ApiState { status, commands })
+}
+
+// 只监听本地地址, 控制接口没有鉴权
+pub async fn serve(
+    addr: &str,
+    status: SharedStatus,
+    commands: mpsc::Sender<Command>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("状态接口监听 {}", listener.local_addr()?);
+    axum::serve(listener, router(status, commands)).await?;
+    Ok(())
+}
+
+// prometheus 指标单独监听, 可以开放给采集端而不暴露控制接口
+pub fn metrics_router(metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route("/metrics", get(get_metrics))
+        .with_state(metrics)
+}
+
+pub async fn serve_metrics(addr: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("指标接口监听 {}", listener.local_addr()?);
+    axum::serve(listener, metrics_router(metrics)).await?;
+    Ok(())
+}
+
+async fn get_status(State(state): State<ApiState>) -> Json<CrawlerStatus> {
+    Json(state.status.read().unwrap().clone())
+}
+
+// 直接读取共享的指标, 不经过消息分发任务
+async fn get_metrics(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    match metrics.render() {
+        Ok(metrics) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics,
+        ),
+        Err(e) => {
+            error!("输出指标出错: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_TYPE, "text/plain")],
+                e.to_string(),
+            )
+        }
+    }
+}
+
+async fn list_rooms(State(state): State<ApiState>) -> Json<Vec<RoomStatus>> {
+    let (tx, rx) = oneshot::channel();
+    if state.commands.send(Command::ListRooms(tx)).await.is_err() {
+        return Json(vec![]);
+    }
+    Json(rx.await.unwrap_or_default())
+}
+
+async fn control(state: &ApiState, action: Action) -> Json<ControlResponse> {
+    let (tx, rx) = oneshot::channel();
+    let result = match state.commands.send(Command::Control(action, tx)).await {
+        Ok(()) => rx
+            .await
+            .unwrap_or_else(|_| Err("crawler is shutting down".to_string())),
+        Err(_) => Err("crawler is shutting down".to_string()),
+    };
+    Json(match result {
+        Ok(()) => ControlResponse {
+            code: 0,
+            message: "success".to_string(),
+        },
+        Err(message) => ControlResponse { code: -1, message },
+    })
+}
+
+async fn add_room(
+    State(state): State<ApiState>,
+    Json(req): Json<AddRoomRequest>,
+) -> Json<ControlResponse> {
+    let action = Action::AddRoom {
+        room_id: req.room_id,
+        label: req.label,
+    };
+    control(&state, action).await
+}
+
+async fn remove_room(
+    State(state): State<ApiState>,
+    Path(room_id): Path<u64>,
+) -> Json<ControlResponse> {
+    control(&state, Action::RemoveRoom { room_id }).await
+}
+
+async fn pause_room(
+    State(state): State<ApiState>,
+    Path(room_id): Path<u64>,
+) -> Json<ControlResponse> {
+    control(&state, Action::Pause { room_id }).await
+}
+
+async fn resume_room(
+    State(state): State<ApiState>,
+    Path(room_id): Path<u64>,
+) -> Json<ControlResponse> {
+    control(&state, Action::Resume { room_id }).await
+}
+
+async fn flush_room(
+    State(state): State<ApiState>,
+    Path(room_id): Path<u64>,
+) -> Json<ControlResponse> {
+    let action = Action::Flush {
+        room_id: Some(room_id),
+    };
+    control(&state, action).await
+}
+
+async fn flush_all(State(state): State<ApiState>) -> Json<ControlResponse> {
+    control(&state, Action::Flush { room_id: None }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_status() {
+        let pool = ClientPool::with_accounts(&[
+            "DedeUserID=10000; buvid3=test-buvid",
+            "DedeUserID=10001; buvid3=test-buvid",
+        ])
+        .unwrap();
+        let status = SharedStatus::default();
+        CrawlerStatus::update(&status, &pool);
+
+        let (tx, _rx) = mpsc::channel(1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { axum::serve(listener, router(status, tx)).await });
+        let body: serde_json::Value = reqwest::get(format!("http://{}/status", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["accounts"][0]["uid"], 10000);
+        assert_eq!(body["accounts"][1]["status"]["status"], "unchecked");
+        assert_eq!(body["rooms"], serde_json::json!({}));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_control() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let actions = tokio::spawn(async move {
+            let mut actions = vec![];
+            while let Some(command) = rx.recv().await {
+                match command {
+                    Command::ListRooms(reply) => {
+                        let _ = reply.send(vec![RoomStatus {
+                            room_id: 1,
+                            label: "one".to_string(),
+                            state: "connected".to_string(),
+                            paused: false,
+                            buffered: 3,
+                            dropped: 0,
+                            spilled: 0,
+                        }]);
+                    }
+                    Command::Control(action, reply) => {
+                        let result = match action {
+                            Action::RemoveRoom { room_id: 2 } => {
+                                Err("room 2 not found".to_string())
+                            }
+                            _ => Ok(()),
+                        };
+                        let _ = reply.send(result);
+                        actions.push(action);
+                    }
+                }
+            }
+            actions
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(SharedStatus::default(), tx);
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+        let base = format!("http://{}", addr);
+
+        let rooms: Vec<RoomStatus> = client
+            .get(format!("{}/rooms", base))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(rooms[0].buffered, 3);
+
+        // 指标不在控制接口上提供
+        let resp = client
+            .get(format!("{}/metrics", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let add = AddRoomRequest {
+            room_id: 1,
+            label: "one".to_string(),
+        };
+        let requests = [
+            client.post(format!("{}/rooms", base)).json(&add),
+            client.post(format!("{}/rooms/1/pause", base)),
+            client.post(format!("{}/rooms/1/resume", base)),
+            client.post(format!("{}/rooms/1/flush", base)),
+            client.post(format!("{}/flush", base)),
+            client.delete(format!("{}/rooms/2", base)),
+        ];
+        let mut codes = vec![];
+        for request in requests {
+            let resp: ControlResponse = request.send().await.unwrap().json().await.unwrap();
+            codes.push(resp.code);
+        }
+        assert_eq!(codes, vec![0, 0, 0, 0, 0, -1]);
+
+        // 关闭连接后 router 持有的发送端才会全部释放
+        drop(client);
+        server.abort();
+        let _ = server.await;
+        assert_eq!(
+            actions.await.unwrap(),
+            vec![
+                Action::AddRoom {
+                    room_id: 1,
+                    label: "one".to_string()
+                },
+                Action::Pause { room_id: 1 },
+                Action::Resume { room_id: 1 },
+                Action::Flush { room_id: Some(1) },
+                Action::Flush { room_id: None },
+                Action::RemoveRoom { room_id: 2 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        metrics.reconnects.with_label_values(&["1"]).inc();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = metrics_router(metrics);
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let resp = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap();
+        assert!(resp.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        assert!(resp
+            .text()
+            .await
+            .unwrap()
+            .contains(r#"crawler_reconnects_total{room="1"} 1"#));
+        server.abort();
+    }
+}
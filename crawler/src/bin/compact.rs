@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Local};
+use crawler::compaction::compact;
+use crawler::s3::S3Client;
+use duckdb::Connection;
+use log::{error, info};
+use utils::location::StorageLocation;
+use utils::rooms::RoomsConfig;
+use utils::utils::{get_enter_table_name, get_gap_table_name, get_local_midnight, get_table_name};
+
+// 合并已收盘日期的 part 文件, 定时在凌晨运行: compact yesterday 或 compact someday <timestamp>
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    pretty_env_logger::init_timed();
+    let args: Vec<String> = std::env::args().collect();
+    let timestamp = match args.get(1).map(String::as_str) {
+        Some("yesterday") => Local::now().timestamp() - Duration::days(1).num_seconds(),
+        Some("someday") => args
+            .get(2)
+            .ok_or_else(|| anyhow!("please input timestamp"))?
+            .parse::<i64>()?,
+        _ => return Err(anyhow!("scope must be yesterday or someday")),
+    };
+    let day = get_local_midnight(timestamp)?;
+    // 当天的文件仍在写入
+    if day >= get_local_midnight(Local::now().timestamp())? {
+        return Err(anyhow!("day {} is not closed yet", day));
+    }
+
+    let location = StorageLocation::from_env()?;
+    let s3 = match &location {
+        StorageLocation::S3(config) => Some(S3Client::new(config)?),
+        StorageLocation::Local(_) => None,
+    };
+    let conn = Connection::open_in_memory()?;
+    location.init_conn(&conn)?;
+    let root = location.root();
+
+    // 包括已停用的房间, 停用前可能还有未合并的 part
+    let mut failed = 0;
+    for room_id in RoomsConfig::from_env()?.all_room_ids() {
+        let targets = [
+            get_table_name(&root, room_id, day)?,
+            get_enter_table_name(&root, room_id, day)?,
+            get_gap_table_name(&root, room_id, day)?,
+        ];
+        for target in targets {
+            let parts = match compact(&conn, &target) {
+                Ok(parts) => parts,
+                Err(e) => {
+                    error!("合并 {} 出错: {:?}", target, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+            for part in parts {
+                let result = match &s3 {
+                    Some(s3) => s3.delete(&part).await,
+                    None => std::fs::remove_file(&part).map_err(Into::into),
+                };
+                if let Err(e) = result {
+                    error!("删除 {} 出错: {:?}", part, e);
+                    failed += 1;
+                }
+            }
+        }
+        info!("compact room {} done", room_id);
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} errors during compaction", failed));
+    }
+    Ok(())
+}
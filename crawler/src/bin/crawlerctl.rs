@@ -0,0 +1,96 @@
+use anyhow::{anyhow, Result};
+use crawler::api::{AddRoomRequest, ControlResponse, RoomStatus};
+
+const USAGE: &str = "usage: crawlerctl <command>
+
+commands:
+    rooms                   列出房间, 连接状态和缓存的消息数
+    status                  账号和连接状态
+    add <room_id> [label]   添加房间
+    remove <room_id>        删除房间
+    pause <room_id>         暂停存储房间消息, 保持连接
+    resume <room_id>        恢复存储房间消息
+    flush [room_id]         立即写出缓存的消息, 不指定房间时写出所有房间
+
+CRAWLER_ADDR 指定 crawler 控制接口地址, 默认为 127.0.0.1:8081";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let addr = std::env::var("CRAWLER_ADDR").unwrap_or_else(|_| "127.0.0.1:8081".to_string());
+    let base = format!("http://{}", addr);
+    let client = reqwest::Client::new();
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let request = match args[..] {
+        ["rooms"] => {
+            let rooms: Vec<RoomStatus> = client
+                .get(format!("{}/rooms", base))
+                .send()
+                .await?
+                .json()
+                .await?;
+            println!(
+                "{:<12} {:<16} {:<14} {:<7} {:>8} {:>8} {:>8}",
+                "ROOM", "LABEL", "STATE", "PAUSED", "BUFFERED", "DROPPED", "SPILLED"
+            );
+            for room in rooms {
+                println!(
+                    "{:<12} {:<16} {:<14} {:<7} {:>8} {:>8} {:>8}",
+                    room.room_id,
+                    room.label,
+                    room.state,
+                    room.paused,
+                    room.buffered,
+                    room.dropped,
+                    room.spilled
+                );
+            }
+            return Ok(());
+        }
+        ["status"] => {
+            let status = client
+                .get(format!("{}/status", base))
+                .send()
+                .await?
+                .text()
+                .await?;
+            println!("{}", status);
+            return Ok(());
+        }
+        ["add", room_id, ref label @ ..] if label.len() <= 1 => client
+            .post(format!("{}/rooms", base))
+            .json(&AddRoomRequest {
+                room_id: parse_room_id(room_id)?,
+                label: label.first().unwrap_or(&"").to_string(),
+            }),
+        ["remove", room_id] => client.delete(format!("{}/rooms/{}", base, parse_room_id(room_id)?)),
+        ["pause", room_id] => {
+            client.post(format!("{}/rooms/{}/pause", base, parse_room_id(room_id)?))
+        }
+        ["resume", room_id] => {
+            client.post(format!("{}/rooms/{}/resume", base, parse_room_id(room_id)?))
+        }
+        ["flush"] => client.post(format!("{}/flush", base)),
+        ["flush", room_id] => {
+            client.post(format!("{}/rooms/{}/flush", base, parse_room_id(room_id)?))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let resp: ControlResponse = request.send().await?.json().await?;
+    if resp.code != 0 {
+        return Err(anyhow!(resp.message));
+    }
+    println!("ok");
+    Ok(())
+}
+
+fn parse_room_id(room_id: &str) -> Result<u64> {
+    room_id
+        .parse()
+        .map_err(|_| anyhow!("invalid room id: {}", room_id))
+}
@@ -1,35 +1,68 @@
 use anyhow::{anyhow, Result};
-use base64::Engine;
 use chrono::Utc;
+use crawler::api::{self, Action, Command, CrawlerStatus, RoomStatus, SharedStatus};
+use crawler::config::{assign_proxies, store_kind, RoomsWatcher};
+use crawler::credential::{credential_paths, load_credentials, CredentialWatcher};
+use crawler::metrics::Metrics;
+use crawler::sink::{JsonlSink, Sinks, StdoutSink, WebhookSink};
 use crawler::storage::Storage;
-use danmu_client::danmu::Client;
+use crawler::supervisor::RestartBudget;
+use danmu_client::queue::DEFAULT_CAPACITY;
+use danmu_client::{ClientEvent, ClientPool, OverflowPolicy, Proxy};
 use duckdb::Connection;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use parse::Message;
-use std::process::exit;
-use std::time::Duration;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
 use tokio::signal;
-use tokio::sync::watch;
-use tokio::task::LocalSet;
-use tokio::time::sleep;
-use utils::utils::{get_rooms, is_new_day};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinSet;
+use tokio::time::{self, sleep, MissedTickBehavior};
+use utils::rooms::{
+    default_sinks, default_store, FlushConfig, RoomConfig, RoomsConfig, SinkConfig,
+};
+use utils::utils::Gap;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
     pretty_env_logger::init_timed();
-    let cookies = String::from_utf8(
-        base64::engine::general_purpose::STANDARD.decode(std::env::var("BILI_COOKIE")?)?,
-    )?;
-    // let cookies = std::env::var("BILI_COOKIE")?;
-    debug!("{}", cookies);
+    // 凭据文件由 login 命令扫码生成, 或首次启动时由 BILI_COOKIE 生成, 刷新后写回
+    let credential_paths = credential_paths();
+    let credentials = load_credentials(&credential_paths)?;
+    let accounts: Vec<String> = credentials.iter().map(|c| c.cookies.clone()).collect();
+    // 各账号的凭据更新合并到同一个 channel
+    let (cookie_tx, mut cookie_rx) = mpsc::channel::<String>(16);
+    for (path, credential) in credential_paths.into_iter().zip(credentials) {
+        let (tx, mut rx) = watch::channel(credential.cookies.clone());
+        tokio::spawn(CredentialWatcher::new(path, credential)?.run(tx));
+        let cookie_tx = cookie_tx.clone();
+        tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                let cookies = rx.borrow_and_update().clone();
+                if cookie_tx.send(cookies).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
 
-    let room_ids = get_rooms(); // 传入多个 room_id
+    // 房间列表来自 ROOMS_FILE, 运行时修改会自动启停房间
+    let rooms_path = RoomsConfig::path();
+    let rooms_config = RoomsConfig::from_env()?;
+    let room_ids = rooms_config.room_ids();
     info!("获取到 {} 个 room {:?}", room_ids.len(), room_ids);
+    let (rooms_tx, mut rooms_rx) = watch::channel(rooms_config.clone());
+    tokio::spawn(RoomsWatcher::new(rooms_path).run(rooms_tx));
 
-    let local_set = LocalSet::new();
-
-    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let (shutdown_tx, _) = watch::channel(());
     let main_shutdown_tx = shutdown_tx.clone();
 
     let shutdown_signal = tokio::spawn(async move {
@@ -49,37 +82,145 @@ async fn main() -> Result<()> {
     });
     info!("启动监听信号");
 
-    local_set
-        .run_until(async move {
-            let mut tasks = Vec::new();
-
-            for room_id in room_ids {
-                info!("开始启动 room_id: {}", room_id);
-                let cookies = cookies.clone();
-                let conn = match Connection::open_in_memory() {
-                    Ok(conn) => conn,
-                    Err(e) => {
-                        error!("Error opening connection: {:?}", e);
-                        return;
+    let mut pool = ClientPool::with_accounts(&accounts)?;
+    // 存储处理不过来时把消息写入本地磁盘, 避免阻塞 socket 读取导致被服务器断开
+    if let Ok(dir) = std::env::var("DANMU_SPILL_DIR") {
+        info!("消息溢出时写入 {}", dir);
+        pool.set_overflow_policy(
+            DEFAULT_CAPACITY,
+            OverflowPolicy::SpillToDisk { dir: dir.into() },
+        );
+    }
+
+    // 房间分散到多个代理, 避免同一 IP 请求 getDanmuInfo 被限流
+    let proxy_spec = std::env::var("DANMU_PROXIES").unwrap_or_default();
+    // 提前检查代理配置, 新增房间时再分配
+    assign_proxies(&proxy_spec, &[])?;
+
+    // 各房间的写入在单独的线程中执行, 分发任务和 socket 读取运行在多线程 runtime 上
+    let metrics = Arc::new(Metrics::new()?);
+    // 超出重启预算的房间通过 failed_rx 通知分发任务停止
+    let (failed_tx, mut failed_rx) = mpsc::unbounded_channel();
+    let mut rooms = Rooms::new(proxy_spec, shutdown_tx.clone(), failed_tx, metrics.clone());
+    rooms.apply(&mut pool, &rooms_config)?;
+
+    // 指标单独监听, 由分发任务定时从 pool 同步连接相关的指标
+    let metrics_addr =
+        std::env::var("METRICS_ADDR").unwrap_or_else(|_| "127.0.0.1:9464".to_string());
+    tokio::spawn(async move {
+        if let Err(e) = api::serve_metrics(&metrics_addr, metrics).await {
+            error!("指标接口出错: {:?}", e);
+        }
+    });
+    let mut metrics_ticker = time::interval(METRICS_SYNC_INTERVAL);
+    metrics_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // 账号状态和房间连接状态
+    let status = SharedStatus::default();
+    CrawlerStatus::update(&status, &pool);
+    let status_addr =
+        std::env::var("CRAWLER_ADDR").unwrap_or_else(|_| "127.0.0.1:8081".to_string());
+    let server_status = status.clone();
+    // 控制接口的命令由消息分发任务处理
+    let (command_tx, mut command_rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        if let Err(e) = api::serve(&status_addr, server_status, command_tx).await {
+            error!("状态接口出错: {:?}", e);
+        }
+    });
+
+    // 定期检查账号是否有效, 在单独的任务中请求, 不阻塞消息分发
+    let mut check_ticker = time::interval(ACCOUNT_CHECK_INTERVAL);
+    let (check_tx, mut check_rx) = mpsc::channel(1);
+
+    // 写入线程处理不过来时暂存的消息定时重新发送
+    let mut overflow_ticker = time::interval(OVERFLOW_DRAIN_INTERVAL);
+    overflow_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // 将 pool 合并后的消息分发到各个房间
+    let mut dispatch_shutdown_rx = shutdown_tx.subscribe();
+    let dispatcher = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some((room_id, event)) = pool.recv_event() => {
+                    let message = match event {
+                        ClientEvent::Message(message) => message,
+                        event => {
+                            log_coverage(room_id, &event);
+                            if let ClientEvent::Reconnecting { .. } = event {
+                                rooms.metrics.reconnects
+                                    .with_label_values(&[&room_id.to_string()])
+                                    .inc();
+                            }
+                            rooms.track_gap(room_id, &event);
+                            CrawlerStatus::update(&status, &pool);
+                            continue;
+                        }
+                    };
+                    rooms.dispatch(room_id, message);
+                },
+                _ = overflow_ticker.tick() => rooms.drain_all(),
+                _ = metrics_ticker.tick() => rooms.sync_metrics(&pool),
+                Some(command) = command_rx.recv() => {
+                    match command {
+                        Command::ListRooms(reply) => {
+                            let _ = reply.send(rooms.list(&pool));
+                        }
+                        Command::Control(Action::Flush { room_id }, reply) => {
+                            rooms.flush(room_id, reply);
+                        }
+                        Command::Control(action, reply) => {
+                            let _ = reply.send(rooms.control(&mut pool, action));
+                            CrawlerStatus::update(&status, &pool);
+                        }
                     }
-                };
-                let shutdown_rx = shutdown_rx.clone();
-                let err_shutdown_tx = shutdown_tx.clone();
-                let task = tokio::task::spawn_local(async move {
-                    if let Err(e) = process_room(room_id, cookies, conn, shutdown_rx).await {
-                        error!("Error processing room {}: {:?}", room_id, e);
-                        let _ = err_shutdown_tx.send(());
-                        sleep(Duration::from_secs(30)).await;
-                        exit(0);
+                },
+                Some(room_id) = failed_rx.recv() => {
+                    rooms.fail(&mut pool, room_id);
+                    CrawlerStatus::update(&status, &pool);
+                },
+                Ok(()) = rooms_rx.changed() => {
+                    let config = rooms_rx.borrow_and_update().clone();
+                    if let Err(e) = rooms.apply(&mut pool, &config) {
+                        error!("更新房间配置出错: {:?}", e);
+                    }
+                    CrawlerStatus::update(&status, &pool);
+                },
+                Some(cookies) = cookie_rx.recv() => {
+                    if let Err(e) = pool.set_cookies(&cookies) {
+                        error!("更新 cookie 出错: {:?}", e);
                     }
-                });
-                tasks.push(task);
+                    CrawlerStatus::update(&status, &pool);
+                },
+                _ = check_ticker.tick() => {
+                    let checker = pool.account_checker();
+                    let check_tx = check_tx.clone();
+                    tokio::spawn(async move {
+                        let _ = check_tx.send(checker.check().await).await;
+                    });
+                },
+                Some(results) = check_rx.recv() => {
+                    if let Err(e) = pool.apply_checks(results) {
+                        error!("切换账号出错: {:?}", e);
+                    }
+                    CrawlerStatus::update(&status, &pool);
+                    status.read().unwrap().log_accounts();
+                },
+                _ = dispatch_shutdown_rx.changed() => {
+                    info!("收到 shutdown 信号，关闭所有连接");
+                    break;
+                },
             }
+        }
+        // drop pool 关闭所有连接, 再等待各房间写出剩余数据
+        drop(pool);
+        rooms.join().await;
+    });
 
-            // 等待所有任务完成
-            futures::future::join_all(tasks).await;
-        })
-        .await;
+    // 等待所有任务完成
+    if let Err(e) = dispatcher.await {
+        error!("消息分发任务出错: {:?}", e);
+    }
 
     // 等待终止信号任务完成
     shutdown_signal.await??;
@@ -87,62 +228,989 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn process_room(
-    room_id: i64,
-    cookies: String,
-    conn: Connection,
-    mut shutdown_rx: watch::Receiver<()>,
-) -> Result<()> {
-    let mut start_time = Utc::now();
+const ACCOUNT_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// 每个房间最多暂存的消息数, 超出时丢弃最早的消息
+const MAX_OVERFLOW: usize = 100_000;
+const OVERFLOW_DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+const METRICS_SYNC_INTERVAL: Duration = Duration::from_secs(5);
 
-    // 创建 Storage 实例
-    let mut storage = Storage::new(&conn, room_id, start_time.timestamp())?;
+// 发给房间写入线程的消息和命令
+enum RoomInput {
+    Message(Message),
+    Flush(oneshot::Sender<Result<(), String>>),
+    // 连接断开到重新认证之间没有数据的时段
+    Gap(Gap),
+    // 写入线程定时检查 flush 策略, 不由分发任务发送
+    Tick,
+}
 
-    let client = Client::new(room_id as u64, &cookies)?;
-    let mut rx = client.listen().await?;
+// 正在爬取的房间, 房间配置或控制接口变化时启停
+struct Rooms {
+    config: RoomsConfig,
+    // 通过控制接口增删的房间, 配置文件更新后仍然生效
+    added: BTreeMap<u64, RoomConfig>,
+    removed: HashSet<u64>,
+    paused: HashSet<u64>,
+    configs: HashMap<u64, RoomConfig>,
+    txs: HashMap<u64, mpsc::Sender<RoomInput>>,
+    // 写入线程的 channel 已满时暂存的消息和断线时段, 分发不等待单个房间
+    overflow: HashMap<u64, VecDeque<RoomInput>>,
+    buffered: HashMap<u64, Arc<AtomicUsize>>,
+    // 连接断开的时间和原因, 重新认证后作为断线时段写入
+    down_since: HashMap<u64, (i64, String)>,
+    metrics: Arc<Metrics>,
+    tasks: JoinSet<()>,
+    proxy_spec: String,
+    shutdown_tx: watch::Sender<()>,
+    failed_tx: mpsc::UnboundedSender<u64>,
+}
 
-    info!("开始监听 room_id: {}", room_id);
+impl Rooms {
+    fn new(
+        proxy_spec: String,
+        shutdown_tx: watch::Sender<()>,
+        failed_tx: mpsc::UnboundedSender<u64>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            config: RoomsConfig {
+                store: default_store(),
+                sinks: default_sinks(),
+                flush: FlushConfig::default(),
+                rooms: vec![],
+            },
+            added: BTreeMap::new(),
+            removed: HashSet::new(),
+            paused: HashSet::new(),
+            configs: HashMap::new(),
+            txs: HashMap::new(),
+            overflow: HashMap::new(),
+            buffered: HashMap::new(),
+            down_since: HashMap::new(),
+            metrics,
+            tasks: JoinSet::new(),
+            proxy_spec,
+            shutdown_tx,
+            failed_tx,
+        }
+    }
 
-    loop {
-        tokio::select! {
-            message = rx.recv() => {
-                if let Some(message) = message {
-                    let now = Utc::now();
-                    if is_new_day(start_time.timestamp(), now.timestamp())? {
-                        start_time = now;
-                        storage.switch_new_date(now.timestamp())?;
-                        info!("切换到新的日期: {}", now);
-                    }
-                    match message {
-                        Message::Danmu(msg) => {
-                            storage.create_danmu_message(msg)?;
-                        }
-                        Message::EnterRoom(_) => {}
-                        Message::OnlineCount(_) => {}
-                        Message::SuperChat(msg) => {
-                            storage.create_super_chat_message(msg)?;
-                        }
-                        Message::BlockUser(msg) => {
-                            storage.create_block_user_message(msg)?;
-                        }
-                        Message::Default => {},
+    fn apply(&mut self, pool: &mut ClientPool, config: &RoomsConfig) -> Result<()> {
+        self.config.clone_from(config);
+        self.reconcile(pool)
+    }
+
+    // 配置文件中启用的房间加上控制接口的增删
+    fn wanted(&self) -> Vec<RoomConfig> {
+        let mut rooms: Vec<RoomConfig> = self
+            .config
+            .enabled()
+            .filter(|room| !self.removed.contains(&room.id))
+            .cloned()
+            .collect();
+        for room in self.added.values() {
+            if !rooms.iter().any(|r| r.id == room.id) {
+                rooms.push(room.clone());
+            }
+        }
+        rooms
+    }
+
+    // 停止已删除或停用的房间, 启动新增的房间
+    fn reconcile(&mut self, pool: &mut ClientPool) -> Result<()> {
+        let wanted = self.wanted();
+        let stopped: Vec<u64> = self
+            .configs
+            .keys()
+            .filter(|room_id| !wanted.iter().any(|room| room.id == **room_id))
+            .copied()
+            .collect();
+        for room_id in stopped {
+            self.stop(pool, room_id);
+        }
+        let room_ids: Vec<u64> = wanted.iter().map(|room| room.id).collect();
+        let mut proxies = assign_proxies(&self.proxy_spec, &room_ids)?;
+        for room in wanted {
+            if let Some(current) = self.configs.get_mut(&room.id) {
+                if *current != room {
+                    info!("房间 {} 配置已更新: {:?}", room.id, room);
+                    if current.sinks != room.sinks || current.flush != room.flush {
+                        warn!("房间 {} 的 sinks 和 flush 需要重启房间后生效", room.id);
                     }
+                    *current = room;
+                }
+                continue;
+            }
+            let room_id = room.id;
+            if let Err(e) = self.start(pool, room, proxies.remove(&room_id)) {
+                error!("启动房间 {} 出错: {:?}", room_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn start(
+        &mut self,
+        pool: &mut ClientPool,
+        room: RoomConfig,
+        proxy: Option<Proxy>,
+    ) -> Result<()> {
+        info!("开始启动 room_id: {} {}", room.id, room.label);
+        pool.add_room_with_proxy(room.id, proxy)?;
+        let (room_tx, room_rx) = mpsc::channel(1024);
+        let room_id = room.id as i64;
+        let configs = room.sinks.clone();
+        let flush = room.flush;
+        let buffered = Arc::new(AtomicUsize::new(0));
+        let task_buffered = buffered.clone();
+        let metrics = self.metrics.clone();
+        let sink_metrics = self.metrics.clone();
+        let open = open_sinks_with(move |conn, timestamp| {
+            open_sinks(conn, room_id, &configs, &flush, timestamp, &sink_metrics)
+        });
+        let shutdown_rx = self.shutdown_tx.subscribe();
+        let failed_tx = self.failed_tx.clone();
+        self.tasks.spawn(async move {
+            let supervised = supervise_room(
+                room_id,
+                open,
+                room_rx,
+                task_buffered,
+                metrics,
+                shutdown_rx,
+                RestartBudget::new(MAX_ROOM_FAILURES, RESTART_WINDOW),
+            );
+            if !supervised.await {
+                let _ = failed_tx.send(room_id as u64);
+            }
+        });
+        self.txs.insert(room.id, room_tx);
+        self.buffered.insert(room.id, buffered);
+        self.metrics.touch(room.id);
+        self.configs.insert(room.id, room);
+        Ok(())
+    }
+
+    // drop 发送端后房间写入线程写出剩余数据并退出
+    fn stop(&mut self, pool: &mut ClientPool, room_id: u64) {
+        info!("停止 room_id: {}", room_id);
+        pool.remove_room(room_id);
+        self.txs.remove(&room_id);
+        self.overflow.remove(&room_id);
+        self.buffered.remove(&room_id);
+        self.down_since.remove(&room_id);
+        self.metrics.remove_room(room_id);
+        self.paused.remove(&room_id);
+        self.configs.remove(&room_id);
+    }
+
+    // 房间任务超出重启预算后停止房间, 配置文件更新或通过控制接口添加时重新启动
+    fn fail(&mut self, pool: &mut ClientPool, room_id: u64) {
+        // 房间已被停止, 或已重新启动了新的任务
+        if !self.txs.get(&room_id).is_some_and(|tx| tx.is_closed()) {
+            return;
+        }
+        error!("房间 {} 多次出错, 停止该房间, 其他房间不受影响", room_id);
+        self.stop(pool, room_id);
+    }
+
+    // 只转发房间配置中需要存储且未暂停的消息
+    fn dispatch(&mut self, room_id: u64, message: Message) {
+        let Some(config) = self.configs.get(&room_id) else {
+            return;
+        };
+        self.metrics.record_message(room_id, &message);
+        if self.paused.contains(&room_id)
+            || !store_kind(&message).is_some_and(|kind| config.stores(kind))
+        {
+            return;
+        }
+        if let Message::EnterRoom(msg) = &message {
+            if !config.sample_enter(msg.uid) {
+                return;
+            }
+        }
+        self.enqueue(room_id, RoomInput::Message(message));
+    }
+
+    // 暂存队列已满时丢弃最早的消息, 断线时段不丢弃
+    fn enqueue(&mut self, room_id: u64, input: RoomInput) {
+        let overflow = self.overflow.entry(room_id).or_default();
+        if overflow.len() >= MAX_OVERFLOW {
+            if let Some(index) = overflow
+                .iter()
+                .position(|input| matches!(input, RoomInput::Message(_)))
+            {
+                overflow.remove(index);
+                self.metrics
+                    .overflow_dropped
+                    .with_label_values(&[&room_id.to_string()])
+                    .inc();
+            }
+        }
+        overflow.push_back(input);
+        self.drain(room_id);
+    }
+
+    // 按顺序把暂存的消息发给写入线程, channel 已满时留到下次, 不等待
+    fn drain(&mut self, room_id: u64) {
+        let (Some(room_tx), Some(overflow)) =
+            (self.txs.get(&room_id), self.overflow.get_mut(&room_id))
+        else {
+            return;
+        };
+        while let Some(input) = overflow.pop_front() {
+            match room_tx.try_send(input) {
+                Ok(()) => {}
+                Err(TrySendError::Full(input)) => {
+                    overflow.push_front(input);
+                    return;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    debug!("room {} 已停止处理消息", room_id);
+                    overflow.clear();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn drain_all(&mut self) {
+        let room_ids: Vec<u64> = self.overflow.keys().copied().collect();
+        for room_id in room_ids {
+            self.drain(room_id);
+        }
+    }
+
+    // 记录断线时段, 写入后查询时可以区分没有消息和没有采集到数据
+    fn track_gap(&mut self, room_id: u64, event: &ClientEvent) {
+        let now = Utc::now().timestamp();
+        match event {
+            ClientEvent::Disconnected { reason } => {
+                self.down_since
+                    .entry(room_id)
+                    .or_insert_with(|| (now, reason.clone()));
+            }
+            ClientEvent::Authenticated { .. } => {
+                let Some((start, reason)) = self.down_since.remove(&room_id) else {
+                    return;
+                };
+                let gap = Gap {
+                    start,
+                    end: now,
+                    reason,
+                };
+                // 和消息一样经过暂存队列, channel 已满时不丢弃
+                if self.txs.contains_key(&room_id) {
+                    self.enqueue(room_id, RoomInput::Gap(gap));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn list(&self, pool: &ClientPool) -> Vec<RoomStatus> {
+        let mut rooms: Vec<RoomStatus> = self
+            .configs
+            .values()
+            .map(|room| {
+                let counters = pool.counters(room.id);
+                RoomStatus {
+                    room_id: room.id,
+                    label: room.label.clone(),
+                    state: pool
+                        .state(room.id)
+                        .map(|state| state.to_string())
+                        .unwrap_or_default(),
+                    paused: self.paused.contains(&room.id),
+                    buffered: self.buffered[&room.id].load(Ordering::Relaxed)
+                        + self.overflow.get(&room.id).map_or(0, |o| o.len()),
+                    dropped: counters.as_ref().map_or(0, |c| c.dropped()),
+                    spilled: counters.as_ref().map_or(0, |c| c.spilled()),
+                }
+            })
+            .collect();
+        rooms.sort_by_key(|room| room.room_id);
+        rooms
+    }
+
+    // 从 pool 同步连接相关的指标
+    fn sync_metrics(&self, pool: &ClientPool) {
+        let metrics = &self.metrics;
+        for room_id in self.configs.keys() {
+            let room_id = *room_id;
+            if let Some(state) = pool.state(room_id) {
+                metrics.set_connection_state(room_id, &state);
+            }
+            if let Some(counters) = pool.counters(room_id) {
+                metrics.sync_counter(&metrics.dropped, room_id, counters.dropped());
+                metrics.sync_counter(&metrics.spilled, room_id, counters.spilled());
+                metrics.sync_counter(&metrics.parse_errors, room_id, counters.parse_errors());
+            }
+        }
+    }
+
+    fn control(&mut self, pool: &mut ClientPool, action: Action) -> Result<(), String> {
+        match action {
+            Action::AddRoom { room_id, label } => {
+                if self.configs.contains_key(&room_id) {
+                    return Err(format!("room {} is already running", room_id));
+                }
+                self.removed.remove(&room_id);
+                if !self.config.enabled().any(|room| room.id == room_id) {
+                    let mut room = RoomConfig::new(room_id);
+                    room.label = label;
+                    room.store.clone_from(&self.config.store);
+                    room.sinks.clone_from(&self.config.sinks);
+                    room.flush = self.config.flush;
+                    self.added.insert(room_id, room);
+                }
+                self.reconcile(pool).map_err(|e| e.to_string())?;
+                if !self.configs.contains_key(&room_id) {
+                    return Err(format!("failed to start room {}", room_id));
+                }
+            }
+            Action::RemoveRoom { room_id } => {
+                if !self.configs.contains_key(&room_id) {
+                    return Err(format!("room {} is not running", room_id));
+                }
+                self.added.remove(&room_id);
+                self.removed.insert(room_id);
+                self.reconcile(pool).map_err(|e| e.to_string())?;
+            }
+            Action::Pause { room_id } => {
+                if !self.configs.contains_key(&room_id) {
+                    return Err(format!("room {} is not running", room_id));
+                }
+                info!("暂停存储 room_id: {}", room_id);
+                self.paused.insert(room_id);
+            }
+            Action::Resume { room_id } => {
+                if !self.paused.remove(&room_id) {
+                    return Err(format!("room {} is not paused", room_id));
+                }
+                info!("恢复存储 room_id: {}", room_id);
+            }
+            Action::Flush { .. } => return Err("flush must be handled by Rooms::flush".to_string()),
+        }
+        Ok(())
+    }
+
+    // 等待各房间写出缓存的数据, 在单独的任务中等待, 不阻塞消息分发
+    fn flush(&self, room_id: Option<u64>, reply: oneshot::Sender<Result<(), String>>) {
+        let txs: Vec<(u64, mpsc::Sender<RoomInput>)> = match room_id {
+            Some(room_id) => match self.txs.get(&room_id) {
+                Some(tx) => vec![(room_id, tx.clone())],
+                None => {
+                    let _ = reply.send(Err(format!("room {} is not running", room_id)));
+                    return;
                 }
             },
-            _ = shutdown_rx.changed() => {
-                // 收到 shutdown 信号，退出循环
-                info!("收到 shutdown 信号，停止监听 room_id: {}", room_id);
-                break;
+            None => self
+                .txs
+                .iter()
+                .map(|(room_id, tx)| (*room_id, tx.clone()))
+                .collect(),
+        };
+        tokio::spawn(async move {
+            let mut errors = vec![];
+            for (room_id, tx) in txs {
+                let (flush_tx, flush_rx) = oneshot::channel();
+                let result = match tx.send(RoomInput::Flush(flush_tx)).await {
+                    Ok(()) => flush_rx
+                        .await
+                        .unwrap_or_else(|_| Err("room stopped".to_string())),
+                    Err(_) => Err("room stopped".to_string()),
+                };
+                if let Err(e) = result {
+                    errors.push(format!("room {}: {}", room_id, e));
+                }
+            }
+            let _ = reply.send(match errors.is_empty() {
+                true => Ok(()),
+                false => Err(errors.join("; ")),
+            });
+        });
+    }
+
+    // 暂存的消息尽量交给写入线程, 已退出的房间丢弃
+    async fn join(mut self) {
+        for (room_id, overflow) in std::mem::take(&mut self.overflow) {
+            let Some(room_tx) = self.txs.get(&room_id) else {
+                continue;
+            };
+            let total = overflow.len();
+            for (sent, input) in overflow.into_iter().enumerate() {
+                if room_tx.send(input).await.is_err() {
+                    warn!("room {} 退出时丢弃 {} 条暂存的消息", room_id, total - sent);
+                    break;
+                }
+            }
+        }
+        // 关闭 channel, 写入线程处理完队列中的消息后写出并退出
+        self.txs.clear();
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+// 连接状态日志, 可通过 RUST_LOG=coverage=info 单独输出, 用于统计各房间数据的覆盖时段
+fn log_coverage(room_id: u64, event: &ClientEvent) {
+    let now = Utc::now().timestamp();
+    match event {
+        ClientEvent::Connecting => info!(target: "coverage", "{} {} connecting", now, room_id),
+        ClientEvent::Authenticated { host } => {
+            info!(target: "coverage", "{} {} up host={}", now, room_id, host)
+        }
+        ClientEvent::Disconnected { reason } => {
+            warn!(target: "coverage", "{} {} down reason={}", now, room_id, reason)
+        }
+        ClientEvent::Reconnecting { attempt } => {
+            info!(target: "coverage", "{} {} reconnecting attempt={}", now, room_id, attempt)
+        }
+        ClientEvent::RiskControl { uid, code } => {
+            warn!(target: "coverage", "{} {} risk_control uid={} code={}", now, room_id, uid, code)
+        }
+        ClientEvent::Message(_) => {}
+    }
+}
+
+// 房间任务在 RESTART_WINDOW 内最多失败的次数, 超出后放弃该房间
+const MAX_ROOM_FAILURES: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+// 等待重启期间最多暂存的消息数, 超出时丢弃最早的消息
+const MAX_PENDING: usize = 100_000;
+// 写入线程检查 flush 策略的间隔, 没有新消息的房间也能按 max_age_secs 写出
+const FLUSH_TICK: Duration = Duration::from_secs(10);
+
+// 房间任务出错时按退避时间重启, 只影响出错的房间. 正常退出时返回 true, 超出重启预算时返回 false
+async fn supervise_room(
+    room_id: i64,
+    open: OpenSinks,
+    mut rx: mpsc::Receiver<RoomInput>,
+    buffered: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+    mut shutdown_rx: watch::Receiver<()>,
+    mut budget: RestartBudget,
+) -> bool {
+    let mut pending = VecDeque::new();
+    let mut pending_gaps = vec![];
+    // 收到 shutdown 信号或超出预算后只再启动一次写入线程, 写出暂存的数据
+    let mut stopping = false;
+    let mut exhausted = false;
+    loop {
+        let writer = RoomWriter {
+            room_id,
+            open: open.clone(),
+            pending: std::mem::take(&mut pending),
+            pending_gaps: std::mem::take(&mut pending_gaps),
+            buffered: buffered.clone(),
+            metrics: metrics.clone(),
+        };
+        // 写入线程退出时交还 rx, 重启后继续接收
+        let result = match writer.spawn(rx) {
+            Ok(done) => match done.await {
+                Ok((room_rx, result)) => {
+                    rx = room_rx;
+                    result
+                }
+                Err(_) => {
+                    error!("room {} 的写入线程异常退出", room_id);
+                    return false;
+                }
             },
+            Err(e) => {
+                error!("启动 room {} 的写入线程出错: {:?}", room_id, e);
+                return false;
+            }
+        };
+        let Err(e) = result else {
+            return !exhausted;
+        };
+        error!("Error processing room {}: {:?}", room_id, e);
+        if stopping {
+            return false;
+        }
+        let Some(delay) = budget.fail(Instant::now()) else {
+            // 关闭 channel 后最后启动一次写入线程, 写出已在 channel 中的消息
+            rx.close();
+            stopping = true;
+            exhausted = true;
+            continue;
+        };
+        metrics
+            .restarts
+            .with_label_values(&[&room_id.to_string()])
+            .inc();
+        warn!("{:?} 后重启 room {}", delay, room_id);
+        // 等待期间继续接收消息, 避免阻塞其他房间的消息分发
+        let wait = sleep(delay);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                input = rx.recv() => match input {
+                    Some(RoomInput::Message(message)) => {
+                        if pending.len() >= MAX_PENDING {
+                            pending.pop_front();
+                            metrics
+                                .pending_dropped
+                                .with_label_values(&[&room_id.to_string()])
+                                .inc();
+                        }
+                        pending.push_back(message);
+                    }
+                    Some(RoomInput::Flush(reply)) => {
+                        let _ = reply.send(Err("room is restarting".to_string()));
+                    }
+                    Some(RoomInput::Gap(gap)) => pending_gaps.push(gap),
+                    Some(RoomInput::Tick) => {}
+                    // 不再等待, 立即重启写入线程写出暂存的消息
+                    None => {
+                        stopping = true;
+                        break;
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    stopping = true;
+                    break;
+                }
+            }
         }
     }
+}
 
-    // 执行清理工作
-    info!("开始清理 room_id: {}", room_id);
-    storage
-        .flush()
-        .map_err(|e| anyhow!("清理 room {} 出错: {}", room_id, e))?;
-    info!("清理 room {} 完成, Bye!", room_id);
+type WriterDone = oneshot::Receiver<(mpsc::Receiver<RoomInput>, Result<()>)>;
 
-    Ok(())
+// 在写入线程中按房间配置创建 sinks, 参数为线程持有的 DuckDB 连接和当前时间
+type OpenSinks = Arc<dyn for<'a> Fn(&'a Connection, i64) -> Result<Sinks<'a>> + Send + Sync>;
+
+fn open_sinks_with<F>(open: F) -> OpenSinks
+where
+    F: for<'a> Fn(&'a Connection, i64) -> Result<Sinks<'a>> + Send + Sync + 'static,
+{
+    Arc::new(open)
+}
+
+// 房间的写入线程, 持有 DuckDB 连接和 sinks, flush 和上传阻塞时只影响本房间
+struct RoomWriter {
+    room_id: i64,
+    open: OpenSinks,
+    // 上次出错后暂存的消息
+    pending: VecDeque<Message>,
+    pending_gaps: Vec<Gap>,
+    buffered: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+}
+
+impl RoomWriter {
+    fn spawn(self, mut rx: mpsc::Receiver<RoomInput>) -> Result<WriterDone> {
+        let handle = Handle::current();
+        let (done_tx, done_rx) = oneshot::channel();
+        std::thread::Builder::new()
+            .name(format!("room-{}", self.room_id))
+            .spawn(move || {
+                // webhook 等 sink 需要在 runtime 中启动后台任务
+                let _guard = handle.enter();
+                // panic 时同样交还 rx, 由 supervise_room 按出错重启
+                let result = panic::catch_unwind(AssertUnwindSafe(|| self.run(&handle, &mut rx)))
+                    .unwrap_or_else(|panic| {
+                        Err(anyhow!("写入线程 panic: {}", panic_message(&panic)))
+                    });
+                let _ = done_tx.send((rx, result));
+            })?;
+        Ok(done_rx)
+    }
+
+    // channel 关闭且已排队的消息都处理完后退出, 退出前写出缓存的数据
+    // 失败时返回错误, 由 supervise_room 重启
+    fn run(self, handle: &Handle, rx: &mut mpsc::Receiver<RoomInput>) -> Result<()> {
+        let room_id = self.room_id;
+        let conn = Connection::open_in_memory()?;
+        let mut sinks = (self.open)(&conn, Utc::now().timestamp())?;
+        let buffered_gauge = self
+            .metrics
+            .buffered
+            .with_label_values(&[&room_id.to_string()]);
+        let update_buffered = |sinks: &Sinks| {
+            self.buffered.store(sinks.buffered(), Ordering::Relaxed);
+            buffered_gauge.set(sinks.buffered() as i64);
+        };
+
+        info!("开始监听 room_id: {}", room_id);
+        for message in &self.pending {
+            sinks.write(message)?;
+        }
+        for gap in &self.pending_gaps {
+            sinks.record_gap(gap)?;
+        }
+
+        let mut ticker = time::interval(FLUSH_TICK);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // 存储出错时返回错误, 不再写出剩余数据, 重启后从 spool 回放
+        let mut process = |input: RoomInput| -> Result<()> {
+            match input {
+                RoomInput::Message(message) => {
+                    // 各 sink 按消息自身的时间写入对应日期的文件
+                    sinks.write(&message)?;
+                    update_buffered(&sinks);
+                }
+                RoomInput::Flush(reply) => {
+                    let result = sinks.flush().map_err(|e| e.to_string());
+                    update_buffered(&sinks);
+                    let _ = reply.send(result);
+                }
+                RoomInput::Gap(gap) => sinks.record_gap(&gap)?,
+                RoomInput::Tick => {
+                    sinks.tick()?;
+                    update_buffered(&sinks);
+                }
+            }
+            Ok(())
+        };
+        loop {
+            let input = handle.block_on(async {
+                tokio::select! {
+                    input = rx.recv() => input,
+                    _ = ticker.tick() => Some(RoomInput::Tick),
+                }
+            });
+            match input {
+                Some(input) => process(input)?,
+                None => break,
+            }
+        }
+
+        // 执行清理工作
+        info!("开始清理 room_id: {}", room_id);
+        let flushed = sinks
+            .flush()
+            .map_err(|e| anyhow!("清理 room {} 出错: {}", room_id, e));
+        update_buffered(&sinks);
+        flushed?;
+        info!("清理 room {} 完成, Bye!", room_id);
+
+        Ok(())
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown"
+    }
+}
+
+// 按房间配置创建 sink, 创建失败时房间不启动
+fn open_sinks<'a>(
+    conn: &'a Connection,
+    room_id: i64,
+    configs: &[SinkConfig],
+    flush: &FlushConfig,
+    timestamp: i64,
+    metrics: &Arc<Metrics>,
+) -> Result<Sinks<'a>> {
+    let mut sinks = Sinks::new(room_id);
+    for config in configs {
+        match config {
+            SinkConfig::Parquet => {
+                let mut storage = Storage::new(conn, room_id, timestamp)?;
+                storage.set_metrics(metrics.clone());
+                storage.set_flush_policy(*flush);
+                // 每条消息先写入本地 spool, 崩溃重启后回放未上传的消息
+                let spool_dir =
+                    std::env::var("DANMU_SPOOL_DIR").unwrap_or_else(|_| "spool".to_string());
+                storage.open_spool(Path::new(&spool_dir))?;
+                sinks.push(storage);
+            }
+            SinkConfig::Jsonl { dir } => {
+                sinks.push(JsonlSink::new(Path::new(dir), room_id, timestamp)?);
+            }
+            SinkConfig::Stdout => sinks.push(StdoutSink::new(room_id)),
+            SinkConfig::Webhook { url } => sinks.push(WebhookSink::new(url, room_id)?),
+        }
+    }
+    Ok(sinks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crawler::sink::MessageSink;
+    use parse::DanmuMessage;
+    use std::sync::Mutex;
+
+    struct Broken;
+
+    impl MessageSink for Broken {
+        fn name(&self) -> &str {
+            "broken"
+        }
+
+        fn write(&mut self, _message: &Message) -> Result<()> {
+            Err(anyhow!("broken"))
+        }
+
+        fn critical(&self) -> bool {
+            true
+        }
+    }
+
+    struct Panicking;
+
+    impl MessageSink for Panicking {
+        fn name(&self) -> &str {
+            "panicking"
+        }
+
+        fn write(&mut self, _message: &Message) -> Result<()> {
+            panic!("panicking sink");
+        }
+    }
+
+    // 写入时阻塞, 直到 Sender 被 drop, 之后记录写入的弹幕 id
+    struct Blocking(std::sync::mpsc::Receiver<()>, Recording);
+
+    impl MessageSink for Blocking {
+        fn name(&self) -> &str {
+            "blocking"
+        }
+
+        fn write(&mut self, message: &Message) -> Result<()> {
+            let _ = self.0.recv();
+            self.1.write(message)
+        }
+    }
+
+    // 记录写入的弹幕 id
+    struct Recording(Arc<Mutex<Vec<String>>>);
+
+    impl MessageSink for Recording {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn write(&mut self, message: &Message) -> Result<()> {
+            if let Message::Danmu(message) = message {
+                self.0.lock().unwrap().push(message.id.clone());
+            }
+            Ok(())
+        }
+    }
+
+    fn danmu(i: usize) -> Message {
+        Message::Danmu(DanmuMessage {
+            uid: 1,
+            username: "user".to_string(),
+            msg: i.to_string(),
+            timestamp: 1720973747,
+            id: i.to_string(),
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dispatch_blocked_room() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let (failed_tx, _failed_rx) = mpsc::unbounded_channel();
+        let mut rooms = Rooms::new(
+            String::new(),
+            shutdown_tx.clone(),
+            failed_tx,
+            metrics.clone(),
+        );
+        let (unblock_tx, unblock_rx) = std::sync::mpsc::channel();
+        let unblock_rx = Mutex::new(Some(unblock_rx));
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let sink_recorded = recorded.clone();
+        let open = open_sinks_with(move |_conn, _timestamp| {
+            let mut sinks = Sinks::new(1);
+            sinks.push(Blocking(
+                unblock_rx.lock().unwrap().take().unwrap(),
+                Recording(sink_recorded.clone()),
+            ));
+            Ok(sinks)
+        });
+        let (tx1, rx1) = mpsc::channel(4);
+        rooms.tasks.spawn(async move {
+            supervise_room(
+                1,
+                open,
+                rx1,
+                Arc::new(AtomicUsize::new(0)),
+                metrics,
+                shutdown_rx,
+                RestartBudget::new(0, RESTART_WINDOW),
+            )
+            .await;
+        });
+        let (tx2, mut rx2) = mpsc::channel(4);
+        for (room_id, tx) in [(1, tx1), (2, tx2)] {
+            rooms.configs.insert(room_id, RoomConfig::new(room_id));
+            rooms.txs.insert(room_id, tx);
+        }
+
+        // 房间 1 的写入线程阻塞时, 房间 2 仍然按顺序收到消息
+        for i in 0..100 {
+            rooms.dispatch(1, danmu(i));
+            rooms.dispatch(2, danmu(i));
+            match rx2.recv().await {
+                Some(RoomInput::Message(Message::Danmu(message))) => {
+                    assert_eq!(message.id, i.to_string())
+                }
+                _ => panic!("unexpected input"),
+            }
+        }
+        assert!(rooms.overflow[&1].len() > 90);
+        drop(rx2);
+
+        // 收到 shutdown 信号后, 队列和暂存的消息都写出后才退出
+        shutdown_tx.send(()).unwrap();
+        sleep(Duration::from_millis(50)).await;
+        drop(unblock_tx);
+        rooms.join().await;
+        let expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        assert_eq!(*recorded.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_track_gap_full_channel() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let (shutdown_tx, _shutdown_rx) = watch::channel(());
+        let (failed_tx, _failed_rx) = mpsc::unbounded_channel();
+        let mut rooms = Rooms::new(String::new(), shutdown_tx, failed_tx, metrics);
+        let (tx, mut rx) = mpsc::channel(1);
+        rooms.configs.insert(1, RoomConfig::new(1));
+        rooms.txs.insert(1, tx);
+        rooms.dispatch(1, danmu(0));
+        rooms.dispatch(1, danmu(1));
+
+        // channel 已满时断线时段排在暂存的消息之后, 不丢弃
+        let reason = "timeout".to_string();
+        rooms.track_gap(1, &ClientEvent::Disconnected { reason });
+        let host = "host".to_string();
+        rooms.track_gap(1, &ClientEvent::Authenticated { host });
+        assert_eq!(rooms.overflow[&1].len(), 2);
+        let mut received = vec![];
+        while received.len() < 3 {
+            match rx.recv().await {
+                Some(RoomInput::Message(Message::Danmu(message))) => received.push(message.id),
+                Some(RoomInput::Gap(gap)) => received.push(gap.reason),
+                _ => panic!("unexpected input"),
+            }
+            rooms.drain_all();
+        }
+        assert_eq!(received, ["0", "1", "timeout"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_writer_panic() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        let open = open_sinks_with(move |_conn, _timestamp| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut sinks = Sinks::new(1);
+            sinks.push(Panicking);
+            Ok(sinks)
+        });
+        let (tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            while tx.send(RoomInput::Message(Message::Default)).await.is_ok() {
+                sleep(Duration::from_millis(50)).await;
+            }
+        });
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        // panic 后交还 rx 并按出错重启
+        let supervised = supervise_room(
+            1,
+            open,
+            rx,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(Metrics::new().unwrap()),
+            shutdown_rx,
+            RestartBudget::new(1, RESTART_WINDOW),
+        );
+        assert!(!supervised.await);
+        // 超出预算后还会最后启动一次写入线程
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_supervise_room_budget() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        let open = open_sinks_with(move |_conn, _timestamp| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut sinks = Sinks::new(1);
+            sinks.push(Broken);
+            Ok(sinks)
+        });
+        let (tx, rx) = mpsc::channel(1024);
+        // 持续发送消息, 每次重启后的写入线程都会写入失败
+        tokio::spawn(async move {
+            while tx.send(RoomInput::Message(Message::Default)).await.is_ok() {
+                sleep(Duration::from_millis(50)).await;
+            }
+        });
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let supervised = supervise_room(
+            1,
+            open,
+            rx,
+            Arc::new(AtomicUsize::new(0)),
+            metrics.clone(),
+            shutdown_rx,
+            RestartBudget::new(2, RESTART_WINDOW),
+        );
+        // 重启两次后超出预算, 再最后启动一次写入线程
+        assert!(!supervised.await);
+        assert_eq!(opened.load(Ordering::SeqCst), 4);
+        assert_eq!(metrics.restarts.with_label_values(&["1"]).get(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_supervise_room_shutdown_while_restarting() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let counter = opened.clone();
+        let sink_recorded = recorded.clone();
+        // 第一次启动的写入线程写入失败, 之后正常写入
+        let open = open_sinks_with(move |_conn, _timestamp| {
+            let mut sinks = Sinks::new(1);
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => sinks.push(Broken),
+                _ => sinks.push(Recording(sink_recorded.clone())),
+            }
+            Ok(sinks)
+        });
+        let (tx, rx) = mpsc::channel(1024);
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let supervised = tokio::spawn(supervise_room(
+            1,
+            open,
+            rx,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(Metrics::new().unwrap()),
+            shutdown_rx,
+            RestartBudget::new(MAX_ROOM_FAILURES, RESTART_WINDOW),
+        ));
+        for i in 0..4 {
+            tx.send(RoomInput::Message(danmu(i))).await.unwrap();
+        }
+        while opened.load(Ordering::SeqCst) == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        sleep(Duration::from_millis(100)).await;
+
+        // 等待重启期间收到 shutdown 信号, 暂存的消息仍然写出
+        shutdown_tx.send(()).unwrap();
+        tx.send(RoomInput::Message(danmu(4))).await.unwrap();
+        drop(tx);
+        assert!(supervised.await.unwrap());
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        assert_eq!(*recorded.lock().unwrap(), ["1", "2", "3", "4"]);
+    }
 }
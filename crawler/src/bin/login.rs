@@ -0,0 +1,25 @@
+use anyhow::Result;
+use crawler::credential::{credential_paths, select_credential_path};
+use danmu_client::{Endpoints, Passport};
+use log::info;
+
+// 扫码登录并写入凭据文件, 正在运行的 crawler 会自动读取新的凭据
+// 配置了多个账号时通过参数选择, 如 login 1 或 login b.json, 默认第一个账号
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    pretty_env_logger::init_timed();
+    let account = std::env::args().nth(1);
+    let credential_path = select_credential_path(&credential_paths(), account.as_deref())?;
+
+    let passport = Passport::new(Endpoints::default())?;
+    let credential = passport
+        .login(|qrcode| {
+            println!("请将以下链接生成二维码, 使用哔哩哔哩 app 扫码登录:");
+            println!("{}", qrcode.url);
+        })
+        .await?;
+    credential.save(&credential_path)?;
+    info!("凭据已保存到 {}", credential_path.display());
+    Ok(())
+}
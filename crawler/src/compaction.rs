@@ -0,0 +1,131 @@
+use anyhow::Result;
+use duckdb::Connection;
+use log::info;
+use utils::utils::{dedup_source, get_parts_glob, get_table_glob};
+
+// 将 target 当天的 part 文件和已有的 target 合并为一个按时间排序的 zstd 文件,
+// 返回已合并的 part 文件, 由调用方在写入成功后删除
+// 删除前中断时重新合并, 按消息 id 去重避免重复写入
+pub fn compact(conn: &Connection, target: &str) -> Result<Vec<String>> {
+    let parts = list_files(conn, &get_parts_glob(target))?;
+    if parts.is_empty() {
+        return Ok(parts);
+    }
+    let files = list_files(conn, &get_table_glob(target))?;
+    let sources = files
+        .iter()
+        .filter(|file| parts.contains(file) || file.as_str() == target)
+        .map(|file| format!("'{}'", file))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let source = dedup_source(conn, &format!("[{sources}]"))?;
+    conn.execute(
+        &format!(
+            "CREATE OR REPLACE TEMP TABLE compacting AS
+             SELECT * FROM {source} ORDER BY timestamp"
+        ),
+        [],
+    )?;
+    conn.execute(
+        &format!("COPY compacting TO '{target}' (FORMAT PARQUET, COMPRESSION ZSTD)"),
+        [],
+    )?;
+    conn.execute("DROP TABLE compacting", [])?;
+    info!("合并 {} 个 part 到 {}", parts.len(), target);
+    Ok(parts)
+}
+
+fn list_files(conn: &Connection, pattern: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("SELECT file FROM glob('{pattern}') ORDER BY file"))?;
+    let files = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<duckdb::Result<Vec<String>>>()?;
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::utils::get_part_name;
+
+    #[test]
+    fn test_compact() {
+        let dir = std::env::temp_dir().join(format!("compact-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("danmu.parquet").to_string_lossy().to_string();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE danmu (uid BIGINT, msg TEXT, timestamp BIGINT)")
+            .unwrap();
+
+        // 没有 part 时不改动
+        assert!(compact(&conn, &target).unwrap().is_empty());
+        conn.execute_batch(&format!(
+            "INSERT INTO danmu VALUES (1, 'a', 30); COPY danmu TO '{target}'; DELETE FROM danmu"
+        ))
+        .unwrap();
+        for (id, rows) in [("1", "(2, 'b', 20)"), ("2", "(3, 'c', 10), (1, 'a', 30)")] {
+            let part = get_part_name(&target, id);
+            conn.execute_batch(&format!(
+                "INSERT INTO danmu VALUES {rows}; COPY danmu TO '{part}'; DELETE FROM danmu"
+            ))
+            .unwrap();
+        }
+
+        let parts = compact(&conn, &target).unwrap();
+        assert_eq!(parts.len(), 2);
+        let rows: Vec<(i64, i64)> = conn
+            .prepare(&format!("SELECT uid, timestamp FROM '{target}'"))
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<duckdb::Result<_>>()
+            .unwrap();
+        assert_eq!(rows, vec![(3, 10), (2, 20), (1, 30)]);
+
+        // part 删除前再次合并不产生重复数据
+        assert_eq!(compact(&conn, &target).unwrap().len(), 2);
+        let count: i64 = conn
+            .query_row(&format!("SELECT COUNT(*) FROM '{target}'"), [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact_gap() {
+        let dir = std::env::temp_dir().join(format!("compact-gap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("gap.parquet").to_string_lossy().to_string();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE gap (timestamp BIGINT, end_timestamp BIGINT, reason TEXT)",
+        )
+        .unwrap();
+        // 断线时段只写入 part 文件, 没有 id 列, 按整行去重
+        for (id, rows) in [
+            ("1", "(20, 30, 'closed')"),
+            ("2", "(10, 15, 'timeout'), (20, 30, 'closed')"),
+        ] {
+            let part = get_part_name(&target, id);
+            conn.execute_batch(&format!(
+                "INSERT INTO gap VALUES {rows}; COPY gap TO '{part}'; DELETE FROM gap"
+            ))
+            .unwrap();
+        }
+
+        assert_eq!(compact(&conn, &target).unwrap().len(), 2);
+        let rows: Vec<(i64, i64)> = conn
+            .prepare(&format!("SELECT timestamp, end_timestamp FROM '{target}'"))
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<duckdb::Result<_>>()
+            .unwrap();
+        assert_eq!(rows, vec![(10, 15), (20, 30)]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Result};
+use danmu_client::Proxy;
+use log::{error, info};
+use parse::Message;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::time;
+use utils::rooms::{RoomsConfig, StoreKind};
+
+// 检查房间配置文件是否被修改的间隔
+const ROOMS_WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+// 解析 DANMU_PROXIES, 逗号分隔:
+// room_id=url 为指定房间使用的代理, 单独的 url 按顺序轮流分配给其余房间
+// 例如 "socks5://10.0.0.1:1080,http://10.0.0.2:8080,22747736=socks5://10.0.0.3:1080"
+pub fn assign_proxies(spec: &str, room_ids: &[u64]) -> Result<HashMap<u64, Proxy>> {
+    let mut assigned = HashMap::new();
+    let mut shared = Vec::new();
+    for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match item.split_once('=') {
+            Some((room_id, url)) => {
+                let room_id = room_id
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("invalid room id in proxy config: {}", item))?;
+                assigned.insert(room_id, url.trim().parse::<Proxy>()?);
+            }
+            None => shared.push(item.parse::<Proxy>()?),
+        }
+    }
+    if !shared.is_empty() {
+        let rest: Vec<u64> = room_ids
+            .iter()
+            .filter(|room_id| !assigned.contains_key(room_id))
+            .copied()
+            .collect();
+        for (room_id, proxy) in rest.into_iter().zip(shared.iter().cycle()) {
+            assigned.insert(room_id, proxy.clone());
+        }
+    }
+    Ok(assigned)
+}
+
+// 消息对应的存储类型, 不存储的消息返回 None
+pub fn store_kind(message: &Message) -> Option<StoreKind> {
+    match message {
+        Message::Danmu(_) => Some(StoreKind::Danmu),
+        Message::SuperChat(_) => Some(StoreKind::SuperChat),
+        Message::BlockUser(_) => Some(StoreKind::BlockUser),
+        Message::EnterRoom(_) => Some(StoreKind::EnterRoom),
+        Message::OnlineCount(_) | Message::Default => None,
+    }
+}
+
+// 监听房间配置文件变化, 新的配置通过 watch channel 发出
+pub struct RoomsWatcher {
+    path: PathBuf,
+    overrides: Option<String>,
+    modified: Option<SystemTime>,
+    interval: Duration,
+}
+
+impl RoomsWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified_time(&path);
+        Self {
+            path,
+            overrides: std::env::var("ROOMS").ok(),
+            modified,
+            interval: ROOMS_WATCH_INTERVAL,
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub async fn run(mut self, tx: watch::Sender<RoomsConfig>) {
+        let mut ticker = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = tx.closed() => return,
+            }
+            let modified = modified_time(&self.path);
+            if modified == self.modified {
+                continue;
+            }
+            self.modified = modified;
+            // 解析失败时保留当前配置
+            let config = match RoomsConfig::load(&self.path)
+                .and_then(|config| config.with_overrides(self.overrides.as_deref()))
+            {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("{:?}", e);
+                    continue;
+                }
+            };
+            tx.send_if_modified(|current| {
+                if *current == config {
+                    return false;
+                }
+                info!("房间配置 {} 已更新", self.path.display());
+                *current = config;
+                true
+            });
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign_proxies() {
+        let rooms = [1, 2, 3, 4];
+        let proxies = assign_proxies(
+            "socks5://10.0.0.1:1080, http://10.0.0.2:8080, 3=socks5://10.0.0.3:1080",
+            &rooms,
+        )
+        .unwrap();
+        let host = |room_id: u64| proxies[&room_id].host.clone();
+        assert_eq!(host(1), "10.0.0.1");
+        assert_eq!(host(2), "10.0.0.2");
+        assert_eq!(host(3), "10.0.0.3");
+        assert_eq!(host(4), "10.0.0.1");
+
+        // 只指定部分房间时其余房间直连
+        let proxies = assign_proxies("2=http://10.0.0.2:8080", &rooms).unwrap();
+        assert_eq!(proxies.keys().collect::<Vec<_>>(), vec![&2]);
+
+        assert!(assign_proxies("", &rooms).unwrap().is_empty());
+        assert!(assign_proxies("abc=http://10.0.0.2:8080", &rooms).is_err());
+        assert!(assign_proxies("ftp://10.0.0.2", &rooms).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rooms_watcher() {
+        let dir = std::env::temp_dir().join(format!("rooms-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rooms.toml");
+        std::fs::write(&path, "[[room]]\nid = 1").unwrap();
+        let config = RoomsConfig::load(&path).unwrap();
+
+        let mut watcher = RoomsWatcher::new(path.clone());
+        watcher.set_interval(Duration::from_millis(20));
+        let (tx, mut rx) = watch::channel(config);
+        let task = tokio::spawn(watcher.run(tx));
+
+        // 保证 mtime 变化
+        time::sleep(Duration::from_millis(20)).await;
+        // 无法解析的内容被忽略
+        std::fs::write(&path, "[[room]]\nid = ").unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!rx.has_changed().unwrap());
+
+        std::fs::write(&path, "[[room]]\nid = 1\nenabled = false\n[[room]]\nid = 2").unwrap();
+        time::timeout(Duration::from_secs(5), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx.borrow().room_ids(), vec![2]);
+
+        task.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use danmu_client::{Credential, Endpoints, Passport};
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::time;
+
+// 检查凭据文件是否被修改的间隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(60);
+// 检查 cookie 是否需要刷新的间隔
+const REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+// BILI_CREDENTIAL_FILE 可以用逗号分隔多个文件, 每个文件一个账号
+pub fn credential_paths() -> Vec<PathBuf> {
+    std::env::var("BILI_CREDENTIAL_FILE")
+        .unwrap_or_else(|_| "credential.json".to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+// login 命令写入的凭据文件, account 为账号序号 (从 0 开始) 或 BILI_CREDENTIAL_FILE 中的路径,
+// 未指定时为第一个账号
+pub fn select_credential_path(paths: &[PathBuf], account: Option<&str>) -> Result<PathBuf> {
+    let Some(account) = account else {
+        return paths
+            .first()
+            .cloned()
+            .ok_or(anyhow!("BILI_CREDENTIAL_FILE 未配置凭据文件"));
+    };
+    if let Ok(index) = account.parse::<usize>() {
+        return paths.get(index).cloned().ok_or(anyhow!(
+            "账号序号 {} 超出范围, 共 {} 个凭据文件",
+            index,
+            paths.len()
+        ));
+    }
+    paths
+        .iter()
+        .find(|path| path.as_path() == Path::new(account))
+        .cloned()
+        .ok_or(anyhow!("{} 不在 BILI_CREDENTIAL_FILE 中", account))
+}
+
+// 第一个账号可以由 BILI_COOKIE 生成, 其余账号的凭据文件需已存在
+pub fn load_credentials(paths: &[PathBuf]) -> Result<Vec<Credential>> {
+    paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| match index {
+            0 => load_credential(path),
+            _ => Credential::load(path)
+                .map_err(|e| anyhow!("读取凭据文件 {} 出错: {}", path.display(), e)),
+        })
+        .collect()
+}
+
+// 优先读取凭据文件, 不存在时使用 BILI_COOKIE (base64) 和 BILI_REFRESH_TOKEN 生成
+pub fn load_credential(path: &Path) -> Result<Credential> {
+    if path.exists() {
+        info!("从 {} 读取凭据", path.display());
+        return Ok(Credential::load(path)?);
+    }
+    let cookies = std::env::var("BILI_COOKIE")
+        .map_err(|_| anyhow!("凭据文件 {} 不存在且未配置 BILI_COOKIE", path.display()))?;
+    let credential = Credential {
+        cookies: String::from_utf8(base64::engine::general_purpose::STANDARD.decode(cookies)?)?,
+        refresh_token: std::env::var("BILI_REFRESH_TOKEN").unwrap_or_default(),
+    };
+    if credential.refresh_token.is_empty() {
+        warn!("未配置 BILI_REFRESH_TOKEN, cookie 过期后无法自动刷新");
+    }
+    credential.save(path)?;
+    info!("已将 BILI_COOKIE 写入 {}", path.display());
+    Ok(credential)
+}
+
+// 监听凭据文件变化并定期刷新 cookie, 新的 cookie 通过 watch channel 发出
+pub struct CredentialWatcher {
+    path: PathBuf,
+    credential: Credential,
+    modified: Option<SystemTime>,
+    passport: Passport,
+    watch_interval: Duration,
+    refresh_interval: Duration,
+}
+
+impl CredentialWatcher {
+    pub fn new(path: PathBuf, credential: Credential) -> Result<Self> {
+        let modified = modified_time(&path);
+        Ok(Self {
+            path,
+            credential,
+            modified,
+            passport: Passport::new(Endpoints::default())?,
+            watch_interval: WATCH_INTERVAL,
+            refresh_interval: REFRESH_INTERVAL,
+        })
+    }
+
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) -> Result<()> {
+        self.passport = Passport::new(endpoints)?;
+        Ok(())
+    }
+
+    pub fn set_intervals(&mut self, watch_interval: Duration, refresh_interval: Duration) {
+        self.watch_interval = watch_interval;
+        self.refresh_interval = refresh_interval;
+    }
+
+    pub async fn run(mut self, tx: watch::Sender<String>) {
+        let mut watch_ticker = time::interval(self.watch_interval);
+        let mut refresh_ticker = time::interval(self.refresh_interval);
+        loop {
+            tokio::select! {
+                _ = watch_ticker.tick() => self.reload(),
+                _ = refresh_ticker.tick() => self.refresh().await,
+                _ = tx.closed() => return,
+            }
+            tx.send_if_modified(|cookies| {
+                if *cookies == self.credential.cookies {
+                    return false;
+                }
+                cookies.clone_from(&self.credential.cookies);
+                true
+            });
+        }
+    }
+
+    // 凭据文件被外部修改 (如重新扫码登录) 时重新读取
+    fn reload(&mut self) {
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match Credential::load(&self.path) {
+            Ok(credential) => {
+                if credential != self.credential {
+                    info!("凭据文件 {} 已更新", self.path.display());
+                    self.credential = credential;
+                }
+            }
+            Err(e) => error!("读取凭据文件 {} 出错: {:?}", self.path.display(), e),
+        }
+    }
+
+    async fn refresh(&mut self) {
+        if self.credential.refresh_token.is_empty() {
+            return;
+        }
+        match self.passport.refresh_if_needed(&self.credential).await {
+            Ok(Some(credential)) => {
+                if let Err(e) = credential.save(&self.path) {
+                    error!("保存凭据文件 {} 出错: {:?}", self.path.display(), e);
+                }
+                self.modified = modified_time(&self.path);
+                self.credential = credential;
+                info!("cookie 刷新完成");
+            }
+            Ok(None) => {}
+            Err(e) => error!("刷新 cookie 出错: {:?}", e),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_bili::MockBili;
+
+    #[test]
+    fn test_select_credential_path() {
+        let paths = vec![PathBuf::from("a.json"), PathBuf::from("b.json")];
+        assert_eq!(
+            select_credential_path(&paths, None).unwrap(),
+            PathBuf::from("a.json")
+        );
+        assert_eq!(
+            select_credential_path(&paths, Some("1")).unwrap(),
+            PathBuf::from("b.json")
+        );
+        assert_eq!(
+            select_credential_path(&paths, Some("b.json")).unwrap(),
+            PathBuf::from("b.json")
+        );
+        assert!(select_credential_path(&paths, Some("2")).is_err());
+        assert!(select_credential_path(&paths, Some("c.json")).is_err());
+        assert!(select_credential_path(&[], None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_credential_watcher() {
+        let mock = MockBili::start().await.unwrap();
+        let endpoints = Endpoints::with_base(&mock.api_base());
+        mock.set_qrcode_status(0);
+        let credential = Passport::new(endpoints.clone())
+            .unwrap()
+            .login(|_| {})
+            .await
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("credential-test-{}", std::process::id()));
+        let path = dir.join("credential.json");
+        credential.save(&path).unwrap();
+        assert_eq!(load_credential(&path).unwrap(), credential);
+
+        let mut watcher = CredentialWatcher::new(path.clone(), credential.clone()).unwrap();
+        watcher.set_endpoints(endpoints).unwrap();
+        watcher.set_intervals(Duration::from_millis(20), Duration::from_millis(50));
+        let (tx, mut rx) = watch::channel(credential.cookies.clone());
+        let task = tokio::spawn(watcher.run(tx));
+
+        // 需要刷新时写回文件并通知
+        mock.set_refresh_needed(true);
+        time::timeout(Duration::from_secs(5), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        let refreshed = Credential::load(&path).unwrap();
+        assert_eq!(refreshed.refresh_token, "mock-refresh-2");
+        assert_eq!(*rx.borrow_and_update(), refreshed.cookies);
+
+        // 外部修改凭据文件后重新读取
+        let edited = Credential {
+            cookies: format!("{}; extra=1", refreshed.cookies),
+            ..refreshed
+        };
+        // 保证 mtime 变化
+        time::sleep(Duration::from_millis(20)).await;
+        edited.save(&path).unwrap();
+        time::timeout(Duration::from_secs(5), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*rx.borrow(), edited.cookies);
+
+        task.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use utils::rooms::FlushConfig;
+
+// Storage 缓冲区的当前状态
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BufferStats {
+    pub rows: usize,
+    pub enter_rows: usize,
+    // 按字段长度估算的字节数
+    pub bytes: usize,
+    // 缓冲中最早一条消息写入的时间, 缓冲为空时为 None
+    pub oldest: Option<i64>,
+}
+
+impl BufferStats {
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0 && self.enter_rows == 0
+    }
+}
+
+// 决定 Storage 什么时候写出缓冲, 收到消息和写入线程定时 tick 时都会检查
+pub trait FlushPolicy {
+    fn should_flush(&self, stats: &BufferStats, now: i64) -> bool;
+}
+
+// 任一阈值超过时写出
+impl FlushPolicy for FlushConfig {
+    fn should_flush(&self, stats: &BufferStats, now: i64) -> bool {
+        if stats.is_empty() {
+            return false;
+        }
+        stats.rows as u64 > self.max_rows
+            || stats.enter_rows as u64 > self.max_enter_rows
+            || stats.bytes as u64 > self.max_bytes
+            || stats
+                .oldest
+                .is_some_and(|oldest| now - oldest >= self.max_age_secs as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_config_policy() {
+        let policy = FlushConfig {
+            max_rows: 10,
+            max_enter_rows: 100,
+            max_bytes: 1000,
+            max_age_secs: 60,
+        };
+        let stats = BufferStats {
+            rows: 1,
+            enter_rows: 0,
+            bytes: 50,
+            oldest: Some(1000),
+        };
+        assert!(!policy.should_flush(&stats, 1059));
+        // 没有新消息时 tick 也会按等待时间写出
+        assert!(policy.should_flush(&stats, 1060));
+        assert!(policy.should_flush(&BufferStats { rows: 11, ..stats }, 1000));
+        assert!(policy.should_flush(
+            &BufferStats {
+                enter_rows: 101,
+                ..stats
+            },
+            1000
+        ));
+        assert!(policy.should_flush(
+            &BufferStats {
+                bytes: 1001,
+                ..stats
+            },
+            1000
+        ));
+        assert!(!policy.should_flush(&BufferStats::default(), 100_000));
+    }
+}
@@ -1 +1,11 @@
+pub mod api;
+pub mod compaction;
+pub mod config;
+pub mod credential;
+pub mod flush;
+pub mod metrics;
+pub mod s3;
+pub mod sink;
+pub mod spool;
 pub mod storage;
+pub mod supervisor;
@@ -0,0 +1,262 @@
+use anyhow::Result;
+use danmu_client::ConnectionState;
+use parse::Message;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+const CONNECTION_STATES: [&str; 4] = ["waiting", "connecting", "connected", "disconnected"];
+
+// crawler 的 prometheus 指标, 由 METRICS_ADDR 上的 /metrics 输出, 标签 room 为房间号
+// 某个房间长时间没有消息时 crawler_seconds_since_last_message 增大, 用于告警
+pub struct Metrics {
+    registry: Registry,
+    pub messages: IntCounterVec,
+    pub parse_errors: IntCounterVec,
+    pub reconnects: IntCounterVec,
+    pub restarts: IntCounterVec,
+    pub dropped: IntCounterVec,
+    pub spilled: IntCounterVec,
+    pub overflow_dropped: IntCounterVec,
+    pub pending_dropped: IntCounterVec,
+    pub buffered: IntGaugeVec,
+    pub seconds_since_last_message: GaugeVec,
+    pub connection_state: IntGaugeVec,
+    pub flush_seconds: HistogramVec,
+    pub flushed_rows: HistogramVec,
+    // 连接计数器上次同步的累计值
+    synced: Mutex<HashMap<(String, u64), u64>>,
+    // 房间最后一条消息的时间, 启动时为启动时间, 输出时换算为 seconds_since_last_message
+    last_message: Mutex<HashMap<u64, Instant>>,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("crawler".to_string()), None)?;
+        let messages = IntCounterVec::new(
+            Opts::new("messages_total", "收到的消息数"),
+            &["room", "type"],
+        )?;
+        let parse_errors = IntCounterVec::new(
+            Opts::new("parse_errors_total", "解析失败的消息数"),
+            &["room"],
+        )?;
+        let reconnects = IntCounterVec::new(Opts::new("reconnects_total", "重连次数"), &["room"])?;
+        let restarts = IntCounterVec::new(
+            Opts::new("room_restarts_total", "房间任务出错后的重启次数"),
+            &["room"],
+        )?;
+        let dropped = IntCounterVec::new(
+            Opts::new("dropped_total", "队列满时丢弃的消息数"),
+            &["room"],
+        )?;
+        let spilled = IntCounterVec::new(
+            Opts::new("spilled_total", "队列满时写入磁盘的消息数"),
+            &["room"],
+        )?;
+        let overflow_dropped = IntCounterVec::new(
+            Opts::new(
+                "overflow_dropped_total",
+                "写入线程处理不过来且暂存队列已满时丢弃的消息数",
+            ),
+            &["room"],
+        )?;
+        let pending_dropped = IntCounterVec::new(
+            Opts::new(
+                "pending_dropped_total",
+                "房间等待重启期间暂存队列已满时丢弃的消息数",
+            ),
+            &["room"],
+        )?;
+        let buffered = IntGaugeVec::new(
+            Opts::new("buffered_messages", "尚未写入存储的消息数"),
+            &["room"],
+        )?;
+        let seconds_since_last_message = GaugeVec::new(
+            Opts::new(
+                "seconds_since_last_message",
+                "距离上一条消息的秒数, 没有消息时从房间启动开始计算",
+            ),
+            &["room"],
+        )?;
+        let connection_state = IntGaugeVec::new(
+            Opts::new("connection_state", "当前连接状态为 1, 其余为 0"),
+            &["room", "state"],
+        )?;
+        let flush_seconds = HistogramVec::new(
+            HistogramOpts::new("flush_duration_seconds", "写入 part 文件的耗时")
+                .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["room"],
+        )?;
+        let flushed_rows = HistogramVec::new(
+            HistogramOpts::new("flushed_rows", "每次 flush 写入的行数")
+                .buckets(prometheus::exponential_buckets(1.0, 4.0, 8)?),
+            &["room"],
+        )?;
+        registry.register(Box::new(messages.clone()))?;
+        registry.register(Box::new(parse_errors.clone()))?;
+        registry.register(Box::new(reconnects.clone()))?;
+        registry.register(Box::new(restarts.clone()))?;
+        registry.register(Box::new(dropped.clone()))?;
+        registry.register(Box::new(spilled.clone()))?;
+        registry.register(Box::new(overflow_dropped.clone()))?;
+        registry.register(Box::new(pending_dropped.clone()))?;
+        registry.register(Box::new(buffered.clone()))?;
+        registry.register(Box::new(seconds_since_last_message.clone()))?;
+        registry.register(Box::new(connection_state.clone()))?;
+        registry.register(Box::new(flush_seconds.clone()))?;
+        registry.register(Box::new(flushed_rows.clone()))?;
+        Ok(Self {
+            registry,
+            messages,
+            parse_errors,
+            reconnects,
+            restarts,
+            dropped,
+            spilled,
+            overflow_dropped,
+            pending_dropped,
+            buffered,
+            seconds_since_last_message,
+            connection_state,
+            flush_seconds,
+            flushed_rows,
+            synced: Mutex::new(HashMap::new()),
+            last_message: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn record_message(&self, room_id: u64, message: &Message) {
+        let kind = match message {
+            Message::Danmu(_) => "danmu",
+            Message::SuperChat(_) => "super_chat",
+            Message::EnterRoom(_) => "enter_room",
+            Message::BlockUser(_) => "block_user",
+            Message::OnlineCount(_) => "online_count",
+            Message::Default => "other",
+        };
+        self.messages
+            .with_label_values(&[&room_id.to_string(), kind])
+            .inc();
+        self.touch(room_id);
+    }
+
+    pub fn touch(&self, room_id: u64) {
+        self.last_message
+            .lock()
+            .unwrap()
+            .insert(room_id, Instant::now());
+    }
+
+    pub fn set_connection_state(&self, room_id: u64, state: &ConnectionState) {
+        let room = room_id.to_string();
+        let current = match state {
+            ConnectionState::Disconnected(_) => "disconnected".to_string(),
+            state => state.to_string(),
+        };
+        for state in CONNECTION_STATES {
+            self.connection_state
+                .with_label_values(&[&room, state])
+                .set(i64::from(state == current));
+        }
+    }
+
+    // 把连接的累计值同步到计数器, 累计值变小说明房间重新加入后从 0 开始
+    pub fn sync_counter(&self, counter: &IntCounterVec, room_id: u64, total: u64) {
+        let name = counter.desc()[0].fq_name.clone();
+        let mut synced = self.synced.lock().unwrap();
+        let last = synced.insert((name, room_id), total).unwrap_or(0);
+        let delta = if total >= last { total - last } else { total };
+        if delta > 0 {
+            counter
+                .with_label_values(&[&room_id.to_string()])
+                .inc_by(delta);
+        }
+    }
+
+    pub fn observe_flush(&self, room_id: i64, seconds: f64, rows: usize) {
+        let room = room_id.to_string();
+        self.flush_seconds
+            .with_label_values(&[&room])
+            .observe(seconds);
+        self.flushed_rows
+            .with_label_values(&[&room])
+            .observe(rows as f64);
+    }
+
+    // 房间停止后不再输出它的状态类指标
+    pub fn remove_room(&self, room_id: u64) {
+        self.last_message.lock().unwrap().remove(&room_id);
+        self.synced
+            .lock()
+            .unwrap()
+            .retain(|(_, id), _| *id != room_id);
+        let room = room_id.to_string();
+        let _ = self.buffered.remove_label_values(&[&room]);
+        let _ = self
+            .seconds_since_last_message
+            .remove_label_values(&[&room]);
+        for state in CONNECTION_STATES {
+            let _ = self.connection_state.remove_label_values(&[&room, state]);
+        }
+    }
+
+    pub fn render(&self) -> Result<String> {
+        for (room_id, last) in self.last_message.lock().unwrap().iter() {
+            self.seconds_since_last_message
+                .with_label_values(&[&room_id.to_string()])
+                .set(last.elapsed().as_secs_f64());
+        }
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse::DanmuMessage;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new().unwrap();
+        let danmu = Message::Danmu(DanmuMessage {
+            uid: 1,
+            username: "a".to_string(),
+            msg: "hi".to_string(),
+            timestamp: 1,
+            id: String::new(),
+        });
+        metrics.record_message(1, &danmu);
+        metrics.record_message(1, &danmu);
+        metrics.set_connection_state(1, &ConnectionState::Disconnected("eof".to_string()));
+        metrics.set_connection_state(1, &ConnectionState::Connected);
+        metrics.sync_counter(&metrics.dropped, 1, 5);
+        metrics.sync_counter(&metrics.dropped, 1, 7);
+        // 重新加入房间后连接的计数从 0 开始
+        metrics.sync_counter(&metrics.dropped, 1, 3);
+        metrics.sync_counter(&metrics.spilled, 1, 2);
+        metrics.observe_flush(1, 0.2, 100);
+
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"crawler_messages_total{room="1",type="danmu"} 2"#));
+        assert!(text.contains(r#"crawler_connection_state{room="1",state="connected"} 1"#));
+        assert!(text.contains(r#"crawler_connection_state{room="1",state="disconnected"} 0"#));
+        assert!(text.contains(r#"crawler_dropped_total{room="1"} 10"#));
+        assert!(text.contains(r#"crawler_spilled_total{room="1"} 2"#));
+        assert!(text.contains(r#"crawler_flushed_rows_count{room="1"} 1"#));
+        assert!(text.contains(r#"crawler_seconds_since_last_message{room="1"}"#));
+
+        metrics.remove_room(1);
+        let text = metrics.render().unwrap();
+        assert!(!text.contains("crawler_connection_state{"));
+        assert!(!text.contains("crawler_seconds_since_last_message{"));
+        assert!(text.contains(r#"crawler_messages_total{room="1",type="danmu"} 2"#));
+    }
+}
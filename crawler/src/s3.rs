@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Result};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path;
+use object_store::ObjectStore;
+use utils::utils::OssConfig;
+
+// duckdb 不能删除文件, s3 上合并后的 part 文件通过 object_store 删除
+// 地址风格和协议与 duckdb 的 secret 使用相同的 OSS_URL_STYLE 和 OSS_USE_SSL
+pub struct S3Client {
+    store: AmazonS3,
+    bucket: String,
+}
+
+impl S3Client {
+    pub fn new(config: &OssConfig) -> Result<Self> {
+        let store = AmazonS3Builder::new()
+            .with_endpoint(bucket_endpoint(config))
+            .with_virtual_hosted_style_request(!config.path_style)
+            .with_allow_http(!config.use_ssl)
+            .with_region(&config.region)
+            .with_bucket_name(&config.bucket)
+            .with_access_key_id(&config.key)
+            .with_secret_access_key(&config.secret)
+            .build()?;
+        Ok(Self {
+            store,
+            bucket: config.bucket.clone(),
+        })
+    }
+
+    // url 为 s3://{bucket}/{key}
+    pub async fn delete(&self, url: &str) -> Result<()> {
+        let key = url
+            .strip_prefix(&format!("s3://{}/", self.bucket))
+            .ok_or_else(|| anyhow!("{} is not in bucket {}", url, self.bucket))?;
+        self.store.delete(&Path::parse(key)?).await?;
+        Ok(())
+    }
+}
+
+// vhost 风格时 object_store 要求 endpoint 包含 bucket, path 风格时由 object_store 拼接
+fn bucket_endpoint(config: &OssConfig) -> String {
+    let scheme = if config.use_ssl { "https" } else { "http" };
+    if config.path_style {
+        format!("{}://{}", scheme, config.host())
+    } else {
+        format!("{}://{}.{}", scheme, config.bucket, config.host())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_endpoint() {
+        let mut config = OssConfig {
+            endpoint: "https://oss-cn-hangzhou.aliyuncs.com/".to_string(),
+            region: "cn-hangzhou".to_string(),
+            key: "key".to_string(),
+            secret: "secret".to_string(),
+            bucket: "danmu".to_string(),
+            path_style: false,
+            use_ssl: true,
+        };
+        assert_eq!(
+            bucket_endpoint(&config),
+            "https://danmu.oss-cn-hangzhou.aliyuncs.com"
+        );
+        // MinIO 等使用 path 风格和 http
+        config.endpoint = "127.0.0.1:9000".to_string();
+        config.path_style = true;
+        config.use_ssl = false;
+        assert_eq!(bucket_endpoint(&config), "http://127.0.0.1:9000");
+        assert!(S3Client::new(&config).is_ok());
+    }
+}
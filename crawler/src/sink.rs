@@ -0,0 +1,414 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{info, warn};
+use parse::Message;
+use serde_json::json;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use utils::utils::{get_format_date, Gap};
+
+// 房间消息的写入目标, 一个房间可以同时写入多个 sink
+pub trait MessageSink {
+    fn name(&self) -> &str;
+
+    fn write(&mut self, message: &Message) -> Result<()>;
+
+    // 写出缓冲的数据
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // 尚未写出的消息数
+    fn buffered(&self) -> usize {
+        0
+    }
+
+    // 写入线程定时调用, 没有新消息时也能写出等待过久的数据
+    fn tick(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // 记录连接断开期间没有数据的时段, 默认不记录
+    fn record_gap(&mut self, _gap: &Gap) -> Result<()> {
+        Ok(())
+    }
+
+    // 出错时是否需要重启房间的写入线程, 默认只记录日志
+    fn critical(&self) -> bool {
+        false
+    }
+}
+
+// 把消息分发到房间的所有 sink, 单个 sink 出错不影响其他 sink 和房间
+// critical sink 的错误返回给写入线程, 由 supervise_room 重启, 其他 sink 的错误只记录日志
+pub struct Sinks<'a> {
+    room_id: i64,
+    sinks: Vec<Box<dyn MessageSink + 'a>>,
+}
+
+impl<'a> Sinks<'a> {
+    pub fn new(room_id: i64) -> Self {
+        Self {
+            room_id,
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, sink: impl MessageSink + 'a) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    pub fn write(&mut self, message: &Message) -> Result<()> {
+        self.each("写入", |sink| sink.write(message))
+    }
+
+    pub fn record_gap(&mut self, gap: &Gap) -> Result<()> {
+        self.each("记录断线时段", |sink| sink.record_gap(gap))
+    }
+
+    pub fn tick(&mut self) -> Result<()> {
+        self.each("tick", |sink| sink.tick())
+    }
+
+    // 所有 sink 都会执行, 返回第一个 critical sink 的错误
+    fn each(
+        &mut self,
+        action: &str,
+        mut f: impl FnMut(&mut (dyn MessageSink + 'a)) -> Result<()>,
+    ) -> Result<()> {
+        let mut result = Ok(());
+        for sink in &mut self.sinks {
+            if let Err(e) = f(sink.as_mut()) {
+                warn!(
+                    "room {} {} {}出错: {:?}",
+                    self.room_id,
+                    sink.name(),
+                    action,
+                    e
+                );
+                if sink.critical() && result.is_ok() {
+                    result = Err(e.context(format!("{} {}出错", sink.name(), action)));
+                }
+            }
+        }
+        result
+    }
+
+    // 所有 sink 都会 flush, 出错的 sink 合并到返回的错误中
+    pub fn flush(&mut self) -> Result<()> {
+        let errors: Vec<String> = self
+            .sinks
+            .iter_mut()
+            .filter_map(|sink| {
+                sink.flush()
+                    .err()
+                    .map(|e| format!("{}: {}", sink.name(), e))
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("{}", errors.join("; ")))
+        }
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.sinks.iter().map(|sink| sink.buffered()).sum()
+    }
+}
+
+// 写入 {dir}/{date}/{room_id}.jsonl, 每行一条消息, date 为消息自身的日期
+pub struct JsonlSink {
+    dir: PathBuf,
+    room_id: i64,
+    // 跨天时前一天的文件保持打开, flush 后只保留最新日期的文件
+    writers: BTreeMap<String, BufWriter<File>>,
+}
+
+impl JsonlSink {
+    pub fn new(dir: &Path, room_id: i64, timestamp: i64) -> Result<Self> {
+        let date = get_format_date(timestamp)?;
+        let writer = Self::open(dir, room_id, &date)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            room_id,
+            writers: BTreeMap::from([(date, writer)]),
+        })
+    }
+
+    fn open(dir: &Path, room_id: i64, date: &str) -> Result<BufWriter<File>> {
+        let dir = dir.join(date);
+        fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(format!("{}.jsonl", room_id)))?;
+        Ok(BufWriter::new(file))
+    }
+}
+
+impl MessageSink for JsonlSink {
+    fn name(&self) -> &str {
+        "jsonl"
+    }
+
+    fn write(&mut self, message: &Message) -> Result<()> {
+        let timestamp = message
+            .timestamp()
+            .unwrap_or_else(|| Utc::now().timestamp());
+        let date = get_format_date(timestamp)?;
+        let writer = match self.writers.entry(date) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let writer = Self::open(&self.dir, self.room_id, entry.key())?;
+                entry.insert(writer)
+            }
+        };
+        serde_json::to_writer(&mut *writer, message)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for writer in self.writers.values_mut() {
+            writer.flush()?;
+        }
+        while self.writers.len() > 1 {
+            self.writers.pop_first();
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        MessageSink::flush(self)
+    }
+}
+
+pub struct StdoutSink {
+    room_id: i64,
+}
+
+impl StdoutSink {
+    pub fn new(room_id: i64) -> Self {
+        Self { room_id }
+    }
+}
+
+impl MessageSink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write(&mut self, message: &Message) -> Result<()> {
+        if let Some(line) = pretty(self.room_id, message) {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+}
+
+fn pretty(room_id: i64, message: &Message) -> Option<String> {
+    let line = match message {
+        Message::Danmu(msg) => format!("{}({}): {}", msg.username, msg.uid, msg.msg),
+        Message::SuperChat(msg) => {
+            format!(
+                "[SC ¥{}] {}({}): {}",
+                msg.worth, msg.username, msg.uid, msg.msg
+            )
+        }
+        Message::EnterRoom(msg) => format!("{}({}) 进入直播间", msg.username, msg.uid),
+        Message::BlockUser(msg) => format!(
+            "{}({}) 被 {:?} 禁言, 到期 {}",
+            msg.username, msg.uid, msg.operator, msg.block_expired
+        ),
+        Message::OnlineCount(msg) => format!("在线人数 {}", msg.count),
+        Message::Default => return None,
+    };
+    Some(format!("[{}] {}", room_id, line))
+}
+
+const WEBHOOK_QUEUE: usize = 1024;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 消息放入队列后由后台任务逐条 POST {"room_id": .., "message": ..},
+// 请求慢或失败不阻塞房间, 队列满时丢弃
+pub struct WebhookSink {
+    room_id: i64,
+    tx: mpsc::Sender<serde_json::Value>,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str, room_id: i64) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+        let (tx, mut rx) = mpsc::channel::<serde_json::Value>(WEBHOOK_QUEUE);
+        let url = url.to_string();
+        tokio::spawn(async move {
+            while let Some(body) = rx.recv().await {
+                let result = client
+                    .post(&url)
+                    .json(&body)
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status());
+                if let Err(e) = result {
+                    warn!("room {} 发送 webhook {} 出错: {}", room_id, url, e);
+                }
+            }
+            info!("room {} webhook {} 已停止", room_id, url);
+        });
+        Ok(Self { room_id, tx })
+    }
+}
+
+impl MessageSink for WebhookSink {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn write(&mut self, message: &Message) -> Result<()> {
+        self.tx
+            .try_send(json!({ "room_id": self.room_id, "message": message }))
+            .map_err(|e| anyhow!("webhook 队列已满, 丢弃消息: {}", e))
+    }
+
+    fn buffered(&self) -> usize {
+        WEBHOOK_QUEUE - self.tx.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse::DanmuMessage;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Failing;
+
+    impl MessageSink for Failing {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn write(&mut self, _message: &Message) -> Result<()> {
+            Err(anyhow!("broken"))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Err(anyhow!("broken"))
+        }
+    }
+
+    struct Critical;
+
+    impl MessageSink for Critical {
+        fn name(&self) -> &str {
+            "critical"
+        }
+
+        fn write(&mut self, _message: &Message) -> Result<()> {
+            Err(anyhow!("broken"))
+        }
+
+        fn critical(&self) -> bool {
+            true
+        }
+    }
+
+    struct Recording(Rc<RefCell<Vec<Message>>>);
+
+    impl MessageSink for Recording {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn write(&mut self, message: &Message) -> Result<()> {
+            self.0.borrow_mut().push(message.clone());
+            Ok(())
+        }
+    }
+
+    fn danmu(msg: &str) -> Message {
+        danmu_at(msg, 1720973747)
+    }
+
+    fn danmu_at(msg: &str, timestamp: u64) -> Message {
+        Message::Danmu(DanmuMessage {
+            uid: 1,
+            username: "user".to_string(),
+            msg: msg.to_string(),
+            timestamp,
+            id: msg.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_sinks_isolation() {
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let mut sinks = Sinks::new(1);
+        sinks.push(Failing);
+        sinks.push(Recording(messages.clone()));
+        sinks.write(&danmu("a")).unwrap();
+        sinks.write(&danmu("b")).unwrap();
+        // 前面的 sink 出错时后面的 sink 仍然收到消息
+        assert_eq!(messages.borrow().len(), 2);
+        let err = sinks.flush().unwrap_err().to_string();
+        assert_eq!(err, "failing: broken");
+    }
+
+    #[test]
+    fn test_sinks_critical() {
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let mut sinks = Sinks::new(1);
+        sinks.push(Critical);
+        sinks.push(Failing);
+        sinks.push(Recording(messages.clone()));
+        // critical sink 出错时返回错误, 其他 sink 仍然收到消息
+        let err = sinks.write(&danmu("a")).unwrap_err();
+        assert_eq!(err.root_cause().to_string(), "broken");
+        assert_eq!(messages.borrow().len(), 1);
+        assert!(sinks.tick().is_ok());
+    }
+
+    #[test]
+    fn test_jsonl_sink() {
+        let dir = std::env::temp_dir().join(format!("jsonl-sink-test-{}", std::process::id()));
+        // 2024-07-15 23:59:59 (UTC+8)
+        let before_midnight = 1721059199;
+        let mut sink = JsonlSink::new(&dir, 1, before_midnight as i64).unwrap();
+        sink.write(&danmu_at("a", before_midnight)).unwrap();
+        sink.write(&danmu_at("b", before_midnight + 1)).unwrap();
+        // 零点后才收到的前一天的消息仍然写入前一天的文件
+        sink.write(&danmu_at("c", before_midnight - 1)).unwrap();
+        sink.write(&danmu_at("d", before_midnight + 2)).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(sink.writers.len(), 1);
+
+        let read = |date: &str| {
+            fs::read_to_string(dir.join(date).join("1.jsonl"))
+                .unwrap()
+                .lines()
+                .map(|line| match serde_json::from_str(line).unwrap() {
+                    Message::Danmu(msg) => msg.msg,
+                    _ => panic!("unexpected message"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read("2024-07-15"), ["a", "c"]);
+        assert_eq!(read("2024-07-16"), ["b", "d"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pretty() {
+        assert_eq!(pretty(1, &danmu("hi")).unwrap(), "[1] user(1): hi");
+        assert!(pretty(1, &Message::Default).is_none());
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use log::warn;
+use parse::Message;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// 写入 spool 的消息, day 为消息所属日期 0 点的时间戳, 回放时按消息自身的时间写入
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpoolRecord {
+    pub day: i64,
+    pub message: Message,
+}
+
+// 每个房间一个追加写入的本地文件, 消息先写入 spool 再写入内存缓冲,
+// 上传成功后清空, 进程崩溃后重启时回放未上传的消息
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+}
+
+impl Spool {
+    // 打开 spool 文件并返回其中尚未上传的消息
+    pub fn open(dir: &Path, room_id: i64) -> Result<(Self, Vec<SpoolRecord>)> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.jsonl", room_id));
+        let content = match std::fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let records = read_records(&path, &content);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // 补全不完整的最后一行, 避免和之后写入的消息连在一起
+        if !content.is_empty() && !content.ends_with(b"\n") {
+            file.write_all(b"\n")?;
+        }
+        Ok((Self { path, file }, records))
+    }
+
+    // 每条消息单独写入, 进程崩溃时已写入的消息由操作系统落盘
+    pub fn append(&mut self, day: i64, message: &Message) -> Result<()> {
+        #[derive(Serialize)]
+        struct RecordRef<'a> {
+            day: i64,
+            message: &'a Message,
+        }
+        let mut line = serde_json::to_vec(&RecordRef { day, message })?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+
+    pub fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn read_records(path: &Path, content: &[u8]) -> Vec<SpoolRecord> {
+    let mut records = vec![];
+    for (index, line) in content.split(|b| *b == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_slice(line) {
+            Ok(record) => records.push(record),
+            // 崩溃时最后一行可能只写入了一部分
+            Err(e) => warn!("跳过 {} 第 {} 行: {}", path.display(), index + 1, e),
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse::DanmuMessage;
+
+    fn danmu(msg: &str) -> Message {
+        Message::Danmu(DanmuMessage {
+            uid: 10000,
+            username: "Alice".to_string(),
+            msg: msg.to_string(),
+            timestamp: 1720973747,
+            id: msg.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_spool() {
+        let dir = std::env::temp_dir().join(format!("spool-test-{}", std::process::id()));
+        let (mut spool, records) = Spool::open(&dir, 1).unwrap();
+        assert!(records.is_empty());
+        spool.append(100, &danmu("a")).unwrap();
+        spool.append(200, &danmu("b")).unwrap();
+        drop(spool);
+
+        // 模拟崩溃时写了一半的最后一行
+        let path = dir.join("1.jsonl");
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"day\":200,\"mess").unwrap();
+        drop(file);
+
+        let (mut spool, records) = Spool::open(&dir, 1).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].day, 200);
+        assert!(matches!(&records[1].message, Message::Danmu(m) if m.msg == "b"));
+        spool.append(300, &danmu("c")).unwrap();
+        drop(spool);
+        let (mut spool, records) = Spool::open(&dir, 1).unwrap();
+        assert_eq!(records.len(), 3);
+
+        spool.truncate().unwrap();
+        spool.append(400, &danmu("d")).unwrap();
+        drop(spool);
+        let (_, records) = Spool::open(&dir, 1).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].day, 400);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,38 +1,88 @@
+use crate::flush::{BufferStats, FlushPolicy};
+use crate::metrics::Metrics;
+use crate::sink::MessageSink;
+use crate::spool::Spool;
 use anyhow::Result;
-use chrono::{Duration, Utc};
+use chrono::Utc;
 use duckdb::{params, Appender, Connection};
-use log::{debug, info};
-use parse::{BlockUserMessage, DanmuMessage, SuperChatMessage};
+use log::{debug, info, warn};
+use parse::{BlockUserMessage, DanmuMessage, EnterRoomMessage, Message, SuperChatMessage};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::Path;
 use std::sync::atomic;
-use utils::utils::{get_table_name, remote_block_user_table_name, MessageType, OssConfig};
+use std::sync::Arc;
+use std::time::Instant;
+use utils::location::StorageLocation;
+use utils::rooms::FlushConfig;
+use utils::utils::{
+    get_enter_table_name, get_gap_table_name, get_local_midnight, get_part_name, get_table_name,
+    remote_block_user_table_name, Gap, MessageType,
+};
 
 pub struct Storage<'a> {
     conn: &'a Connection,
     danmu_message_buffer: Appender<'a>,
     danmu_message_buffer_size: atomic::AtomicI32,
-    last_flush_timestamp: i64,
-    bucket: String,
-    timestamp: i64,
+    enter_room_buffer: Appender<'a>,
+    enter_room_buffer_size: usize,
+    spool: Option<Spool>,
+    // 缓冲的估算字节数和最早一条消息写入的时间
+    buffered_bytes: usize,
+    oldest_buffered: Option<i64>,
+    flush_policy: Box<dyn FlushPolicy>,
+    recent_ids: RecentIds,
+    metrics: Option<Arc<Metrics>>,
+    part_seq: u64,
+    // 写入失败的 part 文件名, 重试时覆盖同一个文件, 避免失败前已写出的 part 重复
+    pending_parts: HashMap<String, String>,
+    // 已创建文件的日期, 和缓冲中有数据的日期, 均为当天 0 点的时间戳
+    days: HashSet<i64>,
+    danmu_days: BTreeSet<i64>,
+    enter_days: BTreeSet<i64>,
+    location: StorageLocation,
     room_id: i64,
 }
 
 impl<'a> Storage<'a> {
     pub fn new(conn: &'a Connection, room_id: i64, timestamp: i64) -> Result<Self> {
-        let oss_config = OssConfig::new()?;
-        oss_config.clone().init_oss_with_conn(conn)?;
-        Self::init_table(conn, &oss_config.bucket, room_id, timestamp)?;
-        Ok(Self {
+        Self::with_location(conn, StorageLocation::from_env()?, room_id, timestamp)
+    }
+
+    pub fn with_location(
+        conn: &'a Connection,
+        location: StorageLocation,
+        room_id: i64,
+        timestamp: i64,
+    ) -> Result<Self> {
+        location.init_conn(conn)?;
+        Self::init_table(conn, &location)?;
+        let mut storage = Self {
             conn,
             danmu_message_buffer: conn.appender("danmu")?,
             danmu_message_buffer_size: atomic::AtomicI32::new(0),
-            last_flush_timestamp: Utc::now().timestamp(),
-            bucket: oss_config.bucket,
+            enter_room_buffer: conn.appender(ENTER_ROOM_TABLE)?,
+            enter_room_buffer_size: 0,
+            spool: None,
+            buffered_bytes: 0,
+            oldest_buffered: None,
+            flush_policy: Box::new(FlushConfig::default()),
+            recent_ids: RecentIds::new(RECENT_IDS),
+            metrics: None,
+            part_seq: 0,
+            pending_parts: HashMap::new(),
+            days: HashSet::new(),
+            danmu_days: BTreeSet::new(),
+            enter_days: BTreeSet::new(),
+            location,
             room_id,
-            timestamp,
-        })
+        };
+        storage.init_day(get_local_midnight(timestamp)?)?;
+        Ok(storage)
     }
 
-    fn init_table(conn: &Connection, bucket: &str, room_id: i64, timestamp: i64) -> Result<()> {
+    // 创建本地缓冲表和禁言表, 只在启动时调用一次
+    fn init_table(conn: &Connection, location: &StorageLocation) -> Result<()> {
+        let root = location.root();
         conn.execute(
             "CREATE TABLE IF NOT EXISTS danmu (
                 msg_type UTINYINT,
@@ -41,23 +91,38 @@ impl<'a> Storage<'a> {
                 msg TEXT,
                 timestamp BIGINT,
                 worth FLOAT DEFAULT 0,
+                id TEXT,
             )",
             [],
         )?;
-        let danmu_target = get_table_name(bucket, room_id, timestamp)?;
-        // check file exists
-        if conn
-            .execute(
-                &format!("SELECT COUNT(*) as count FROM '{danmu_target}'"),
-                [],
-            )
-            .is_err()
-        {
-            conn.execute(&format!("COPY danmu TO '{danmu_target}'"), [])?;
-        }
+
+        // 进房记录只保留 uid, 用户名和时间
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {ENTER_ROOM_TABLE} (
+                uid BIGINT,
+                username TEXT,
+                timestamp BIGINT,
+                id TEXT,
+            )"
+            ),
+            [],
+        )?;
+
+        // 断线时段, timestamp 为开始时间, 和消息一样按 timestamp 分天
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {GAP_TABLE} (
+                timestamp BIGINT,
+                end_timestamp BIGINT,
+                reason TEXT,
+            )"
+            ),
+            [],
+        )?;
 
         // init block user table
-        let remote_block_user_table_name = remote_block_user_table_name(bucket);
+        let remote_block_user_table_name = remote_block_user_table_name(&root);
         let local_table = "block_user".to_string();
         if conn
             .execute(
@@ -80,6 +145,7 @@ impl<'a> Storage<'a> {
                 .as_str(),
                 [],
             )?;
+            location.prepare(&remote_block_user_table_name)?;
             conn.execute(
                 &format!("COPY {local_table} TO '{remote_block_user_table_name}'"),
                 [],
@@ -96,34 +162,46 @@ impl<'a> Storage<'a> {
         Ok(())
     }
 
-    pub fn create_super_chat_message(&mut self, message: SuperChatMessage) -> Result<()> {
-        self.danmu_message_buffer.append_row(params![
-            i8::from(MessageType::SuperChat),
-            message.uid,
-            message.username,
-            message.msg,
-            message.timestamp,
-            message.worth
-        ])?;
-        self.danmu_message_buffer_size
-            .fetch_add(1, atomic::Ordering::SeqCst);
-        self.flush_with_strategy(strategy_with_time_and_count)?;
+    // 第一次写入某一天的消息时创建当天的空文件, 查询时不会因为文件不存在而出错
+    fn init_day(&mut self, day: i64) -> Result<()> {
+        if self.days.contains(&day) {
+            return Ok(());
+        }
+        let root = self.location.root();
+        let targets = [
+            (get_table_name(&root, self.room_id, day)?, "danmu"),
+            (
+                get_enter_table_name(&root, self.room_id, day)?,
+                ENTER_ROOM_TABLE,
+            ),
+        ];
+        for (target, local_table) in targets {
+            // 只在文件不存在时创建, 读取出错时不能覆盖已有的数据
+            let files: i64 = self.conn.query_row(
+                &format!("SELECT COUNT(*) FROM glob('{target}')"),
+                [],
+                |row| row.get(0),
+            )?;
+            if files == 0 {
+                self.location.prepare(&target)?;
+                // 只写入表结构, 缓冲中的消息由 flush 写入 part 文件
+                self.conn.execute(
+                    &format!("COPY (SELECT * FROM {local_table} LIMIT 0) TO '{target}'"),
+                    [],
+                )?;
+            }
+        }
+        self.days.insert(day);
         Ok(())
     }
 
+    pub fn create_super_chat_message(&mut self, message: SuperChatMessage) -> Result<()> {
+        self.create_message(&Message::SuperChat(message))
+    }
+
     pub fn create_block_user_message(&mut self, message: BlockUserMessage) -> Result<()> {
-        let remote_block_user_table_name = remote_block_user_table_name(self.bucket.as_str());
-        let stmt = format!(
-            "INSERT INTO 'block_user' (uid, username, room_id, operator, timestamp, block_expired)
-             VALUES ({}, '{}', {}, {}, {}, {})",
-            message.uid,
-            message.username,
-            self.room_id,
-            i16::from(message.operator),
-            message.timestamp,
-            message.block_expired,
-        );
-        self.conn.execute(stmt.as_str(), [])?;
+        let remote_block_user_table_name = remote_block_user_table_name(&self.location.root());
+        append_block_user(self.conn, self.room_id, message)?;
         self.conn.execute(
             &format!("COPY 'block_user'  TO '{remote_block_user_table_name}'"),
             [],
@@ -131,118 +209,448 @@ impl<'a> Storage<'a> {
         Ok(())
     }
 
+    // 断线很少发生, 每段记录立即写入当天的 part 文件
+    pub fn record_gap(&mut self, gap: &Gap) -> Result<()> {
+        let root = self.location.root();
+        for gap in gap.split_by_day()? {
+            self.conn.execute(
+                &format!("INSERT INTO {GAP_TABLE} VALUES (?, ?, ?)"),
+                params![gap.start, gap.end, gap.reason],
+            )?;
+            let day = get_local_midnight(gap.start)?;
+            let target = get_gap_table_name(&root, self.room_id, day)?;
+            self.write_part(&target, GAP_TABLE, day)?;
+            self.pending_parts.remove(&target);
+        }
+        Ok(())
+    }
+
     pub fn create_danmu_message(&mut self, message: DanmuMessage) -> Result<()> {
         debug!(
             "receive danmu count: {}",
             self.danmu_message_buffer_size
                 .load(atomic::Ordering::SeqCst)
         );
-        self.danmu_message_buffer.append_row(params![
-            i8::from(MessageType::Danmu),
-            message.uid,
-            message.username,
-            message.msg,
-            message.timestamp,
-            0.0
-        ])?;
-        self.danmu_message_buffer_size
-            .fetch_add(1, atomic::Ordering::SeqCst);
-        self.flush_with_strategy(strategy_with_time_and_count)?;
+        self.create_message(&Message::Danmu(message))
+    }
+
+    pub fn create_enter_room_message(&mut self, message: EnterRoomMessage) -> Result<()> {
+        self.create_message(&Message::EnterRoom(message))
+    }
+
+    // 先写入 spool 再写入内存缓冲
+    fn create_message(&mut self, message: &Message) -> Result<()> {
+        if let Some(spool) = &mut self.spool {
+            let timestamp = message
+                .timestamp()
+                .unwrap_or_else(|| Utc::now().timestamp());
+            spool.append(get_local_midnight(timestamp)?, message)?;
+        }
+        self.append(message)?;
+        self.tick()
+    }
+
+    // 消息按自身的时间写入对应日期的缓冲, 跨天时前一天的消息仍然写入前一天的文件
+    fn append(&mut self, message: &Message) -> Result<()> {
+        let (Some(id), Some(timestamp)) = (message.stable_id(), message.timestamp()) else {
+            return Ok(());
+        };
+        let day = get_local_midnight(timestamp)?;
+        self.init_day(day)?;
+        if !self.recent_ids.insert(&id) {
+            debug!("skip duplicate message {}", id);
+            return Ok(());
+        }
+        match message {
+            Message::Danmu(message) => {
+                self.danmu_message_buffer.append_row(params![
+                    i8::from(MessageType::Danmu),
+                    message.uid,
+                    message.username,
+                    message.msg,
+                    message.timestamp,
+                    0.0,
+                    id
+                ])?;
+                self.danmu_message_buffer_size
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+                self.danmu_days.insert(day);
+            }
+            Message::SuperChat(message) => {
+                self.danmu_message_buffer.append_row(params![
+                    i8::from(MessageType::SuperChat),
+                    message.uid,
+                    message.username,
+                    message.msg,
+                    message.timestamp,
+                    message.worth,
+                    id
+                ])?;
+                self.danmu_message_buffer_size
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+                self.danmu_days.insert(day);
+            }
+            Message::EnterRoom(message) => {
+                self.enter_room_buffer.append_row(params![
+                    message.uid,
+                    message.username,
+                    message.timestamp,
+                    id
+                ])?;
+                self.enter_room_buffer_size += 1;
+                self.enter_days.insert(day);
+            }
+            _ => return Ok(()),
+        }
+        self.buffered_bytes += row_bytes(message, &id);
+        self.oldest_buffered.get_or_insert(Utc::now().timestamp());
         Ok(())
     }
 
-    fn merge_data_and_persist(&self, persist_target: &str, local_table_local: &str) -> Result<()> {
-        // check persist target exists
-        if let Err(e) = self.conn.execute(
-            &format!("SELECT COUNT(*) as count FROM '{persist_target}'"),
-            [],
-        ) {
-            debug!("check persist target exists failed: {}", e);
+    // 打开房间的 spool, 上次退出前未上传的消息按各自的时间写入对应日期的文件后清空
+    pub fn open_spool(&mut self, dir: &Path) -> Result<usize> {
+        let (mut spool, records) = Spool::open(dir, self.room_id)?;
+        if records.is_empty() {
+            self.spool = Some(spool);
+            return Ok(0);
         }
-        // load existing data
+        info!(
+            "回放 {} 中的 {} 条消息",
+            spool.path().display(),
+            records.len()
+        );
+        for record in &records {
+            self.append(&record.message)?;
+        }
+        self.flush()?;
+        // 回放中途失败时保留 spool, 下次启动重新回放
+        spool.truncate()?;
+        self.spool = Some(spool);
+        Ok(records.len())
+    }
+
+    // 每次 flush 写入一个新的 part 文件, 不读取和改写已有的文件, 由 compact 在收盘后合并
+    // 只写入 local_table 中属于 day 的行, 上次写入失败时沿用上次的文件名
+    fn write_part(&mut self, target: &str, local_table: &str, day: i64) -> Result<()> {
+        let part = match self.pending_parts.get(target) {
+            Some(part) => part.clone(),
+            None => {
+                self.part_seq += 1;
+                let id = format!(
+                    "{}-{}-{}",
+                    Utc::now().timestamp_millis(),
+                    std::process::id(),
+                    self.part_seq
+                );
+                let part = get_part_name(target, &id);
+                self.pending_parts.insert(target.to_string(), part.clone());
+                part
+            }
+        };
+        let filter = day_filter(day)?;
+        self.location.prepare(&part)?;
         self.conn.execute(
-            &format!("CREATE TABLE existing_data AS SELECT * FROM '{persist_target}'"),
+            &format!("COPY (SELECT * FROM {local_table} WHERE {filter}) TO '{part}'"),
             [],
         )?;
-        // merge data
-        self.conn.execute(
-            &format!("CREATE TABLE merged_data AS SELECT * FROM existing_data UNION ALL SELECT * FROM {local_table_local}"), [],
-        )?;
         self.conn
-            .execute(&format!("COPY merged_data TO '{persist_target}'"), [])?;
-        // clean
-        self.conn.execute("DROP TABLE existing_data", [])?;
-        self.conn.execute("DROP TABLE merged_data", [])?;
-        self.conn
-            .execute(&format!("DELETE FROM {local_table_local}"), [])?;
+            .execute(&format!("DELETE FROM {local_table} WHERE {filter}"), [])?;
+        debug!("write part {}", part);
+        Ok(())
+    }
+
+    // 缓冲中每个有数据的日期写入一个 part 文件, 没有新数据时不写入空的 part 文件
+    fn write_parts(&mut self) -> Result<()> {
+        let root = self.location.root();
+        for day in self.danmu_days.clone() {
+            let danmu_target = get_table_name(&root, self.room_id, day)?;
+            self.write_part(&danmu_target, &MessageType::Danmu.to_string(), day)?;
+        }
+        for day in self.enter_days.clone() {
+            let enter_target = get_enter_table_name(&root, self.room_id, day)?;
+            self.write_part(&enter_target, ENTER_ROOM_TABLE, day)?;
+        }
         Ok(())
     }
 
-    fn flush_with_strategy(&mut self, strategy: fn(&mut Storage) -> bool) -> Result<()> {
-        if strategy(self) {
+    // 尚未写入远端的消息数
+    pub fn buffered(&self) -> usize {
+        self.danmu_message_buffer_size
+            .load(atomic::Ordering::SeqCst) as usize
+            + self.enter_room_buffer_size
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        BufferStats {
+            rows: self
+                .danmu_message_buffer_size
+                .load(atomic::Ordering::SeqCst) as usize,
+            enter_rows: self.enter_room_buffer_size,
+            bytes: self.buffered_bytes,
+            oldest: self.oldest_buffered,
+        }
+    }
+
+    pub fn set_flush_policy(&mut self, policy: impl FlushPolicy + 'static) {
+        self.flush_policy = Box::new(policy);
+    }
+
+    // 按 flush 策略检查是否需要写出, 收到消息和定时 tick 时调用
+    pub fn tick(&mut self) -> Result<()> {
+        if self
+            .flush_policy
+            .should_flush(&self.stats(), Utc::now().timestamp())
+        {
             self.flush()?;
         }
         Ok(())
     }
 
+    // 记录 flush 耗时和行数
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
     pub fn flush(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let rows = self.buffered();
         self.danmu_message_buffer.flush()?;
+        self.enter_room_buffer.flush()?;
+        // 所有 part 在一个事务中写入, 任一失败时回滚已执行的 DELETE, 缓冲和 spool 保留到下次 flush
+        self.conn.execute_batch("BEGIN TRANSACTION")?;
+        if let Err(e) = self
+            .write_parts()
+            .and_then(|_| Ok(self.conn.execute_batch("COMMIT")?))
+        {
+            if let Err(rollback) = self.conn.execute_batch("ROLLBACK") {
+                warn!("rollback flush error: {}", rollback);
+            }
+            return Err(e);
+        }
+        self.pending_parts.clear();
+        self.danmu_days.clear();
+        self.enter_days.clear();
         self.danmu_message_buffer_size
             .store(0, atomic::Ordering::SeqCst);
-
-        let danmu_target = get_table_name(&self.bucket, self.room_id, self.timestamp)?;
-
-        self.merge_data_and_persist(&danmu_target, &MessageType::Danmu.to_string())?;
+        self.enter_room_buffer_size = 0;
+        self.buffered_bytes = 0;
+        self.oldest_buffered = None;
+        // 所有 part 上传成功后才清空 spool
+        if let Some(spool) = &mut self.spool {
+            spool.truncate()?;
+        }
+        if let Some(metrics) = self.metrics.as_ref().filter(|_| rows > 0) {
+            metrics.observe_flush(self.room_id, start.elapsed().as_secs_f64(), rows);
+        }
         info!("flush success");
 
         Ok(())
     }
+}
+
+// 只写入存储的消息类型, 其余消息忽略
+impl MessageSink for Storage<'_> {
+    fn name(&self) -> &str {
+        "parquet"
+    }
 
-    pub fn switch_new_date(&mut self, timestamp: i64) -> Result<()> {
-        // flush exist data
-        self.flush()?;
-        // change timestamp
-        self.timestamp = timestamp;
-        Self::init_table(self.conn, &self.bucket, self.room_id, timestamp)?;
-        Ok(())
+    fn write(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::BlockUser(msg) => self.create_block_user_message(msg.clone()),
+            Message::Danmu(_) | Message::SuperChat(_) | Message::EnterRoom(_) => {
+                self.create_message(message)
+            }
+            Message::OnlineCount(_) | Message::Default => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Storage::flush(self)
     }
-}
 
-fn strategy_with_time_and_count(storage: &mut Storage) -> bool {
-    let count = storage
-        .danmu_message_buffer_size
-        .load(atomic::Ordering::SeqCst);
-    if count == 0 {
-        return false;
+    fn tick(&mut self) -> Result<()> {
+        Storage::tick(self)
     }
-    if count > 100 {
-        return true;
+
+    fn buffered(&self) -> usize {
+        Storage::buffered(self)
     }
-    let timestamp = Utc::now().timestamp();
-    // minimum flush interval is 5 minutes
-    if timestamp >= storage.last_flush_timestamp + Duration::minutes(5).num_seconds() {
-        storage.last_flush_timestamp = timestamp;
-        return true;
+
+    fn record_gap(&mut self, gap: &Gap) -> Result<()> {
+        Storage::record_gap(self, gap)
     }
-    false
+
+    // 写入失败的消息保留在 spool 中, 重启写入线程后回放
+    fn critical(&self) -> bool {
+        true
+    }
+}
+
+const ENTER_ROOM_TABLE: &str = "enter_room";
+const GAP_TABLE: &str = "gap";
+
+// 本地缓冲表中属于 day 这一天的行, day 为当天 0 点的时间戳
+fn day_filter(day: i64) -> Result<String> {
+    let next_day = get_local_midnight(day + 36 * 3600)?;
+    Ok(format!("timestamp >= {day} AND timestamp < {next_day}"))
+}
+
+// 每行除字段内容外的估算开销
+const ROW_OVERHEAD: usize = 32;
+
+fn row_bytes(message: &Message, id: &str) -> usize {
+    let content = match message {
+        Message::Danmu(msg) => msg.username.len() + msg.msg.len(),
+        Message::SuperChat(msg) => msg.username.len() + msg.msg.len(),
+        Message::EnterRoom(msg) => msg.username.len(),
+        _ => 0,
+    };
+    content + id.len() + ROW_OVERHEAD
+}
+
+// 同一进程内重连后重复收到的消息在写入缓冲前丢弃,
+// 其他 crawler 写入的重复消息在查询, 统计和合并时按 id 去重
+const RECENT_IDS: usize = 100_000;
+
+struct RecentIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl RecentIds {
+    fn new(capacity: usize) -> Self {
+        Self {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    // 已存在时返回 false
+    fn insert(&mut self, id: &str) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        true
+    }
+}
+
+// 禁言记录很少, 每条单独写入后立即上传
+fn append_block_user(conn: &Connection, room_id: i64, message: BlockUserMessage) -> Result<()> {
+    let mut appender = conn.appender("block_user")?;
+    appender.append_row(params![
+        message.uid,
+        message.username,
+        room_id,
+        i16::from(message.operator),
+        message.timestamp,
+        message.block_expired,
+    ])?;
+    appender.flush()?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use chrono::Utc;
-    use dotenv::dotenv;
     use parse::BlockUserEnum;
+    use std::path::PathBuf;
+
+    // 写入本地临时目录, 不依赖 OSS
+    fn init(name: &str) -> (PathBuf, StorageLocation) {
+        let _ = pretty_env_logger::try_init();
+        let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+        let location = StorageLocation::local(dir.to_str().unwrap());
+        (dir, location)
+    }
+
+    #[test]
+    fn test_recent_ids() {
+        let mut ids = RecentIds::new(2);
+        assert!(ids.insert("a"));
+        assert!(!ids.insert("a"));
+        assert!(ids.insert("b"));
+        // 超出容量时淘汰最早的 id
+        assert!(ids.insert("c"));
+        assert!(ids.insert("a"));
+        assert!(!ids.insert("c"));
+    }
+
+    #[test]
+    fn test_day_filter() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE danmu (timestamp BIGINT)")
+            .unwrap();
+        // 2024-07-14 23:59:59 和 2024-07-15 00:00:00 (UTC+8)
+        let before_midnight = 1720972799;
+        conn.execute(
+            "INSERT INTO danmu VALUES (?), (?), (?)",
+            params![before_midnight, before_midnight + 1, before_midnight - 3600],
+        )
+        .unwrap();
+        let count = |timestamp: i64| -> i64 {
+            let day = get_local_midnight(timestamp).unwrap();
+            conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM danmu WHERE {}",
+                    day_filter(day).unwrap()
+                ),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(count(before_midnight), 2);
+        assert_eq!(count(before_midnight + 1), 1);
+        assert_eq!(count(before_midnight + 86400 + 1), 0);
+    }
 
-    fn init() {
-        pretty_env_logger::init();
-        dotenv().ok();
+    #[test]
+    fn test_append_block_user() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE block_user (uid BIGINT, username TEXT, room_id BIGINT, operator UTINYINT, timestamp BIGINT, block_expired BIGINT)",
+        )
+        .unwrap();
+        let usernames = [
+            "it's",
+            "back\\slash",
+            "100%_",
+            "禁言'); DROP TABLE block_user; --",
+        ];
+        for (uid, username) in usernames.iter().enumerate() {
+            let message = BlockUserMessage {
+                uid: uid as u64,
+                username: username.to_string(),
+                operator: BlockUserEnum::Owner,
+                timestamp: 1720973747,
+                room_id: 0,
+                block_expired: 0,
+            };
+            append_block_user(&conn, 1, message).unwrap();
+        }
+        let stored = conn
+            .prepare("SELECT username FROM block_user WHERE room_id = 1 ORDER BY uid")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<duckdb::Result<Vec<String>>>()
+            .unwrap();
+        assert_eq!(stored, usernames);
     }
 
     #[test]
-    #[ignore]
     fn test_create_block_user_message() {
-        init();
+        let (dir, location) = init("block");
         let conn = Connection::open_in_memory().unwrap();
         let now = Utc::now();
         let block_user = BlockUserMessage {
@@ -253,14 +661,24 @@ mod tests {
             room_id: 22747736,
             block_expired: 123,
         };
-        let mut storage = Storage::new(&conn, 22747736, now.timestamp()).unwrap();
+        let mut storage =
+            Storage::with_location(&conn, location.clone(), 22747736, now.timestamp()).unwrap();
         storage.create_block_user_message(block_user).unwrap();
+        let remote_table = remote_block_user_table_name(&location.root());
+        let count: i64 = conn
+            .query_row(
+                &format!("SELECT COUNT(*) FROM '{remote_table}' WHERE uid = 10000"),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
-    #[ignore]
     fn test_storage_crate_danmu() {
-        init();
+        let (dir, location) = init("danmu");
         let conn = Connection::open_in_memory().unwrap();
         let now = Utc::now();
         let danmu = DanmuMessage {
@@ -268,12 +686,28 @@ mod tests {
             username: "Alice".to_string(),
             msg: "Hello, Bilibili".to_string(),
             timestamp: now.timestamp() as u64,
+            id: String::new(),
         };
         let room_id = 22747736;
-        let mut storage = Storage::new(&conn, room_id, now.timestamp()).unwrap();
-        for _ in 0..10 {
-            storage.create_danmu_message(danmu.clone()).unwrap();
+        let mut storage =
+            Storage::with_location(&conn, location.clone(), room_id, now.timestamp()).unwrap();
+        for i in 0..10 {
+            let id = i.to_string();
+            storage
+                .create_danmu_message(DanmuMessage {
+                    id,
+                    ..danmu.clone()
+                })
+                .unwrap();
         }
+        // 重复的消息不写入
+        let id = "0".to_string();
+        storage
+            .create_danmu_message(DanmuMessage {
+                id,
+                ..danmu.clone()
+            })
+            .unwrap();
         storage.danmu_message_buffer.flush().unwrap();
         conn.query_row(
             "SELECT COUNT(*) as count FROM danmu where msg_type = 1",
@@ -299,12 +733,12 @@ mod tests {
             Ok(())
         })
         .unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
-    #[ignore]
     fn test_storage_crate_super_chat() {
-        init();
+        let (dir, location) = init("super-chat");
         let conn = Connection::open_in_memory().unwrap();
         let now = Utc::now();
         let super_chat = SuperChatMessage {
@@ -313,14 +747,20 @@ mod tests {
             msg: "Hello, Bilibili".to_string(),
             timestamp: now.timestamp() as u64,
             worth: 100.0,
+            id: String::new(),
         };
 
         let room_id = 22747736;
-        let mut storage = Storage::new(&conn, room_id, now.timestamp()).unwrap();
+        let mut storage =
+            Storage::with_location(&conn, location.clone(), room_id, now.timestamp()).unwrap();
         for i in 0..10 {
             debug!("{}", i);
+            let id = i.to_string();
             storage
-                .create_super_chat_message(super_chat.clone())
+                .create_super_chat_message(SuperChatMessage {
+                    id,
+                    ..super_chat.clone()
+                })
                 .unwrap();
         }
         storage.danmu_message_buffer.flush().unwrap();
@@ -350,12 +790,231 @@ mod tests {
             Ok(())
         })
         .unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
-    #[ignore]
-    fn test_merge_data_and_persist() {
-        init();
+    fn test_storage_create_enter_room() {
+        let (dir, location) = init("enter");
+        let conn = Connection::open_in_memory().unwrap();
+        let now = Utc::now();
+        let enter = EnterRoomMessage {
+            uid: 10000,
+            username: "Alice".to_string(),
+            timestamp: now.timestamp() as u64,
+            id: String::new(),
+        };
+
+        let room_id = 22747736;
+        let mut storage =
+            Storage::with_location(&conn, location.clone(), room_id, now.timestamp()).unwrap();
+        for i in 0..10 {
+            let id = i.to_string();
+            storage
+                .create_enter_room_message(EnterRoomMessage {
+                    id,
+                    ..enter.clone()
+                })
+                .unwrap();
+        }
+        assert_eq!(storage.buffered(), 10);
+        storage.enter_room_buffer.flush().unwrap();
+        conn.query_row(
+            "SELECT COUNT(*) as count FROM enter_room where uid = 10000",
+            [],
+            |row| {
+                let count: i64 = row.get("count")?;
+                assert_eq!(count, 10);
+                Ok(())
+            },
+        )
+        .unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.buffered(), 0);
+        let table = get_enter_table_name(&location.root(), room_id, now.timestamp()).unwrap();
+        let count: i64 = conn
+            .query_row(
+                &format!("SELECT COUNT(*) FROM '{}'", get_part_name(&table, "*")),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 10);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush_across_midnight() {
+        let (dir, location) = init("midnight");
+        let conn = Connection::open_in_memory().unwrap();
+        // 2024-07-14 23:59:59 (UTC+8)
+        let before_midnight = 1720972799;
+        let room_id = 1;
+        let mut storage =
+            Storage::with_location(&conn, location.clone(), room_id, before_midnight).unwrap();
+        // 零点前后的消息交替到达, 包括零点后才收到的前一天的消息
+        for (i, timestamp) in [before_midnight, before_midnight + 1, before_midnight - 10]
+            .into_iter()
+            .enumerate()
+        {
+            storage
+                .create_danmu_message(DanmuMessage {
+                    uid: 10000,
+                    username: "Alice".to_string(),
+                    msg: "Hello, Bilibili".to_string(),
+                    timestamp: timestamp as u64,
+                    id: i.to_string(),
+                })
+                .unwrap();
+        }
+        assert_eq!(storage.danmu_days.len(), 2);
+        storage.flush().unwrap();
+        assert_eq!(storage.buffered(), 0);
+
+        let count = |timestamp: i64| -> i64 {
+            let table = get_table_name(&location.root(), room_id, timestamp).unwrap();
+            let parts = get_part_name(&table, "*");
+            conn.query_row(&format!("SELECT COUNT(*) FROM '{parts}'"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count(before_midnight), 2);
+        assert_eq!(count(before_midnight + 1), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_init_day() {
+        let (dir, location) = init("init-day");
+        let conn = Connection::open_in_memory().unwrap();
+        let now = Utc::now().timestamp();
+        let room_id = 1;
+        // 已有的文件读取出错时不覆盖
+        let target = get_table_name(&location.root(), room_id, now).unwrap();
+        location.prepare(&target).unwrap();
+        std::fs::write(&target, "not parquet").unwrap();
+        Storage::with_location(&conn, location.clone(), room_id, now).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"not parquet");
+
+        // 不存在的文件只写入表结构
+        let enter_target = get_enter_table_name(&location.root(), room_id, now).unwrap();
+        let count: i64 = conn
+            .query_row(
+                &format!("SELECT COUNT(*) FROM '{enter_target}'"),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush_rollback() {
+        let (dir, location) = init("rollback");
+        let conn = Connection::open_in_memory().unwrap();
+        let now = Utc::now().timestamp();
+        let room_id = 1;
+        let mut storage = Storage::with_location(&conn, location.clone(), room_id, now).unwrap();
+        for i in 0..10 {
+            storage
+                .create_danmu_message(DanmuMessage {
+                    uid: 10000,
+                    username: "Alice".to_string(),
+                    msg: "Hello, Bilibili".to_string(),
+                    timestamp: now as u64,
+                    id: i.to_string(),
+                })
+                .unwrap();
+            storage
+                .create_enter_room_message(EnterRoomMessage {
+                    uid: 10000,
+                    username: "Alice".to_string(),
+                    timestamp: now as u64,
+                    id: format!("enter-{}", i),
+                })
+                .unwrap();
+        }
+        // 弹幕 part 写入成功后, 进房 part 的上级目录是一个文件, 整个 flush 失败
+        let enter_target = get_enter_table_name(&location.root(), room_id, now).unwrap();
+        let blocked = dir.join("blocked");
+        std::fs::write(&blocked, "").unwrap();
+        storage.pending_parts.insert(
+            enter_target.clone(),
+            blocked.join("part.parquet").to_string_lossy().to_string(),
+        );
+        assert!(storage.flush().is_err());
+        assert_eq!(storage.buffered(), 20);
+
+        let count = |source: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {source}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        let danmu_parts = format!(
+            "'{}'",
+            get_part_name(
+                &get_table_name(&location.root(), room_id, now).unwrap(),
+                "*"
+            )
+        );
+        // 已写出的弹幕 part 保留, 但本地缓冲表中的数据被回滚
+        assert_eq!(count(&danmu_parts), 10);
+        assert_eq!(count("danmu"), 10);
+
+        // 重试时覆盖上次写出的 part, 不产生重复数据
+        storage.pending_parts.remove(&enter_target);
+        storage.flush().unwrap();
+        assert_eq!(storage.buffered(), 0);
+        assert_eq!(count(&danmu_parts), 10);
+        assert_eq!(count("danmu"), 0);
+        assert_eq!(
+            count(&format!("'{}'", get_part_name(&enter_target, "*"))),
+            10
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_record_gap() {
+        let (dir, location) = init("gap");
+        let conn = Connection::open_in_memory().unwrap();
+        // 2024-07-14 23:59:00 到 2024-07-15 00:01:00 (UTC+8)
+        let before_midnight = 1720972740;
+        let room_id = 1;
+        let mut storage =
+            Storage::with_location(&conn, location.clone(), room_id, before_midnight).unwrap();
+        storage
+            .record_gap(&Gap {
+                start: before_midnight,
+                end: before_midnight + 120,
+                reason: "closed".to_string(),
+            })
+            .unwrap();
+
+        let gaps = |timestamp: i64| -> Vec<(i64, i64)> {
+            let table = get_gap_table_name(&location.root(), room_id, timestamp).unwrap();
+            conn.prepare(&format!(
+                "SELECT timestamp, end_timestamp FROM '{}'",
+                get_part_name(&table, "*")
+            ))
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<duckdb::Result<_>>()
+            .unwrap()
+        };
+        // 跨天的断线时段分别写入两天的文件
+        assert_eq!(gaps(before_midnight), [(before_midnight, 1720972800)]);
+        assert_eq!(gaps(1720972800), [(1720972800, before_midnight + 120)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_part() {
+        let (dir, location) = init("part");
         let conn = Connection::open_in_memory().unwrap();
         let now = Utc::now();
         let danmu = DanmuMessage {
@@ -363,17 +1022,41 @@ mod tests {
             username: "Alice".to_string(),
             msg: "Hello, Bilibili".to_string(),
             timestamp: now.timestamp() as u64,
+            id: String::new(),
         };
         let room_id = 22747736;
-        let mut storage = Storage::new(&conn, room_id, now.timestamp()).unwrap();
-        for _ in 0..100 {
-            storage.create_danmu_message(danmu.clone()).unwrap();
+        let mut storage =
+            Storage::with_location(&conn, location.clone(), room_id, now.timestamp()).unwrap();
+        for i in 0..100 {
+            let id = i.to_string();
+            storage
+                .create_danmu_message(DanmuMessage {
+                    id,
+                    ..danmu.clone()
+                })
+                .unwrap();
         }
-        let oss_config = OssConfig::new().unwrap();
-        let danmu_target = get_table_name(&oss_config.bucket, room_id, now.timestamp()).unwrap();
-        println!("danmu_target: {}", danmu_target);
+        storage.danmu_message_buffer.flush().unwrap();
+        let danmu_target = get_table_name(&location.root(), room_id, now.timestamp()).unwrap();
         storage
-            .merge_data_and_persist(&danmu_target, &MessageType::Danmu.to_string())
+            .write_part(
+                &danmu_target,
+                &MessageType::Danmu.to_string(),
+                get_local_midnight(now.timestamp()).unwrap(),
+            )
             .unwrap();
+        let count = |source: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {source}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        // 写入 part 文件后从本地缓冲表删除
+        assert_eq!(
+            count(&format!("'{}'", get_part_name(&danmu_target, "*"))),
+            100
+        );
+        assert_eq!(count("danmu"), 0);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,66 @@
+use danmu_client::event::backoff;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// 房间任务的重启预算: 出错后按指数退避重启, window 内失败超过 max_failures 次时放弃
+pub struct RestartBudget {
+    max_failures: usize,
+    window: Duration,
+    failures: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub fn new(max_failures: usize, window: Duration) -> Self {
+        Self {
+            max_failures,
+            window,
+            failures: VecDeque::new(),
+        }
+    }
+
+    // 记录一次失败, 返回重启前需要等待的时间, 超出预算时返回 None
+    pub fn fail(&mut self, now: Instant) -> Option<Duration> {
+        while self
+            .failures
+            .front()
+            .is_some_and(|failure| now.duration_since(*failure) >= self.window)
+        {
+            self.failures.pop_front();
+        }
+        self.failures.push_back(now);
+        if self.failures.len() > self.max_failures {
+            return None;
+        }
+        let failures = self.failures.len() as u32;
+        Some(backoff(INITIAL_BACKOFF, MAX_BACKOFF, failures))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_budget() {
+        let mut budget = RestartBudget::new(3, Duration::from_secs(600));
+        let start = Instant::now();
+        assert_eq!(budget.fail(start), Some(Duration::from_secs(1)));
+        assert_eq!(
+            budget.fail(start + Duration::from_secs(10)),
+            Some(Duration::from_secs(2))
+        );
+        // 早于窗口的失败不计入预算
+        assert_eq!(
+            budget.fail(start + Duration::from_secs(600)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            budget.fail(start + Duration::from_secs(601)),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(budget.fail(start + Duration::from_secs(602)), None);
+    }
+}
@@ -13,11 +13,12 @@ async fn main() -> anyhow::Result<()> {
     let roomid = 22747736;
 
     let client = Client::new(roomid, &cookies)?;
-    let mut rx = client.listen().await?;
+    let mut handle = client.listen().await?;
 
-    while let Some(message) = rx.recv().await {
+    while let Some(message) = handle.recv().await {
         print_danmu(message);
     }
+    println!("连接断开: {}", handle.close().await);
 
     Ok(())
 }
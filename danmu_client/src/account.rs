@@ -0,0 +1,213 @@
+use crate::danmu::{build_http_client, ApiResponse, Endpoints};
+use crate::proxy::Proxy;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use cookie::Cookie;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// 触发风控后至少等待该时间再重新使用
+pub(crate) const RISK_CONTROL_COOLDOWN: i64 = 30 * 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AccountStatus {
+    Unchecked,                      // 尚未检查
+    Healthy,                        // nav 返回已登录
+    LoggedOut,                      // cookie 已失效
+    RiskControlled { since: i64 },  // getDanmuInfo 触发风控
+    CheckFailed { reason: String }, // 请求 nav 失败, 仍然可以分配房间
+}
+
+impl AccountStatus {
+    pub fn is_usable(&self) -> bool {
+        matches!(
+            self,
+            AccountStatus::Unchecked | AccountStatus::Healthy | AccountStatus::CheckFailed { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountInfo {
+    pub uid: u64,
+    pub uname: String,
+    pub status: AccountStatus,
+    pub last_check: i64,
+    pub rooms: Vec<u64>,
+}
+
+// 账号及其 http client, 使用代理的房间按代理分别创建
+pub(crate) struct Account {
+    pub(crate) cookies: String,
+    pub(crate) http: reqwest::Client,
+    pub(crate) proxy_http: HashMap<Proxy, reqwest::Client>,
+    pub(crate) info: AccountInfo,
+}
+
+impl Account {
+    pub(crate) fn new(cookies: &str) -> Result<Self> {
+        let uid = Cookie::split_parse(cookies)
+            .filter_map(|cookie| cookie.ok())
+            .find(|cookie| cookie.name() == "DedeUserID")
+            .and_then(|cookie| cookie.value().parse().ok())
+            .ok_or_else(|| anyhow!("DedeUserID not found in cookies"))?;
+        Ok(Self {
+            cookies: cookies.to_string(),
+            http: build_http_client(cookies, None)?,
+            proxy_http: HashMap::new(),
+            info: AccountInfo {
+                uid,
+                uname: String::new(),
+                status: AccountStatus::Unchecked,
+                last_check: 0,
+                rooms: vec![],
+            },
+        })
+    }
+
+    pub(crate) fn http(&mut self, proxy: Option<&Proxy>) -> Result<reqwest::Client> {
+        let Some(proxy) = proxy else {
+            return Ok(self.http.clone());
+        };
+        if let Some(http) = self.proxy_http.get(proxy) {
+            return Ok(http.clone());
+        }
+        let http = build_http_client(&self.cookies, Some(proxy))?;
+        self.proxy_http.insert(proxy.clone(), http.clone());
+        Ok(http)
+    }
+
+    // 风控冷却期内的检查结果不改变状态
+    pub(crate) fn apply_check(&mut self, result: CheckResult, now: i64) {
+        self.info.last_check = now;
+        if let AccountStatus::RiskControlled { since } = self.info.status {
+            if now - since < RISK_CONTROL_COOLDOWN {
+                return;
+            }
+        }
+        match result {
+            CheckResult::LoggedIn { uname } => {
+                self.info.uname = uname;
+                self.info.status = AccountStatus::Healthy;
+            }
+            CheckResult::LoggedOut => self.info.status = AccountStatus::LoggedOut,
+            CheckResult::Failed(reason) => {
+                // 冷却结束但 nav 请求失败, 保持风控状态等待下次检查
+                if !matches!(self.info.status, AccountStatus::RiskControlled { .. }) {
+                    self.info.status = AccountStatus::CheckFailed { reason };
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckResult {
+    LoggedIn { uname: String },
+    LoggedOut,
+    Failed(String),
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct NavData {
+    #[serde(rename = "isLogin")]
+    is_login: bool,
+    uname: String,
+}
+
+// 检查各账号是否仍然登录, 可在其他任务中运行, 结果交给 ClientPool::apply_checks
+#[derive(Clone)]
+pub struct AccountChecker {
+    pub(crate) endpoints: Endpoints,
+    pub(crate) accounts: Vec<(u64, reqwest::Client)>,
+}
+
+impl AccountChecker {
+    pub async fn check(&self) -> Vec<(u64, CheckResult)> {
+        let mut results = Vec::with_capacity(self.accounts.len());
+        for (uid, http) in &self.accounts {
+            results.push((*uid, check_nav(http, &self.endpoints).await));
+        }
+        results
+    }
+}
+
+async fn check_nav(http: &reqwest::Client, endpoints: &Endpoints) -> CheckResult {
+    let url = format!("{}/x/web-interface/nav", endpoints.main_api);
+    let resp = match http.get(url).send().await {
+        Ok(resp) => resp.json::<ApiResponse<NavData>>().await,
+        Err(e) => Err(e),
+    };
+    match resp {
+        Ok(resp) if resp.code == 0 && resp.data.is_login => CheckResult::LoggedIn {
+            uname: resp.data.uname,
+        },
+        Ok(resp) if resp.code == -101 || resp.code == 0 => CheckResult::LoggedOut,
+        Ok(resp) => CheckResult::Failed(format!("code: {}, message: {}", resp.code, resp.message)),
+        Err(e) => CheckResult::Failed(e.to_string()),
+    }
+}
+
+pub(crate) fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_bili::MockBili;
+
+    #[test]
+    fn test_apply_check() {
+        let mut account = Account::new("DedeUserID=10000; buvid3=test-buvid").unwrap();
+        assert_eq!(account.info.uid, 10000);
+        assert!(account.info.status.is_usable());
+
+        let logged_in = CheckResult::LoggedIn {
+            uname: "test".to_string(),
+        };
+        account.apply_check(logged_in.clone(), 100);
+        assert_eq!(account.info.status, AccountStatus::Healthy);
+        assert_eq!(account.info.uname, "test");
+
+        account.info.status = AccountStatus::RiskControlled { since: 100 };
+        account.apply_check(logged_in.clone(), 200);
+        assert!(!account.info.status.is_usable());
+        account.apply_check(
+            CheckResult::Failed("timeout".to_string()),
+            100 + RISK_CONTROL_COOLDOWN,
+        );
+        assert!(!account.info.status.is_usable());
+        account.apply_check(logged_in, 100 + RISK_CONTROL_COOLDOWN);
+        assert_eq!(account.info.status, AccountStatus::Healthy);
+
+        account.apply_check(CheckResult::LoggedOut, 300);
+        assert_eq!(account.info.status, AccountStatus::LoggedOut);
+        assert!(!account.info.status.is_usable());
+
+        assert!(Account::new("buvid3=test-buvid").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_nav() {
+        let mock = MockBili::start().await.unwrap();
+        let endpoints = Endpoints::with_base(&mock.api_base());
+        let http = build_http_client("DedeUserID=10000; buvid3=test-buvid", None).unwrap();
+        assert_eq!(
+            check_nav(&http, &endpoints).await,
+            CheckResult::LoggedIn {
+                uname: "mock-10000".to_string()
+            }
+        );
+        mock.set_logged_out(10000, true);
+        assert_eq!(check_nav(&http, &endpoints).await, CheckResult::LoggedOut);
+
+        let endpoints = Endpoints::with_base("http://127.0.0.1:1");
+        assert!(matches!(
+            check_nav(&http, &endpoints).await,
+            CheckResult::Failed(_)
+        ));
+    }
+}
@@ -1,35 +1,97 @@
+use crate::error::{ConnectError, ProtocolError};
+use crate::handle::{CloseReason, ListenHandle};
+use crate::pool::Stagger;
+use crate::proxy::Proxy;
+use crate::queue::{self, OverflowPolicy, QueueCounters, DEFAULT_CAPACITY};
 use anyhow::Result;
 use cookie::Cookie;
 use log::{debug, error, info};
-use parse::{parse_message, Message};
+use parse::parse_packet;
 use reqwest::header::HeaderMap;
 use serde::{Deserialize, Serialize};
-use std::str;
+use std::io::ErrorKind;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::Receiver;
 use tokio::time;
 
 // const HOST: &str = "broadcastlv.chat.bilibili.com";
 // const PORT: u16 = 2243;
+const DEFAULT_SEND_INTERVAL: Duration = Duration::from_secs(1);
+// 等待认证回复的时间, 服务器不回复时换下一次重连
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+// 认证回复只有 {"code":0} 之类的短 json
+const MAX_AUTH_REPLY_SIZE: usize = 64 * 1024;
+// 单个包的最大长度, 超出时认为数据流已错位, 断开后重连
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
 
-#[derive(Debug)]
+// 接口地址和弹幕服务器, 测试时指向本地 mock
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoints {
+    pub api: String,      // api.live.bilibili.com
+    pub main_api: String, // api.bilibili.com
+    pub passport: String, // passport.bilibili.com, 登录和刷新 cookie
+    pub www: String,      // www.bilibili.com
+    // 为 None 时使用 getDanmuInfo 返回的 host_list
+    pub hosts: Option<Vec<HostList>>,
+}
+
+impl Endpoints {
+    // 所有接口使用同一个地址, 用于测试
+    pub fn with_base(base: &str) -> Self {
+        Self {
+            api: base.to_string(),
+            main_api: base.to_string(),
+            passport: base.to_string(),
+            www: base.to_string(),
+            hosts: None,
+        }
+    }
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            api: "https://api.live.bilibili.com".to_string(),
+            main_api: "https://api.bilibili.com".to_string(),
+            passport: "https://passport.bilibili.com".to_string(),
+            www: "https://www.bilibili.com".to_string(),
+            hosts: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Client {
     pub room_id: u64,
     pub cookies: HeaderMap,
     pub uid: u64,
     pub buvid: String,
+    pub(crate) csrf: String,
+    pub(crate) http: reqwest::Client,
+    pub(crate) endpoints: Endpoints,
+    pub(crate) send_limiter: Stagger,
+    pub(crate) proxy: Option<Proxy>,
+    queue_capacity: usize,
+    overflow: OverflowPolicy,
+    counters: Arc<QueueCounters>,
 }
 
 impl Client {
     pub fn new(room_id: u64, cookies: &str) -> Result<Self> {
+        Self::with_http_client(room_id, cookies, build_http_client(cookies, None)?)
+    }
+
+    // 多个房间共用同一个 http client, 由 ClientPool 使用
+    pub fn with_http_client(room_id: u64, cookies: &str, http: reqwest::Client) -> Result<Self> {
         let mut headers = HeaderMap::new();
         headers.insert("Cookie", cookies.parse()?);
         let cookies = Cookie::split_parse(cookies.to_string());
         let mut uid = 0;
         let mut buvid = String::new();
+        let mut csrf = String::new();
         for cookie in cookies {
             let cookie = cookie?;
             match cookie.name() {
@@ -39,6 +101,10 @@ impl Client {
                 "buvid3" => {
                     buvid = cookie.value().to_string();
                 }
+                // 只在发送弹幕时需要
+                "bili_jct" => {
+                    csrf = cookie.value().to_string();
+                }
                 _ => {}
             }
         }
@@ -51,12 +117,61 @@ impl Client {
             cookies: headers,
             uid,
             buvid,
+            csrf,
+            http,
+            endpoints: Endpoints::default(),
+            send_limiter: Stagger::new(DEFAULT_SEND_INTERVAL),
+            proxy: None,
+            queue_capacity: DEFAULT_CAPACITY,
+            overflow: OverflowPolicy::default(),
+            counters: Arc::new(QueueCounters::default()),
         })
     }
 
-    pub async fn listen(&self) -> Result<Receiver<Message>> {
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
+    }
+
+    // getDanmuInfo 等接口请求和弹幕服务器连接都经过该代理
+    pub fn set_proxy(&mut self, proxy: Proxy) -> Result<()> {
+        let cookies = self.cookies.get("Cookie").map(|v| v.to_str()).transpose()?;
+        self.http = build_http_client(cookies.unwrap_or_default(), Some(&proxy))?;
+        self.proxy = Some(proxy);
+        Ok(())
+    }
+
+    // 两次发送弹幕的最小间隔, clone 出的 Client 共用
+    pub fn set_send_interval(&mut self, interval: Duration) {
+        self.send_limiter = Stagger::new(interval);
+    }
+
+    // 消费者处理不过来时, 读取队列满后的处理方式, 默认阻塞
+    pub fn set_overflow_policy(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.queue_capacity = capacity;
+        self.overflow = policy;
+    }
+
+    // clone 出的 Client 共用计数
+    pub fn counters(&self) -> Arc<QueueCounters> {
+        self.counters.clone()
+    }
+
+    // 重新创建 Client 时沿用之前的计数
+    pub(crate) fn set_counters(&mut self, counters: Arc<QueueCounters>) {
+        self.counters = counters;
+    }
+
+    pub async fn listen(&self) -> Result<ListenHandle, ConnectError> {
         let room_info = self.get_danmu_info().await?;
+        let (host, reader, writer) = self.connect(room_info).await?;
+        self.spawn(host, reader, writer)
+    }
 
+    // 依次尝试 host_list 中的服务器, 连接成功后发送认证包并校验认证回复
+    async fn connect(
+        &self,
+        room_info: GetKeyData,
+    ) -> Result<(String, OwnedReadHalf, OwnedWriteHalf), ConnectError> {
         let certificate = parse::Certificate {
             uid: self.uid,
             roomid: self.room_id,
@@ -64,114 +179,148 @@ impl Client {
             buvid: self.buvid.clone(),
             platform: "web".to_string(),
             r#type: 2,
-            key: room_info.data.token,
+            key: room_info.token,
         };
 
+        let host_list = match &self.endpoints.hosts {
+            Some(hosts) => hosts.clone(),
+            None => room_info.host_list,
+        };
         let mut stream = None;
-        for host in room_info.data.host_list {
-            if let Ok(conn) = TcpStream::connect((host.host, host.port)).await {
-                stream = Some(conn);
-                break;
+        for host in host_list {
+            let addr = format!("{}:{}", host.host, host.port);
+            let conn = match &self.proxy {
+                Some(proxy) => proxy.connect(&host.host, host.port).await,
+                None => TcpStream::connect((host.host.as_str(), host.port)).await,
+            };
+            match conn {
+                Ok(conn) => {
+                    stream = Some((addr, conn));
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to connect {}: {}", addr, e);
+                }
             }
         }
-        let (mut reader, mut writer) = stream
-            .ok_or(anyhow::anyhow!("Failed to connect to Danmu server"))?
-            .into_split();
+        let (host, stream) = stream.ok_or(ConnectError::NoHostReachable)?;
+        let (mut reader, mut writer) = stream.into_split();
 
         let auth_packet = parse::build_auth_packet(&certificate);
 
         writer.write_all(&auth_packet).await?;
         debug!("Auth packet sent");
-        let mut buffer = [0; 1024];
 
-        let n = reader.read(&mut buffer).await?; // read auth resp
-        if n == 0 {
-            error!("Failed to read auth resp: {:?}", buffer.to_vec());
-            return Err(anyhow::anyhow!("Failed to read auth resp"));
+        let reply = time::timeout(AUTH_TIMEOUT, read_auth_reply(&mut reader))
+            .await
+            .map_err(|_| ConnectError::AuthTimeout)??;
+        if reply.code != 0 {
+            return Err(ConnectError::AuthRejected(reply.code));
         }
-        info!("Auth resp: {:?}", str::from_utf8(&buffer[16..n])?);
+        info!("Auth success, room_id: {}, host: {}", self.room_id, host);
 
-        tokio::spawn(async move {
+        Ok((host, reader, writer))
+    }
+
+    fn spawn(
+        &self,
+        host: String,
+        mut reader: OwnedReadHalf,
+        mut writer: OwnedWriteHalf,
+    ) -> Result<ListenHandle, ConnectError> {
+        let heartbeat = tokio::spawn(async move {
             loop {
                 let heartbeat_packet = parse::build_hearbeat_packet();
                 if let Err(e) = writer.write_all(&heartbeat_packet).await {
                     error!("Failed to send heartbeat packet: {}", e);
-                } else {
-                    debug!("Heartbeat packet sent");
+                    return;
                 }
+                debug!("Heartbeat packet sent");
                 time::sleep(Duration::from_secs(30)).await;
             }
         });
+        let heartbeat_abort = heartbeat.abort_handle();
 
-        let (tx, rx) = mpsc::channel(1024);
+        let (tx, rx) = queue::channel(
+            self.room_id,
+            self.queue_capacity,
+            self.overflow.clone(),
+            self.counters.clone(),
+        )?;
 
-        tokio::spawn(async move {
-            loop {
-                let mut header_buffer = [0; 16];
-                match reader.read_exact(&mut header_buffer).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Failed to read header: {}", e);
-                        continue;
-                    }
-                };
-
-                let header = parse::parse_header(&header_buffer);
-
-                let mut buffer = vec![0; header.total_size as usize - 16];
-                match reader.read_exact(&mut buffer).await {
-                    Ok(_) => header.total_size as usize,
-                    Err(e) => {
-                        error!("Failed to read message: {}", e);
-                        continue;
-                    }
+        let counters = self.counters.clone();
+        let reader = tokio::spawn(async move {
+            let reason = loop {
+                let (header, packet) = match read_packet(&mut reader, MAX_PACKET_SIZE).await {
+                    Ok(packet) => packet,
+                    Err(ProtocolError::Io(e)) => break read_error_reason(e),
+                    Err(e) => break CloseReason::Io(e.to_string()),
                 };
-                let mut packet = Vec::new();
-                packet.extend_from_slice(&header_buffer);
-                packet.extend_from_slice(&buffer);
-                match parse_message(header, &packet) {
-                    Ok(messages) => {
+                match parse_packet(header, &packet) {
+                    Ok((messages, errors)) => {
+                        if errors > 0 {
+                            counters.add_parse_errors(errors as u64);
+                        }
                         for msg in messages {
-                            if let Err(e) = tx.send(msg).await {
-                                error!("Failed to send message: {}", e);
-                                break;
+                            if tx.send(msg).await.is_err() {
+                                // ListenHandle 已被 drop
+                                heartbeat_abort.abort();
+                                return CloseReason::Closed;
                             }
                         }
                     }
                     Err(e) => {
                         error!("Failed to parse message: {}", e);
+                        counters.add_parse_errors(1);
                         continue;
                     }
                 };
-            }
+            };
+            error!("Danmu connection closed: {}", reason);
+            heartbeat_abort.abort();
+            reason
         });
 
-        // tokio::try_join!(heart_handle, read_handle)?;
-
-        Ok(rx)
+        Ok(ListenHandle::new(host, rx, heartbeat, reader))
     }
 
-    async fn get_danmu_info(&self) -> Result<GetKeyResponse> {
-        let client = reqwest::Client::builder()
-            .cookie_store(true)
-            .default_headers(self.cookies.clone())
-            .build()?;
+    async fn get_danmu_info(&self) -> Result<GetKeyData, ConnectError> {
         let url = format!(
-            "https://api.live.bilibili.com/xlive/web-room/v1/index/getDanmuInfo?id={}&type=0",
-            self.room_id
+            "{}/xlive/web-room/v1/index/getDanmuInfo?id={}&type=0",
+            self.endpoints.api, self.room_id
         );
-        let resp = client
+        let resp = self
+            .http
             .get(&url)
             .send()
             .await?
             .json::<GetKeyResponse>()
             .await?;
-        Ok(resp)
+        resp.into_data()
+    }
+}
+
+pub fn build_http_client(cookies: &str, proxy: Option<&Proxy>) -> Result<reqwest::Client> {
+    let mut headers = HeaderMap::new();
+    headers.insert("Cookie", cookies.parse()?);
+    let mut builder = reqwest::Client::builder()
+        .cookie_store(true)
+        .default_headers(headers);
+    if let Some(proxy) = proxy {
+        builder = proxy.apply(builder)?;
+    }
+    Ok(builder.build()?)
+}
+
+fn read_error_reason(e: std::io::Error) -> CloseReason {
+    match e.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset => CloseReason::ServerClosed,
+        _ => CloseReason::Io(e.to_string()),
     }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct GetKeyResponse {
     pub code: i64,
     pub message: String,
@@ -179,8 +328,36 @@ pub struct GetKeyResponse {
     pub data: GetKeyData,
 }
 
+impl GetKeyResponse {
+    // -352 / -412 为 B 站风控
+    pub fn into_data(self) -> Result<GetKeyData, ConnectError> {
+        match self.code {
+            0 if !self.data.token.is_empty() => Ok(self.data),
+            0 => Err(ConnectError::TokenFetchFailed("empty token".to_string())),
+            -352 | -412 => Err(ConnectError::RiskControl {
+                code: self.code,
+                message: self.message,
+            }),
+            code => Err(ConnectError::TokenFetchFailed(format!(
+                "code: {}, message: {}",
+                code, self.message
+            ))),
+        }
+    }
+}
+
+// 主站和 passport 接口的通用返回格式
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct ApiResponse<T: Default> {
+    pub code: i64,
+    pub message: String,
+    pub data: T,
+}
+
+// 风控时 data 只有 v_voucher 字段, 缺失的字段使用默认值
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct GetKeyData {
     pub group: String,
     #[serde(rename = "business_id")]
@@ -206,3 +383,338 @@ pub struct HostList {
     #[serde(rename = "ws_port")]
     pub ws_port: i64,
 }
+
+// 读取认证回复, 包长度不合法或无法解析时返回 InvalidAuthReply
+async fn read_auth_reply(reader: &mut OwnedReadHalf) -> Result<parse::AuthReply, ConnectError> {
+    let (header, packet) = match read_packet(reader, MAX_AUTH_REPLY_SIZE).await {
+        Ok(packet) => packet,
+        Err(ProtocolError::Io(e)) => return Err(e.into()),
+        Err(e) => return Err(ConnectError::InvalidAuthReply(e.to_string())),
+    };
+    parse::parse_auth_reply(&header, &packet)
+        .map_err(|e| ConnectError::InvalidAuthReply(e.to_string()))
+}
+
+// 读取一个包含包头的完整包, 长度小于包头或超过 max_size 时不分配 body
+async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<(parse::Header, Vec<u8>), ProtocolError> {
+    let mut header_buffer = [0; 16];
+    reader.read_exact(&mut header_buffer).await?;
+    let header = parse::parse_header(&header_buffer);
+    let total_size = header.total_size as usize;
+    if !(header_buffer.len()..=max_size).contains(&total_size) {
+        return Err(ProtocolError::InvalidPacketSize {
+            size: header.total_size,
+            max: max_size,
+        });
+    }
+    let mut packet = vec![0; total_size];
+    packet[..16].copy_from_slice(&header_buffer);
+    reader.read_exact(&mut packet[16..]).await?;
+    Ok((header, packet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_bili::{message, MockBili, Session};
+    use parse::Message;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    const COOKIES: &str = "DedeUserID=10000; buvid3=test-buvid";
+    const DANMU: &str =
+        r#"{"cmd":"DANMU_MSG","info":[[0,1,25,16777215,1720068325513],"hello",[10000,"Alice"]]}"#;
+
+    // 本地假弹幕服务器: 校验认证包后回复 auth_reply, 认证成功则推送一条弹幕
+    // hold_open 为 true 时保持连接, 客户端断开后通过返回的 Receiver 通知
+    async fn fake_danmu_server(
+        auth_reply: &'static str,
+        hold_open: bool,
+    ) -> (HostList, oneshot::Receiver<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let (header, packet) = read_packet(&mut socket, MAX_PACKET_SIZE).await.unwrap();
+            assert_eq!(header.msg_type, 7);
+            let certificate: parse::Certificate = serde_json::from_slice(&packet[16..]).unwrap();
+            assert_eq!(certificate.uid, 10000);
+            assert_eq!(certificate.buvid, "test-buvid");
+            assert_eq!(certificate.key, "test-token");
+
+            socket
+                .write_all(&parse::build_packet(1, 8, auth_reply.as_bytes()))
+                .await
+                .unwrap();
+            socket
+                .write_all(&parse::build_packet(0, 5, DANMU.as_bytes()))
+                .await
+                .unwrap();
+            if !hold_open {
+                return;
+            }
+            let mut buffer = [0; 1024];
+            while let Ok(n) = socket.read(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+            }
+            let _ = closed_tx.send(());
+        });
+        let host = HostList {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..Default::default()
+        };
+        (host, closed_rx)
+    }
+
+    fn room_info(host_list: Vec<HostList>) -> GetKeyData {
+        GetKeyData {
+            token: "test-token".to_string(),
+            host_list,
+            ..Default::default()
+        }
+    }
+
+    fn assert_danmu(message: Option<Message>) {
+        match message {
+            Some(Message::Danmu(danmu)) => {
+                assert_eq!(danmu.uid, 10000);
+                assert_eq!(danmu.username, "Alice");
+                assert_eq!(danmu.msg, "hello");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_success() {
+        let (host, _) = fake_danmu_server(r#"{"code":0}"#, true).await;
+        let addr = format!("127.0.0.1:{}", host.port);
+        let client = Client::new(22747736, COOKIES).unwrap();
+        let (host, reader, writer) = client.connect(room_info(vec![host])).await.unwrap();
+        let mut handle = client.spawn(host, reader, writer).unwrap();
+        assert_eq!(handle.host(), addr);
+        assert_danmu(handle.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_auth_rejected() {
+        let (host, _) = fake_danmu_server(r#"{"code":-101}"#, true).await;
+        let client = Client::new(22747736, COOKIES).unwrap();
+        match client.connect(room_info(vec![host])).await {
+            Err(ConnectError::AuthRejected(code)) => assert_eq!(code, -101),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    // 读取认证包后回复 reply 原始字节并保持连接
+    async fn raw_reply_server(reply: Vec<u8>) -> HostList {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            read_packet(&mut socket, MAX_PACKET_SIZE).await.unwrap();
+            socket.write_all(&reply).await.unwrap();
+            let mut buffer = [0; 1024];
+            while let Ok(n) = socket.read(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+        HostList {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_auth_reply() {
+        let client = Client::new(22747736, COOKIES).unwrap();
+        // total_size 小于包头长度
+        let mut reply = parse::build_packet(1, 8, b"");
+        reply[..4].copy_from_slice(&8u32.to_be_bytes());
+        let replies = [
+            reply,
+            parse::build_packet(1, 5, br#"{"code":0}"#),
+            parse::build_packet(1, 8, b"not json"),
+        ];
+        for reply in replies {
+            let host = raw_reply_server(reply).await;
+            match client.connect(room_info(vec![host])).await {
+                Err(ConnectError::InvalidAuthReply(_)) => {}
+                other => panic!("unexpected result: {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_auth_timeout() {
+        let host = raw_reply_server(vec![]).await;
+        let client = Client::new(22747736, COOKIES).unwrap();
+        match client.connect(room_info(vec![host])).await {
+            Err(ConnectError::AuthTimeout) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_handle() {
+        let (host, closed_rx) = fake_danmu_server(r#"{"code":0}"#, true).await;
+        let client = Client::new(22747736, COOKIES).unwrap();
+        let (host, reader, writer) = client.connect(room_info(vec![host])).await.unwrap();
+        let mut handle = client.spawn(host, reader, writer).unwrap();
+        assert_danmu(handle.recv().await);
+        assert!(!handle.is_finished());
+        assert_eq!(handle.close().await, CloseReason::Closed);
+        // 两个任务结束后连接被关闭
+        time::timeout(Duration::from_secs(5), closed_rx)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drop_handle() {
+        let (host, closed_rx) = fake_danmu_server(r#"{"code":0}"#, true).await;
+        let client = Client::new(22747736, COOKIES).unwrap();
+        let (host, reader, writer) = client.connect(room_info(vec![host])).await.unwrap();
+        drop(client.spawn(host, reader, writer).unwrap());
+        time::timeout(Duration::from_secs(5), closed_rx)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_closed() {
+        let (host, _) = fake_danmu_server(r#"{"code":0}"#, false).await;
+        let client = Client::new(22747736, COOKIES).unwrap();
+        let (host, reader, writer) = client.connect(room_info(vec![host])).await.unwrap();
+        let mut handle = client.spawn(host, reader, writer).unwrap();
+        assert_danmu(handle.recv().await);
+        assert!(handle.recv().await.is_none());
+        assert_eq!(handle.close().await, CloseReason::ServerClosed);
+    }
+
+    #[tokio::test]
+    async fn test_packet_too_large() {
+        // 认证成功后收到长度超过 MAX_PACKET_SIZE 的包头, 不分配 body 直接断开
+        let mut reply = parse::build_packet(1, 8, br#"{"code":0}"#);
+        let mut header = parse::build_packet(0, 5, b"");
+        header[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        reply.extend_from_slice(&header);
+        let host = raw_reply_server(reply).await;
+        let client = Client::new(22747736, COOKIES).unwrap();
+        let (host, reader, writer) = client.connect(room_info(vec![host])).await.unwrap();
+        let mut handle = client.spawn(host, reader, writer).unwrap();
+        assert!(handle.recv().await.is_none());
+        assert_eq!(
+            handle.close().await,
+            CloseReason::Io(format!(
+                "invalid packet size: {}, max {}",
+                u32::MAX,
+                MAX_PACKET_SIZE
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_host_reachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let client = Client::new(22747736, COOKIES).unwrap();
+        let host = HostList {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..Default::default()
+        };
+        assert!(matches!(
+            client.connect(room_info(vec![host])).await,
+            Err(ConnectError::NoHostReachable)
+        ));
+        assert!(matches!(
+            client.connect(room_info(vec![])).await,
+            Err(ConnectError::NoHostReachable)
+        ));
+    }
+
+    fn mock_client(mock: &MockBili, room_id: u64) -> Client {
+        let mut client = Client::new(room_id, COOKIES).unwrap();
+        client.set_endpoints(Endpoints::with_base(&mock.api_base()));
+        client
+    }
+
+    #[tokio::test]
+    async fn test_listen_mock() {
+        let mock = MockBili::start().await.unwrap();
+        mock.push_session(
+            1,
+            Session::new()
+                .messages(&[
+                    message::danmu(10000, "Alice", "hello", 1720068325513),
+                    message::online_count(42),
+                ])
+                .close(),
+        );
+        let client = mock_client(&mock, 1);
+        let mut handle = client.listen().await.unwrap();
+        assert_eq!(handle.host(), mock.danmu_addr().to_string());
+        assert_danmu(handle.recv().await);
+        assert!(matches!(handle.recv().await, Some(Message::OnlineCount(m)) if m.count == 42));
+        assert!(handle.recv().await.is_none());
+        assert_eq!(handle.close().await, CloseReason::ServerClosed);
+
+        let certificates = mock.certificates();
+        assert_eq!(certificates.len(), 1);
+        assert_eq!(certificates[0].uid, 10000);
+        assert_eq!(certificates[0].key, MockBili::token(1));
+    }
+
+    #[tokio::test]
+    async fn test_listen_risk_control() {
+        let mock = MockBili::start().await.unwrap();
+        mock.set_danmu_info_code(1, -352);
+        let client = mock_client(&mock, 1);
+        assert!(matches!(
+            client.listen().await,
+            Err(ConnectError::RiskControl { code: -352, .. })
+        ));
+        assert_eq!(mock.connections(1), 0);
+    }
+
+    #[test]
+    fn test_get_key_response_into_data() {
+        let resp: GetKeyResponse = serde_json::from_str(
+            r#"{"code":-352,"message":"-352","ttl":1,"data":{"v_voucher":"voucher_test"}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            resp.into_data(),
+            Err(ConnectError::RiskControl { code: -352, .. })
+        ));
+
+        let resp: GetKeyResponse =
+            serde_json::from_str(r#"{"code":-400,"message":"invalid","ttl":1}"#).unwrap();
+        assert!(matches!(
+            resp.into_data(),
+            Err(ConnectError::TokenFetchFailed(_))
+        ));
+
+        let resp: GetKeyResponse = serde_json::from_str(
+            r#"{"code":0,"message":"0","ttl":1,"data":{"token":"test-token","host_list":[{"host":"127.0.0.1","port":2243,"wss_port":443,"ws_port":2244}]}}"#,
+        )
+        .unwrap();
+        let data = resp.into_data().unwrap();
+        assert_eq!(data.token, "test-token");
+        assert_eq!(data.host_list[0].port, 2243);
+    }
+}
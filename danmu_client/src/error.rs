@@ -0,0 +1,99 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    #[error("auth rejected by danmu server, code: {0}")]
+    AuthRejected(i64),
+    #[error("invalid auth reply: {0}")]
+    InvalidAuthReply(String),
+    #[error("auth reply timed out")]
+    AuthTimeout,
+    #[error("no danmu host reachable")]
+    NoHostReachable,
+    #[error("failed to fetch danmu token: {0}")]
+    TokenFetchFailed(String),
+    #[error("risk control triggered, code: {code}, message: {message}")]
+    RiskControl { code: i64, message: String },
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<reqwest::Error> for ConnectError {
+    fn from(e: reqwest::Error) -> Self {
+        ConnectError::TokenFetchFailed(e.to_string())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("invalid packet size: {size}, max {max}")]
+    InvalidPacketSize { size: u32, max: usize },
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum SendError {
+    #[error("empty danmu")]
+    Empty,
+    #[error("danmu too long: {len} chars, max {max}")]
+    TooLong { len: usize, max: usize },
+    #[error("bili_jct not found in cookies")]
+    MissingCsrf,
+    #[error("not logged in")]
+    NotLoggedIn,
+    #[error("csrf check failed")]
+    CsrfFailed,
+    #[error("sending too fast, code: {0}")]
+    RateLimited(i64),
+    #[error("danmu filtered: {0}")]
+    Filtered(String),
+    #[error("send failed, code: {code}, message: {message}")]
+    Api { code: i64, message: String },
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ModerationError {
+    #[error("bili_jct not found in cookies")]
+    MissingCsrf,
+    #[error("not logged in")]
+    NotLoggedIn,
+    #[error("csrf check failed")]
+    CsrfFailed,
+    #[error("user {0} is not silenced")]
+    NotSilenced(u64),
+    #[error("moderation failed, code: {code}, message: {message}")]
+    Api { code: i64, message: String },
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum LoginError {
+    #[error("qrcode expired")]
+    QrCodeExpired,
+    #[error("not logged in")]
+    NotLoggedIn,
+    #[error("cookie {0} not found")]
+    MissingCookie(&'static str),
+    #[error("refresh_csrf not found in correspond page")]
+    RefreshCsrfNotFound,
+    #[error("passport error, code: {code}, message: {message}")]
+    Api { code: i64, message: String },
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ProxyError {
+    #[error("invalid proxy url: {0}")]
+    InvalidUrl(String),
+    #[error("unsupported proxy scheme: {0}")]
+    UnsupportedScheme(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+}
@@ -0,0 +1,229 @@
+use crate::danmu::Client;
+use crate::error::ConnectError;
+use crate::pool::Stagger;
+use futures_util::Stream;
+use log::{error, info};
+use parse::Message;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    Connecting,                          // 开始获取 token 并连接
+    Authenticated { host: String },      // 认证成功, host 为 host:port
+    Message(Message),                    // 弹幕消息
+    Disconnected { reason: String },     // 连接断开或连接失败
+    RiskControl { uid: u64, code: i64 }, // 账号请求 getDanmuInfo 触发风控, 随后发出 Disconnected
+    Reconnecting { attempt: u32 },       // 等待退避后第 attempt 次重连
+}
+
+// 持有重连任务, drop 时取消任务并关闭连接
+pub struct EventHandle {
+    rx: Receiver<ClientEvent>,
+    task: JoinHandle<()>,
+    stop: watch::Sender<bool>,
+}
+
+impl EventHandle {
+    pub async fn recv(&mut self) -> Option<ClientEvent> {
+        self.rx.recv().await
+    }
+
+    // 停止读取和重连, 已读取的消息仍会发出, 之后 recv 返回 None
+    pub fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
+
+    pub async fn close(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Stream for EventHandle {
+    type Item = ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ClientEvent>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for EventHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Client {
+    // 断线后按指数退避自动重连, 连接状态变化和消息都以 ClientEvent 发出
+    pub fn listen_events(&self) -> EventHandle {
+        spawn_events(self.clone(), None)
+    }
+}
+
+pub(crate) fn spawn_events(client: Client, stagger: Option<Stagger>) -> EventHandle {
+    let (tx, rx) = mpsc::channel(1024);
+    let (stop, stop_rx) = watch::channel(false);
+    let task = tokio::spawn(run_events(client, stagger, tx, stop_rx));
+    EventHandle { rx, task, stop }
+}
+
+async fn run_events(
+    client: Client,
+    stagger: Option<Stagger>,
+    tx: mpsc::Sender<ClientEvent>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let room_id = client.room_id;
+    let mut attempt = 0;
+    loop {
+        let listen = async {
+            if let Some(stagger) = &stagger {
+                stagger.wait().await;
+            }
+            if tx.send(ClientEvent::Connecting).await.is_err() {
+                return None;
+            }
+            Some(client.listen().await)
+        };
+        // 等待错峰和连接期间停止时直接退出
+        let result = tokio::select! {
+            result = listen => result,
+            _ = stopped(&mut stop) => return,
+        };
+        let Some(result) = result else {
+            return;
+        };
+        let reason = match result {
+            Ok(mut handle) => {
+                attempt = 0;
+                info!("[room: {}] authenticated, host: {}", room_id, handle.host());
+                let host = handle.host().to_string();
+                if tx.send(ClientEvent::Authenticated { host }).await.is_err() {
+                    return;
+                }
+                let mut stopping = false;
+                loop {
+                    let message = tokio::select! {
+                        message = handle.recv() => message,
+                        _ = stopped(&mut stop), if !stopping => {
+                            // 不再读取 socket, 继续发出队列中已缓冲的消息
+                            handle.stop_reading();
+                            stopping = true;
+                            continue;
+                        }
+                    };
+                    let Some(message) = message else {
+                        break;
+                    };
+                    if tx.send(ClientEvent::Message(message)).await.is_err() {
+                        // EventHandle 已被 drop
+                        return;
+                    }
+                }
+                if stopping {
+                    return;
+                }
+                handle.close().await.to_string()
+            }
+            Err(e) => {
+                if let ConnectError::RiskControl { code, .. } = e {
+                    let uid = client.uid;
+                    if tx
+                        .send(ClientEvent::RiskControl { uid, code })
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                e.to_string()
+            }
+        };
+        error!("[room: {}] disconnected: {}", room_id, reason);
+        if tx.send(ClientEvent::Disconnected { reason }).await.is_err() {
+            return;
+        }
+
+        attempt += 1;
+        if tx
+            .send(ClientEvent::Reconnecting { attempt })
+            .await
+            .is_err()
+        {
+            return;
+        }
+        tokio::select! {
+            _ = time::sleep(backoff(RECONNECT_BACKOFF, MAX_RECONNECT_BACKOFF, attempt)) => {},
+            _ = stopped(&mut stop) => return,
+        }
+    }
+}
+
+// 调用 EventHandle::stop 或 EventHandle 被 drop 后返回
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+// 第 attempt 次重试前的等待时间, 从 initial 开始每次翻倍, 不超过 max
+pub fn backoff(initial: Duration, max: Duration, attempt: u32) -> Duration {
+    initial
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::danmu::Endpoints;
+    use mock_bili::{message, MockBili, Session};
+
+    #[test]
+    fn test_backoff() {
+        let backoff = |attempt| backoff(RECONNECT_BACKOFF, MAX_RECONNECT_BACKOFF, attempt);
+        assert_eq!(backoff(1), Duration::from_secs(5));
+        assert_eq!(backoff(2), Duration::from_secs(10));
+        assert_eq!(backoff(4), Duration::from_secs(40));
+        assert_eq!(backoff(100), MAX_RECONNECT_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_events_mock() {
+        let mock = MockBili::start().await.unwrap();
+        mock.push_session(
+            1,
+            Session::new()
+                .messages(&[message::online_count(42)])
+                .close(),
+        );
+        let mut client = Client::new(1, "DedeUserID=10000; buvid3=test-buvid").unwrap();
+        client.set_endpoints(Endpoints::with_base(&mock.api_base()));
+        let mut events = client.listen_events();
+        assert!(matches!(events.recv().await, Some(ClientEvent::Connecting)));
+        assert!(matches!(
+            events.recv().await,
+            Some(ClientEvent::Authenticated { host }) if host == mock.danmu_addr().to_string()
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Some(ClientEvent::Message(Message::OnlineCount(m))) if m.count == 42
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Some(ClientEvent::Disconnected { .. })
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Some(ClientEvent::Reconnecting { attempt: 1 })
+        ));
+        events.close().await;
+    }
+}
@@ -0,0 +1,93 @@
+use crate::queue::QueueReceiver;
+use futures_util::{Stream, StreamExt};
+use parse::Message;
+use std::fmt::{Display, Formatter};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CloseReason {
+    Closed,       // 调用 close 主动关闭
+    ServerClosed, // 服务器断开连接
+    Io(String),   // 读写失败
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::Closed => write!(f, "closed by client"),
+            CloseReason::ServerClosed => write!(f, "closed by server"),
+            CloseReason::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+// 持有心跳和读取两个任务, drop 时一并取消
+pub struct ListenHandle {
+    host: String,
+    rx: QueueReceiver,
+    heartbeat: JoinHandle<()>,
+    reader: JoinHandle<CloseReason>,
+}
+
+impl ListenHandle {
+    pub(crate) fn new(
+        host: String,
+        rx: QueueReceiver,
+        heartbeat: JoinHandle<()>,
+        reader: JoinHandle<CloseReason>,
+    ) -> Self {
+        Self {
+            host,
+            rx,
+            heartbeat,
+            reader,
+        }
+    }
+
+    // 已认证的弹幕服务器地址, host:port
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    // 连接断开且缓冲的消息读完后返回 None, 断开原因通过 close 获取
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.rx.next().await
+    }
+
+    // 停止读取 socket, 已缓冲的消息仍可通过 recv 读取, 读完后返回 None
+    pub fn stop_reading(&self) {
+        self.heartbeat.abort();
+        self.reader.abort();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.reader.is_finished()
+    }
+
+    pub async fn close(mut self) -> CloseReason {
+        self.heartbeat.abort();
+        self.reader.abort();
+        match (&mut self.reader).await {
+            Ok(reason) => reason,
+            Err(e) if e.is_panic() => CloseReason::Io(format!("reader panicked: {}", e)),
+            Err(_) => CloseReason::Closed,
+        }
+    }
+}
+
+impl Stream for ListenHandle {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+impl Drop for ListenHandle {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+        self.reader.abort();
+    }
+}
@@ -1 +1,21 @@
+pub mod account;
 pub mod danmu;
+pub mod error;
+pub mod event;
+pub mod handle;
+pub mod login;
+pub mod moderation;
+pub mod pool;
+pub mod proxy;
+pub mod queue;
+pub mod send;
+
+pub use account::{AccountChecker, AccountInfo, AccountStatus, CheckResult};
+pub use danmu::Endpoints;
+pub use event::ClientEvent;
+pub use login::{Credential, Passport, QrStatus};
+pub use moderation::SilentUser;
+pub use pool::{ClientPool, ConnectionState};
+pub use proxy::Proxy;
+pub use queue::{OverflowPolicy, QueueCounters};
+pub use send::SendOptions;
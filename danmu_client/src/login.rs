@@ -0,0 +1,552 @@
+use crate::danmu::{ApiResponse, Endpoints};
+use crate::error::LoginError;
+use cookie::Cookie;
+use log::{debug, info};
+use num_bigint::BigUint;
+use reqwest::header::{HeaderMap, COOKIE, SET_COOKIE};
+use reqwest::RequestBuilder;
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time;
+
+// 生成 correspondPath 使用的 B 站 RSA 公钥 (1024 bit, e = 65537)
+const CORRESPOND_KEY_N: &str = "cb81dd8e02470656da04dd38544446e2a3412051cfe9adc6a330a5ef90228509684960970b91c3360ca29c49e1690ff8fa068cb9dfc6179d1e9585cb9424e847db1ef59f33e37dd4dca8ccfb7631ee9b4a92640d00c8204300152a0ab7cd802889d3445aec69918fe6022b534912e7b095be3424dad1ba81145e969b533181f1";
+const CORRESPOND_KEY_E: u32 = 65537;
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// 登录凭据, 保存在本地文件中, 刷新后覆盖
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Credential {
+    pub cookies: String,       // Cookie 请求头格式
+    pub refresh_token: String, // 登录时获得, 刷新 cookie 时使用
+}
+
+impl Credential {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // 先写临时文件再 rename, 避免 crawler 读到写了一半的文件
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // 凭据只允许当前用户读写
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        // 上次中断留下的临时文件保留了原来的权限
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        Cookie::split_parse(self.cookies.as_str())
+            .filter_map(|cookie| cookie.ok())
+            .find(|cookie| cookie.name() == name)
+            .map(|cookie| cookie.value().to_string())
+    }
+
+    // 用新的值覆盖同名 cookie, 保持原有顺序
+    fn merge_cookies(&mut self, cookies: &[(String, String)]) {
+        let mut merged: Vec<(String, String)> = Cookie::split_parse(self.cookies.as_str())
+            .filter_map(|cookie| cookie.ok())
+            .map(|cookie| (cookie.name().to_string(), cookie.value().to_string()))
+            .collect();
+        for (name, value) in cookies {
+            match merged.iter_mut().find(|(n, _)| n == name) {
+                Some(cookie) => cookie.1 = value.clone(),
+                None => merged.push((name.clone(), value.clone())),
+            }
+        }
+        self.cookies = merged
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct QrCode {
+    pub url: String, // 生成二维码的内容, 用 B 站 app 扫描
+    pub qrcode_key: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QrStatus {
+    Waiting,               // 未扫码
+    Scanned,               // 已扫码, 等待在手机上确认
+    Expired,               // 二维码已失效
+    Confirmed(Credential), // 登录成功
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct PollData {
+    code: i64,
+    message: String,
+    refresh_token: String,
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct SpiData {
+    b_3: String,
+    b_4: String,
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct CookieInfo {
+    refresh: bool,
+    timestamp: i64,
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct RefreshData {
+    refresh_token: String,
+}
+
+// web 端扫码登录和 cookie 刷新
+pub struct Passport {
+    http: reqwest::Client,
+    endpoints: Endpoints,
+    poll_interval: Duration,
+}
+
+impl Passport {
+    pub fn new(endpoints: Endpoints) -> Result<Self, LoginError> {
+        Ok(Self {
+            http: reqwest::Client::builder().build()?,
+            endpoints,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        })
+    }
+
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    pub async fn generate_qrcode(&self) -> Result<QrCode, LoginError> {
+        let url = format!(
+            "{}/x/passport-login/web/qrcode/generate",
+            self.endpoints.passport
+        );
+        let (_, qrcode) = send::<QrCode>(self.http.get(url)).await?;
+        Ok(qrcode)
+    }
+
+    pub async fn poll_qrcode(&self, qrcode_key: &str) -> Result<QrStatus, LoginError> {
+        let url = format!(
+            "{}/x/passport-login/web/qrcode/poll",
+            self.endpoints.passport
+        );
+        let (headers, data) =
+            send::<PollData>(self.http.get(url).query(&[("qrcode_key", qrcode_key)])).await?;
+        match data.code {
+            0 => {}
+            86101 => return Ok(QrStatus::Waiting),
+            86090 => return Ok(QrStatus::Scanned),
+            86038 => return Ok(QrStatus::Expired),
+            code => {
+                return Err(LoginError::Api {
+                    code,
+                    message: data.message,
+                })
+            }
+        }
+        let mut credential = Credential {
+            refresh_token: data.refresh_token,
+            ..Default::default()
+        };
+        credential.merge_cookies(&set_cookies(&headers));
+        // 登录接口不下发 buvid, 连接弹幕服务器时需要
+        let url = format!("{}/x/frontend/finger/spi", self.endpoints.main_api);
+        let (_, spi) = send::<SpiData>(self.http.get(url)).await?;
+        credential.merge_cookies(&[
+            ("buvid3".to_string(), spi.b_3),
+            ("buvid4".to_string(), spi.b_4),
+        ]);
+        Ok(QrStatus::Confirmed(credential))
+    }
+
+    // 生成二维码后调用 on_qrcode 展示, 轮询直到登录成功或二维码失效
+    pub async fn login(&self, on_qrcode: impl FnOnce(&QrCode)) -> Result<Credential, LoginError> {
+        let qrcode = self.generate_qrcode().await?;
+        on_qrcode(&qrcode);
+        loop {
+            match self.poll_qrcode(&qrcode.qrcode_key).await? {
+                QrStatus::Confirmed(credential) => {
+                    info!("扫码登录成功");
+                    return Ok(credential);
+                }
+                QrStatus::Expired => return Err(LoginError::QrCodeExpired),
+                QrStatus::Scanned => debug!("已扫码, 等待确认"),
+                QrStatus::Waiting => {}
+            }
+            time::sleep(self.poll_interval).await;
+        }
+    }
+
+    // 不需要刷新时返回 None
+    pub async fn refresh_if_needed(
+        &self,
+        credential: &Credential,
+    ) -> Result<Option<Credential>, LoginError> {
+        let csrf = credential
+            .cookie("bili_jct")
+            .ok_or(LoginError::MissingCookie("bili_jct"))?;
+        let url = format!(
+            "{}/x/passport-login/web/cookie/info",
+            self.endpoints.passport
+        );
+        let (_, info) = send::<CookieInfo>(
+            self.http
+                .get(url)
+                .query(&[("csrf", csrf)])
+                .header(COOKIE, &credential.cookies),
+        )
+        .await?;
+        if !info.refresh {
+            return Ok(None);
+        }
+        info!("cookie 需要刷新");
+        self.refresh(credential, info.timestamp).await.map(Some)
+    }
+
+    async fn refresh(
+        &self,
+        credential: &Credential,
+        timestamp: i64,
+    ) -> Result<Credential, LoginError> {
+        let csrf = credential
+            .cookie("bili_jct")
+            .ok_or(LoginError::MissingCookie("bili_jct"))?;
+        let url = format!(
+            "{}/correspond/1/{}",
+            self.endpoints.www,
+            correspond_path(timestamp)
+        );
+        let html = self
+            .http
+            .get(url)
+            .header(COOKIE, &credential.cookies)
+            .send()
+            .await?
+            .text()
+            .await?;
+        let refresh_csrf = extract_refresh_csrf(&html).ok_or(LoginError::RefreshCsrfNotFound)?;
+
+        let url = format!(
+            "{}/x/passport-login/web/cookie/refresh",
+            self.endpoints.passport
+        );
+        let form = [
+            ("csrf", csrf.as_str()),
+            ("refresh_csrf", refresh_csrf.as_str()),
+            ("source", "main_web"),
+            ("refresh_token", credential.refresh_token.as_str()),
+        ];
+        let (headers, data) = send::<RefreshData>(
+            self.http
+                .post(url)
+                .form(&form)
+                .header(COOKIE, &credential.cookies),
+        )
+        .await?;
+        let mut refreshed = Credential {
+            cookies: credential.cookies.clone(),
+            refresh_token: data.refresh_token,
+        };
+        refreshed.merge_cookies(&set_cookies(&headers));
+
+        // 用新 cookie 确认刷新, 使旧的 refresh_token 失效
+        let csrf = refreshed
+            .cookie("bili_jct")
+            .ok_or(LoginError::MissingCookie("bili_jct"))?;
+        let url = format!(
+            "{}/x/passport-login/web/confirm/refresh",
+            self.endpoints.passport
+        );
+        let form = [
+            ("csrf", csrf.as_str()),
+            ("refresh_token", credential.refresh_token.as_str()),
+        ];
+        send::<serde_json::Value>(
+            self.http
+                .post(url)
+                .form(&form)
+                .header(COOKIE, &refreshed.cookies),
+        )
+        .await?;
+        info!("cookie 刷新成功");
+        Ok(refreshed)
+    }
+}
+
+async fn send<T: DeserializeOwned + Default>(
+    req: RequestBuilder,
+) -> Result<(HeaderMap, T), LoginError> {
+    let resp = req.send().await?;
+    let headers = resp.headers().clone();
+    let resp = resp.json::<ApiResponse<T>>().await?;
+    match resp.code {
+        0 => Ok((headers, resp.data)),
+        -101 => Err(LoginError::NotLoggedIn),
+        code => Err(LoginError::Api {
+            code,
+            message: resp.message,
+        }),
+    }
+}
+
+fn set_cookies(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| Cookie::parse(value).ok())
+        .map(|cookie| (cookie.name().to_string(), cookie.value().to_string()))
+        .collect()
+}
+
+// refresh_csrf 在页面的 <div id="1-name"> 中
+fn extract_refresh_csrf(html: &str) -> Option<String> {
+    let start = html.find(r#"<div id="1-name">"#)? + r#"<div id="1-name">"#.len();
+    let end = html[start..].find("</div>")? + start;
+    let refresh_csrf = html[start..end].trim();
+    (!refresh_csrf.is_empty()).then(|| refresh_csrf.to_string())
+}
+
+// 用 RSA-OAEP (SHA-256) 加密 refresh_{timestamp}, 结果转为小写 hex
+fn correspond_path(timestamp: i64) -> String {
+    let n = BigUint::parse_bytes(CORRESPOND_KEY_N.as_bytes(), 16).expect("invalid correspond key");
+    let mut seed = [0; SHA256_OUTPUT_LEN];
+    SystemRandom::new()
+        .fill(&mut seed)
+        .expect("failed to generate random seed");
+    let ciphertext = oaep_encrypt(
+        &n,
+        &BigUint::from(CORRESPOND_KEY_E),
+        format!("refresh_{}", timestamp).as_bytes(),
+        &seed,
+    );
+    ciphertext.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// RFC 8017 RSAES-OAEP-ENCRYPT, label 为空, 消息长度不超过 k - 66
+fn oaep_encrypt(n: &BigUint, e: &BigUint, message: &[u8], seed: &[u8]) -> Vec<u8> {
+    let k = (n.bits() as usize).div_ceil(8);
+    let h_len = SHA256_OUTPUT_LEN;
+    assert!(message.len() + 2 * h_len + 2 <= k, "message too long");
+
+    let mut db = digest(&SHA256, b"").as_ref().to_vec();
+    db.resize(k - message.len() - h_len - 2, 0);
+    db.push(1);
+    db.extend_from_slice(message);
+    let db_mask = mgf1(seed, db.len());
+    let masked_db: Vec<u8> = db.iter().zip(db_mask).map(|(a, b)| a ^ b).collect();
+    let seed_mask = mgf1(&masked_db, h_len);
+    let masked_seed: Vec<u8> = seed.iter().zip(seed_mask).map(|(a, b)| a ^ b).collect();
+
+    let mut em = vec![0];
+    em.extend_from_slice(&masked_seed);
+    em.extend_from_slice(&masked_db);
+    let c = BigUint::from_bytes_be(&em).modpow(e, n).to_bytes_be();
+    let mut ciphertext = vec![0; k - c.len()];
+    ciphertext.extend_from_slice(&c);
+    ciphertext
+}
+
+fn mgf1(seed: &[u8], len: usize) -> Vec<u8> {
+    let mut mask = Vec::with_capacity(len + SHA256_OUTPUT_LEN);
+    let mut counter: u32 = 0;
+    while mask.len() < len {
+        let mut input = seed.to_vec();
+        input.extend_from_slice(&counter.to_be_bytes());
+        mask.extend_from_slice(digest(&SHA256, &input).as_ref());
+        counter += 1;
+    }
+    mask.truncate(len);
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_bili::MockBili;
+
+    // openssl genrsa 生成的 1024 bit 测试密钥
+    const TEST_N: &str = "abfc1f7f382861dfb72f8bc7c490d42de256c7b390bcffdc20eb35c63939a1c2f9d91e7dbf1c5c56df68bd772e5fbf55b2bdcfa8ccb30e13764053613d9b53e6a64f9c179c1c1f1885887aa1235869bf4e29b022f5e2dae92985e16626161444b6ab01303b249e8441a45947cc3c21fd523cce744a230d41e9240faa409173c3";
+    const TEST_D: &str = "5b60f147420a4abe77f15e3e7d154a773aac4b1d00493b54b574e3b47791447e790bc064a5972704cd5d3455cfa0f5e34dbe0648b4c26a3732fc4891010fb78a9530f90a1522f99d8f827d2f7a7da383bafb6a067b5fd245189716a4e4b063779d250b36e60c2110610809be5fcf1466ef20d607cd07e40fd7e651b4b9c8c081";
+
+    fn oaep_decrypt(n: &BigUint, d: &BigUint, ciphertext: &[u8]) -> Vec<u8> {
+        let k = ciphertext.len();
+        let m = BigUint::from_bytes_be(ciphertext)
+            .modpow(d, n)
+            .to_bytes_be();
+        let mut em = vec![0; k - m.len()];
+        em.extend_from_slice(&m);
+        assert_eq!(em[0], 0);
+        let (masked_seed, masked_db) = em[1..].split_at(SHA256_OUTPUT_LEN);
+        let seed_mask = mgf1(masked_db, SHA256_OUTPUT_LEN);
+        let seed: Vec<u8> = masked_seed
+            .iter()
+            .zip(seed_mask)
+            .map(|(a, b)| a ^ b)
+            .collect();
+        let db_mask = mgf1(&seed, masked_db.len());
+        let db: Vec<u8> = masked_db.iter().zip(db_mask).map(|(a, b)| a ^ b).collect();
+        assert_eq!(&db[..SHA256_OUTPUT_LEN], digest(&SHA256, b"").as_ref());
+        let start = db[SHA256_OUTPUT_LEN..]
+            .iter()
+            .position(|b| *b == 1)
+            .unwrap();
+        db[SHA256_OUTPUT_LEN + start + 1..].to_vec()
+    }
+
+    #[test]
+    fn test_oaep_encrypt() {
+        let n = BigUint::parse_bytes(TEST_N.as_bytes(), 16).unwrap();
+        let d = BigUint::parse_bytes(TEST_D.as_bytes(), 16).unwrap();
+        let e = BigUint::from(CORRESPOND_KEY_E);
+        let message = b"refresh_1720068325513";
+        let a = oaep_encrypt(&n, &e, message, &[1; SHA256_OUTPUT_LEN]);
+        let b = oaep_encrypt(&n, &e, message, &[2; SHA256_OUTPUT_LEN]);
+        assert_eq!(a.len(), 128);
+        assert_ne!(a, b);
+        assert_eq!(oaep_decrypt(&n, &d, &a), message);
+        assert_eq!(oaep_decrypt(&n, &d, &b), message);
+
+        let path = correspond_path(1720068325513);
+        assert_eq!(path.len(), 256);
+        assert!(path
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() && !c.is_uppercase()));
+    }
+
+    #[test]
+    fn test_credential() {
+        let mut credential = Credential {
+            cookies: "SESSDATA=a; bili_jct=b; buvid3=c".to_string(),
+            refresh_token: "r".to_string(),
+        };
+        credential.merge_cookies(&[
+            ("bili_jct".to_string(), "d".to_string()),
+            ("DedeUserID".to_string(), "1".to_string()),
+        ]);
+        assert_eq!(
+            credential.cookies,
+            "SESSDATA=a; bili_jct=d; buvid3=c; DedeUserID=1"
+        );
+        assert_eq!(credential.cookie("bili_jct").as_deref(), Some("d"));
+        assert_eq!(credential.cookie("missing"), None);
+
+        let path = std::env::temp_dir()
+            .join(format!("credential-{}", std::process::id()))
+            .join("credential.json");
+        credential.save(&path).unwrap();
+        assert_eq!(Credential::load(&path).unwrap(), credential);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_extract_refresh_csrf() {
+        let html = r#"<html><div id="1-name">b0cc8411ded2f9db2cff2edb3123acac</div></html>"#;
+        assert_eq!(
+            extract_refresh_csrf(html).as_deref(),
+            Some("b0cc8411ded2f9db2cff2edb3123acac")
+        );
+        assert_eq!(extract_refresh_csrf("<html></html>"), None);
+    }
+
+    #[tokio::test]
+    async fn test_qrcode_login() {
+        let mock = MockBili::start().await.unwrap();
+        let mut passport = Passport::new(Endpoints::with_base(&mock.api_base())).unwrap();
+        passport.set_poll_interval(Duration::from_millis(10));
+
+        let qrcode = passport.generate_qrcode().await.unwrap();
+        assert!(!qrcode.url.is_empty());
+        assert_eq!(
+            passport.poll_qrcode(&qrcode.qrcode_key).await.unwrap(),
+            QrStatus::Waiting
+        );
+        mock.set_qrcode_status(86090);
+        assert_eq!(
+            passport.poll_qrcode(&qrcode.qrcode_key).await.unwrap(),
+            QrStatus::Scanned
+        );
+
+        mock.set_qrcode_status(0);
+        let credential = passport.login(|_| {}).await.unwrap();
+        assert_eq!(credential.cookie("DedeUserID").as_deref(), Some("10000"));
+        assert_eq!(
+            credential.cookie("bili_jct").as_deref(),
+            Some("mock-csrf-1")
+        );
+        assert!(credential.cookie("buvid3").is_some());
+        assert_eq!(credential.refresh_token, "mock-refresh-1");
+        // 登录得到的 cookie 可以直接用于 Client
+        assert!(crate::danmu::Client::new(1, &credential.cookies).is_ok());
+
+        mock.set_qrcode_status(86038);
+        assert!(matches!(
+            passport.login(|_| {}).await,
+            Err(LoginError::QrCodeExpired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_cookie() {
+        let mock = MockBili::start().await.unwrap();
+        let passport = Passport::new(Endpoints::with_base(&mock.api_base())).unwrap();
+        mock.set_qrcode_status(0);
+        let credential = passport.login(|_| {}).await.unwrap();
+
+        assert_eq!(passport.refresh_if_needed(&credential).await.unwrap(), None);
+
+        mock.set_refresh_needed(true);
+        let refreshed = passport
+            .refresh_if_needed(&credential)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(refreshed.cookie("bili_jct").as_deref(), Some("mock-csrf-2"));
+        assert_eq!(
+            refreshed.cookie("SESSDATA").as_deref(),
+            Some("mock-sessdata-2")
+        );
+        assert_eq!(refreshed.cookie("buvid3"), credential.cookie("buvid3"));
+        assert_eq!(refreshed.refresh_token, "mock-refresh-2");
+        assert!(mock.refresh_confirmed());
+
+        // 旧凭据已失效
+        assert!(matches!(
+            passport.refresh_if_needed(&credential).await,
+            Err(LoginError::NotLoggedIn)
+        ));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::danmu::{ApiResponse, Client};
+use crate::error::ModerationError;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// 禁言列表中的一条记录, 需要账号为该房间主播或房管
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SilentUser {
+    pub id: u64, // 禁言记录 id, 解除禁言时使用
+    pub tuid: u64,
+    pub tname: String,
+    pub uid: u64, // 操作者
+    pub name: String,
+    pub ctime: String,
+    pub is_anchor: i64,
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct SilentUserList {
+    data: Vec<SilentUser>,
+    total: u64,
+    total_page: u64,
+}
+
+impl Client {
+    // hour: -1 永久, 0 本场直播, 其余为小时数
+    pub async fn add_silent_user(
+        &self,
+        room_id: u64,
+        uid: u64,
+        hour: i64,
+    ) -> Result<(), ModerationError> {
+        let form = vec![
+            ("room_id", room_id.to_string()),
+            ("tuid", uid.to_string()),
+            ("mobile_app", "web".to_string()),
+            ("type", "1".to_string()),
+            ("hour", hour.to_string()),
+        ];
+        self.post_form::<serde_json::Value>("/xlive/web-ucenter/v1/banned/AddSilentUser", form)
+            .await?;
+        info!(
+            "[room: {}] silenced user {} for {} hours",
+            room_id, uid, hour
+        );
+        Ok(())
+    }
+
+    // 按 uid 查找禁言记录后解除
+    pub async fn remove_silent_user(&self, room_id: u64, uid: u64) -> Result<(), ModerationError> {
+        let record = self
+            .list_silent_users(room_id)
+            .await?
+            .into_iter()
+            .find(|user| user.tuid == uid)
+            .ok_or(ModerationError::NotSilenced(uid))?;
+        let form = vec![
+            ("roomid", room_id.to_string()),
+            ("id", record.id.to_string()),
+        ];
+        self.post_form::<serde_json::Value>("/xlive/web-ucenter/v1/banned/DelSilentUser", form)
+            .await?;
+        info!("[room: {}] unsilenced user {}", room_id, uid);
+        Ok(())
+    }
+
+    // 逐页获取当前禁言列表
+    pub async fn list_silent_users(
+        &self,
+        room_id: u64,
+    ) -> Result<Vec<SilentUser>, ModerationError> {
+        let mut result = vec![];
+        let mut page = 1;
+        loop {
+            let form = vec![("room_id", room_id.to_string()), ("ps", page.to_string())];
+            let list = self
+                .post_form::<SilentUserList>("/xlive/web-ucenter/v1/banned/GetSilentUserList", form)
+                .await?;
+            result.extend(list.data);
+            if page >= list.total_page || result.len() as u64 >= list.total {
+                return Ok(result);
+            }
+            page += 1;
+        }
+    }
+
+    async fn post_form<T: DeserializeOwned + Default>(
+        &self,
+        path: &str,
+        mut form: Vec<(&str, String)>,
+    ) -> Result<T, ModerationError> {
+        if self.csrf.is_empty() {
+            return Err(ModerationError::MissingCsrf);
+        }
+        form.push(("csrf", self.csrf.clone()));
+        form.push(("csrf_token", self.csrf.clone()));
+        let resp = self
+            .http
+            .post(format!("{}{}", self.endpoints.api, path))
+            .form(&form)
+            .send()
+            .await?
+            .json::<ApiResponse<T>>()
+            .await?;
+        match resp.code {
+            0 => Ok(resp.data),
+            -101 => Err(ModerationError::NotLoggedIn),
+            -111 => Err(ModerationError::CsrfFailed),
+            code => Err(ModerationError::Api {
+                code,
+                message: resp.message,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::danmu::Endpoints;
+    use mock_bili::MockBili;
+
+    fn mock_client(mock: &MockBili, cookies: &str) -> Client {
+        let mut client = Client::new(1, cookies).unwrap();
+        client.set_endpoints(Endpoints::with_base(&mock.api_base()));
+        client
+    }
+
+    #[tokio::test]
+    async fn test_silent_users() {
+        let mock = MockBili::start().await.unwrap();
+        let client = mock_client(
+            &mock,
+            "DedeUserID=10000; buvid3=test-buvid; bili_jct=test-csrf",
+        );
+        client.add_silent_user(1, 20000, 24).await.unwrap();
+        client.add_silent_user(1, 20001, -1).await.unwrap();
+        client.add_silent_user(2, 20000, 0).await.unwrap();
+        assert_eq!(mock.silent_users(1), vec![20000, 20001]);
+
+        let users = client.list_silent_users(1).await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].tuid, 20000);
+        assert_eq!(users[0].uid, 10000);
+
+        client.remove_silent_user(1, 20000).await.unwrap();
+        assert_eq!(mock.silent_users(1), vec![20001]);
+        assert_eq!(mock.silent_users(2), vec![20000]);
+        assert!(matches!(
+            client.remove_silent_user(1, 20000).await,
+            Err(ModerationError::NotSilenced(20000))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_silent_users_errors() {
+        let mock = MockBili::start().await.unwrap();
+        let client = mock_client(&mock, "DedeUserID=10000; buvid3=test-buvid");
+        assert!(matches!(
+            client.add_silent_user(1, 20000, 24).await,
+            Err(ModerationError::MissingCsrf)
+        ));
+
+        let client = mock_client(
+            &mock,
+            "DedeUserID=10000; buvid3=test-buvid; bili_jct=test-csrf",
+        );
+        mock.set_manager(1, false);
+        assert!(matches!(
+            client.add_silent_user(1, 20000, 24).await,
+            Err(ModerationError::Api { .. })
+        ));
+        assert!(mock.silent_users(1).is_empty());
+    }
+}
@@ -0,0 +1,624 @@
+use crate::account::{self, Account, AccountChecker, AccountInfo, AccountStatus, CheckResult};
+use crate::danmu::{Client, Endpoints};
+use crate::event::{spawn_events, ClientEvent};
+use crate::proxy::Proxy;
+use crate::queue::{OverflowPolicy, QueueCounters, DEFAULT_CAPACITY};
+use anyhow::{anyhow, Result};
+use futures_util::Stream;
+use log::{error, info, warn};
+use parse::Message;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+const DEFAULT_STAGGER: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    Waiting,              // 排队等待错峰连接
+    Connecting,           // 获取 token 并认证中
+    Connected,            // 已认证, 正在接收消息
+    Disconnected(String), // 断开或连接失败的原因, 等待重连
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Waiting => write!(f, "waiting"),
+            ConnectionState::Connecting => write!(f, "connecting"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Disconnected(reason) => write!(f, "disconnected: {}", reason),
+        }
+    }
+}
+
+type States = Arc<RwLock<HashMap<u64, ConnectionState>>>;
+
+// 管理多个房间的连接, 房间分配到健康的账号上, 账号触发风控后房间切换到其他账号,
+// 所有房间的消息合并到同一个 channel
+pub struct ClientPool {
+    accounts: Vec<Account>,
+    room_accounts: HashMap<u64, usize>,
+    proxies: HashMap<u64, Proxy>,
+    endpoints: Endpoints,
+    stagger: Stagger,
+    queue_capacity: usize,
+    overflow: OverflowPolicy,
+    counters: HashMap<u64, Arc<QueueCounters>>,
+    rooms: HashMap<u64, RoomTask>,
+    states: States,
+    tx: mpsc::Sender<(u64, ClientEvent)>,
+    rx: mpsc::Receiver<(u64, ClientEvent)>,
+}
+
+impl ClientPool {
+    pub fn new(cookies: &str) -> Result<Self> {
+        Self::with_accounts(&[cookies])
+    }
+
+    // 每个账号一个 cookie 字符串, 通过 DedeUserID 区分
+    pub fn with_accounts<S: AsRef<str>>(cookies: &[S]) -> Result<Self> {
+        let mut accounts: Vec<Account> = Vec::new();
+        for cookies in cookies {
+            let account = Account::new(cookies.as_ref())?;
+            if accounts.iter().any(|a| a.info.uid == account.info.uid) {
+                return Err(anyhow!("duplicate account: {}", account.info.uid));
+            }
+            accounts.push(account);
+        }
+        if accounts.is_empty() {
+            return Err(anyhow!("no account configured"));
+        }
+        let (tx, rx) = mpsc::channel(1024);
+        Ok(Self {
+            accounts,
+            room_accounts: HashMap::new(),
+            proxies: HashMap::new(),
+            endpoints: Endpoints::default(),
+            stagger: Stagger::new(DEFAULT_STAGGER),
+            queue_capacity: DEFAULT_CAPACITY,
+            overflow: OverflowPolicy::default(),
+            counters: HashMap::new(),
+            rooms: HashMap::new(),
+            states: Arc::new(RwLock::new(HashMap::new())),
+            tx,
+            rx,
+        })
+    }
+
+    // 两次连接之间的最小间隔, 避免 getDanmuInfo 被限流, 需在 add_room 之前设置
+    pub fn set_stagger(&mut self, interval: Duration) {
+        self.stagger = Stagger::new(interval);
+    }
+
+    // 对之后 add_room 的房间生效
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
+    }
+
+    // 每个房间读取队列的容量和溢出策略, 对之后 add_room 的房间生效
+    pub fn set_overflow_policy(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.queue_capacity = capacity;
+        self.overflow = policy;
+    }
+
+    pub fn add_room(&mut self, room_id: u64) -> Result<()> {
+        self.add_room_with_proxy(room_id, None)
+    }
+
+    // 房间的接口请求和弹幕连接都经过 proxy, 用于分散 getDanmuInfo 的限流
+    pub fn add_room_with_proxy(&mut self, room_id: u64, proxy: Option<Proxy>) -> Result<()> {
+        if self.rooms.contains_key(&room_id) {
+            return Ok(());
+        }
+        match proxy {
+            Some(proxy) => self.proxies.insert(room_id, proxy),
+            None => self.proxies.remove(&room_id),
+        };
+        self.spawn_room(room_id)
+    }
+
+    // 当前账号仍可用时保留, 否则换到房间最少的可用账号, 没有可用账号时不变
+    fn assign_account(&mut self, room_id: u64) -> usize {
+        let current = self.room_accounts.get(&room_id).copied();
+        if let Some(index) = current {
+            if self.accounts[index].info.status.is_usable() {
+                return index;
+            }
+        }
+        let mut loads = vec![0; self.accounts.len()];
+        for (other, index) in &self.room_accounts {
+            if *other != room_id {
+                loads[*index] += 1;
+            }
+        }
+        let index = (0..self.accounts.len())
+            .filter(|index| self.accounts[*index].info.status.is_usable())
+            .min_by_key(|index| loads[*index])
+            .or(current)
+            .unwrap_or(0);
+        if !self.accounts[index].info.status.is_usable() {
+            warn!(
+                "[room: {}] no usable account, keep using {}",
+                room_id, self.accounts[index].info.uid
+            );
+        }
+        self.room_accounts.insert(room_id, index);
+        index
+    }
+
+    // 房间已在运行时先停止旧任务, 旧连接已读取的消息发出后新任务再连接
+    fn spawn_room(&mut self, room_id: u64) -> Result<()> {
+        let previous = self.rooms.remove(&room_id).map(|room| {
+            let _ = room.stop.send(());
+            room.task
+        });
+        let proxy = self.proxies.get(&room_id).cloned();
+        let index = self.assign_account(room_id);
+        let account = &mut self.accounts[index];
+        let http = account.http(proxy.as_ref())?;
+        let mut client = Client::with_http_client(room_id, &account.cookies, http)?;
+        if let Some(proxy) = &proxy {
+            info!("[room: {}] using proxy {}", room_id, proxy);
+        }
+        client.proxy = proxy;
+        client.set_endpoints(self.endpoints.clone());
+        client.set_overflow_policy(self.queue_capacity, self.overflow.clone());
+        // 切换账号和重连后计数继续累计
+        match self.counters.get(&room_id) {
+            Some(counters) => client.set_counters(counters.clone()),
+            None => {
+                self.counters.insert(room_id, client.counters());
+            }
+        }
+        set_state(&self.states, room_id, ConnectionState::Waiting);
+        let (stop, stop_rx) = oneshot::channel();
+        let task = tokio::spawn(run_room(
+            client,
+            self.tx.clone(),
+            self.states.clone(),
+            self.stagger.clone(),
+            stop_rx,
+            previous,
+        ));
+        self.rooms.insert(room_id, RoomTask { task, stop });
+        info!(
+            "[room: {}] added to pool, account: {}",
+            room_id, account.info.uid
+        );
+        Ok(())
+    }
+
+    // 取消房间任务, 连接随 ListenHandle 一起关闭
+    pub fn remove_room(&mut self, room_id: u64) -> bool {
+        let Some(room) = self.rooms.remove(&room_id) else {
+            return false;
+        };
+        room.task.abort();
+        self.proxies.remove(&room_id);
+        self.room_accounts.remove(&room_id);
+        self.counters.remove(&room_id);
+        self.states.write().unwrap().remove(&room_id);
+        info!("[room: {}] removed from pool", room_id);
+        true
+    }
+
+    // 更新 DedeUserID 相同的账号的 cookie 并重新连接其房间, 没有该账号时新增, 刷新凭据后调用
+    pub fn set_cookies(&mut self, cookies: &str) -> Result<()> {
+        let account = Account::new(cookies)?;
+        let uid = account.info.uid;
+        let Some(index) = self.accounts.iter().position(|a| a.info.uid == uid) else {
+            info!("新增账号 {}", uid);
+            self.accounts.push(account);
+            return Ok(());
+        };
+        // 新的 cookie 需要重新检查
+        self.accounts[index] = account;
+        let rooms = self.account_rooms(index);
+        for room_id in &rooms {
+            self.spawn_room(*room_id)?;
+        }
+        info!(
+            "账号 {} cookie 已更新, 重新连接 {} 个房间",
+            uid,
+            rooms.len()
+        );
+        Ok(())
+    }
+
+    fn account_rooms(&self, index: usize) -> Vec<u64> {
+        let mut rooms: Vec<u64> = self
+            .room_accounts
+            .iter()
+            .filter(|(_, i)| **i == index)
+            .map(|(room_id, _)| *room_id)
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    // 账号状态和分配的房间, 按添加顺序
+    pub fn accounts(&self) -> Vec<AccountInfo> {
+        (0..self.accounts.len())
+            .map(|index| AccountInfo {
+                rooms: self.account_rooms(index),
+                ..self.accounts[index].info.clone()
+            })
+            .collect()
+    }
+
+    // 用于在其他任务中检查账号, 不阻塞消息接收
+    pub fn account_checker(&self) -> AccountChecker {
+        AccountChecker {
+            endpoints: self.endpoints.clone(),
+            accounts: self
+                .accounts
+                .iter()
+                .map(|a| (a.info.uid, a.http.clone()))
+                .collect(),
+        }
+    }
+
+    // 更新账号状态, 不可用账号上的房间切换到其他账号
+    pub fn apply_checks(&mut self, results: Vec<(u64, CheckResult)>) -> Result<()> {
+        let now = account::now();
+        for (uid, result) in results {
+            if let Some(account) = self.accounts.iter_mut().find(|a| a.info.uid == uid) {
+                account.apply_check(result, now);
+            }
+        }
+        self.failover()
+    }
+
+    fn failover(&mut self) -> Result<()> {
+        if !self.accounts.iter().any(|a| a.info.status.is_usable()) {
+            return Ok(());
+        }
+        for index in 0..self.accounts.len() {
+            if self.accounts[index].info.status.is_usable() {
+                continue;
+            }
+            for room_id in self.account_rooms(index) {
+                info!(
+                    "[room: {}] account {} unusable, switching account",
+                    room_id, self.accounts[index].info.uid
+                );
+                self.spawn_room(room_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &ClientEvent) {
+        let ClientEvent::RiskControl { uid, code } = event else {
+            return;
+        };
+        let Some(account) = self.accounts.iter_mut().find(|a| a.info.uid == *uid) else {
+            return;
+        };
+        if matches!(account.info.status, AccountStatus::RiskControlled { .. }) {
+            return;
+        }
+        warn!("account {} risk controlled, code: {}", uid, code);
+        account.info.status = AccountStatus::RiskControlled {
+            since: account::now(),
+        };
+        if let Err(e) = self.failover() {
+            error!("failover error: {:?}", e);
+        }
+    }
+
+    pub fn rooms(&self) -> Vec<u64> {
+        self.rooms.keys().copied().collect()
+    }
+
+    pub fn state(&self, room_id: u64) -> Option<ConnectionState> {
+        self.states.read().unwrap().get(&room_id).cloned()
+    }
+
+    pub fn states(&self) -> HashMap<u64, ConnectionState> {
+        self.states.read().unwrap().clone()
+    }
+
+    // 丢弃和写盘的消息计数, 跨重连累计
+    pub fn counters(&self, room_id: u64) -> Option<Arc<QueueCounters>> {
+        self.counters.get(&room_id).cloned()
+    }
+
+    // 只返回弹幕消息, 连接事件只反映在 state 中
+    pub async fn recv(&mut self) -> Option<(u64, Message)> {
+        loop {
+            let (room_id, event) = self.recv_event().await?;
+            if let ClientEvent::Message(message) = event {
+                return Some((room_id, message));
+            }
+        }
+    }
+
+    // 返回包括连接事件在内的所有事件, 需持续调用以处理账号风控
+    pub async fn recv_event(&mut self) -> Option<(u64, ClientEvent)> {
+        let (room_id, event) = self.rx.recv().await?;
+        self.handle_event(&event);
+        Some((room_id, event))
+    }
+}
+
+impl Stream for ClientPool {
+    type Item = (u64, Message);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<(u64, Message)>> {
+        loop {
+            match ready!(self.rx.poll_recv(cx)) {
+                Some((room_id, ClientEvent::Message(message))) => {
+                    return Poll::Ready(Some((room_id, message)))
+                }
+                Some((_, event)) => self.handle_event(&event),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Drop for ClientPool {
+    fn drop(&mut self) {
+        for room in self.rooms.values() {
+            room.task.abort();
+        }
+    }
+}
+
+// 房间的读取任务, drop stop 或发送后停止读取, 已缓冲的消息发出后退出
+struct RoomTask {
+    task: JoinHandle<()>,
+    stop: oneshot::Sender<()>,
+}
+
+async fn run_room(
+    client: Client,
+    tx: mpsc::Sender<(u64, ClientEvent)>,
+    states: States,
+    stagger: Stagger,
+    mut stop: oneshot::Receiver<()>,
+    previous: Option<JoinHandle<()>>,
+) {
+    let room_id = client.room_id;
+    // 等待旧任务发出缓冲的消息, 避免新旧连接的消息交错
+    if let Some(previous) = previous {
+        let _ = previous.await;
+    }
+    let mut events = spawn_events(client, Some(stagger));
+    let mut stopping = false;
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = &mut stop, if !stopping => {
+                events.stop();
+                stopping = true;
+                continue;
+            }
+        };
+        let Some(event) = event else {
+            return;
+        };
+        match &event {
+            ClientEvent::Connecting => {
+                set_state(&states, room_id, ConnectionState::Connecting);
+            }
+            ClientEvent::Authenticated { .. } => {
+                set_state(&states, room_id, ConnectionState::Connected);
+            }
+            ClientEvent::Disconnected { reason } => {
+                set_state(
+                    &states,
+                    room_id,
+                    ConnectionState::Disconnected(reason.clone()),
+                );
+            }
+            ClientEvent::Reconnecting { .. } => {
+                set_state(&states, room_id, ConnectionState::Waiting);
+            }
+            ClientEvent::RiskControl { .. } | ClientEvent::Message(_) => {}
+        }
+        if tx.send((room_id, event)).await.is_err() {
+            // pool 已被 drop
+            return;
+        }
+    }
+}
+
+fn set_state(states: &States, room_id: u64, state: ConnectionState) {
+    info!("[room: {}] {}", room_id, state);
+    states.write().unwrap().insert(room_id, state);
+}
+
+// 相邻两次操作至少间隔 interval, 用于错峰连接和发送弹幕限速
+#[derive(Debug, Clone)]
+pub(crate) struct Stagger {
+    next_connect: Arc<Mutex<Instant>>,
+    interval: Duration,
+}
+
+impl Stagger {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            next_connect: Arc::new(Mutex::new(Instant::now())),
+            interval,
+        }
+    }
+
+    pub(crate) async fn wait(&self) {
+        let slot = reserve_slot(
+            &mut *self.next_connect.lock().await,
+            Instant::now(),
+            self.interval,
+        );
+        time::sleep_until(slot).await;
+    }
+}
+
+fn reserve_slot(next_connect: &mut Instant, now: Instant, interval: Duration) -> Instant {
+    let slot = (*next_connect).max(now);
+    *next_connect = slot + interval;
+    slot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_bili::{message, MockBili, Session};
+
+    #[test]
+    fn test_reserve_slot() {
+        let now = Instant::now();
+        let interval = Duration::from_secs(2);
+        let mut next_connect = now;
+        assert_eq!(reserve_slot(&mut next_connect, now, interval), now);
+        assert_eq!(
+            reserve_slot(&mut next_connect, now, interval),
+            now + interval
+        );
+        assert_eq!(
+            reserve_slot(&mut next_connect, now, interval),
+            now + interval * 2
+        );
+        // 空闲一段时间后不会累积等待
+        let later = now + Duration::from_secs(60);
+        assert_eq!(reserve_slot(&mut next_connect, later, interval), later);
+    }
+
+    #[tokio::test]
+    async fn test_pool_mock() {
+        let mock = MockBili::start().await.unwrap();
+        mock.push_session(1, Session::new().messages(&[message::online_count(1)]));
+        mock.push_session(2, Session::new().messages(&[message::online_count(2)]));
+        let mut pool = ClientPool::new("DedeUserID=10000; buvid3=test-buvid").unwrap();
+        pool.set_stagger(Duration::ZERO);
+        pool.set_endpoints(Endpoints::with_base(&mock.api_base()));
+        pool.add_room(1).unwrap();
+        pool.add_room(2).unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            match time::timeout(Duration::from_secs(5), pool.recv())
+                .await
+                .unwrap()
+            {
+                Some((room_id, Message::OnlineCount(m))) => received.push((room_id, m.count)),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        received.sort();
+        assert_eq!(received, vec![(1, 1), (2, 2)]);
+        assert_eq!(pool.state(1), Some(ConnectionState::Connected));
+        assert_eq!(pool.state(2), Some(ConnectionState::Connected));
+
+        assert!(pool.remove_room(1));
+        assert_eq!(pool.rooms(), vec![2]);
+        assert_eq!(pool.state(1), None);
+
+        // 更换 cookie 后房间重新连接
+        mock.push_session(2, Session::new().messages(&[message::online_count(3)]));
+        pool.set_cookies("DedeUserID=10000; buvid3=new-buvid")
+            .unwrap();
+        assert_eq!(pool.rooms(), vec![2]);
+        match time::timeout(Duration::from_secs(5), pool.recv())
+            .await
+            .unwrap()
+        {
+            Some((2, Message::OnlineCount(m))) => assert_eq!(m.count, 3),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        let certificates = mock.certificates();
+        assert_eq!(certificates.last().unwrap().buvid, "new-buvid");
+    }
+
+    #[tokio::test]
+    async fn test_respawn_keeps_buffered_messages() {
+        let mock = MockBili::start().await.unwrap();
+        let counts: Vec<String> = (0..2500).map(message::online_count).collect();
+        mock.push_session(1, Session::new().messages(&counts));
+        let mut pool = ClientPool::new("DedeUserID=10000; buvid3=test-buvid").unwrap();
+        pool.set_stagger(Duration::ZERO);
+        pool.set_endpoints(Endpoints::with_base(&mock.api_base()));
+        pool.add_room(1).unwrap();
+        let counters = pool.counters(1).unwrap();
+        // 不读取 pool, 消息堆积在 pool, 事件和读取队列中
+        time::sleep(Duration::from_millis(500)).await;
+
+        mock.push_session(1, Session::new().messages(&[message::online_count(2500)]));
+        pool.set_cookies("DedeUserID=10000; buvid3=new-buvid")
+            .unwrap();
+        assert!(Arc::ptr_eq(&counters, &pool.counters(1).unwrap()));
+        // 旧连接已读取的消息全部发出后才切换到新的连接
+        for count in 0..=2500 {
+            match time::timeout(Duration::from_secs(5), pool.recv())
+                .await
+                .unwrap()
+            {
+                Some((1, Message::OnlineCount(m))) => assert_eq!(m.count, count),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        assert_eq!(mock.certificates().last().unwrap().buvid, "new-buvid");
+    }
+
+    #[tokio::test]
+    async fn test_account_failover() {
+        let mock = MockBili::start().await.unwrap();
+        mock.set_risk_controlled(10000, true);
+        mock.push_session(1, Session::new().messages(&[message::online_count(1)]));
+        mock.push_session(2, Session::new().messages(&[message::online_count(2)]));
+        let mut pool = ClientPool::with_accounts(&[
+            "DedeUserID=10000; buvid3=test-buvid",
+            "DedeUserID=10001; buvid3=test-buvid",
+        ])
+        .unwrap();
+        pool.set_stagger(Duration::ZERO);
+        pool.set_endpoints(Endpoints::with_base(&mock.api_base()));
+        pool.add_room(1).unwrap();
+        pool.add_room(2).unwrap();
+        assert_eq!(pool.accounts()[0].rooms, vec![1]);
+        assert_eq!(pool.accounts()[1].rooms, vec![2]);
+
+        // 10000 触发风控后房间 1 切换到 10001
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            match time::timeout(Duration::from_secs(5), pool.recv())
+                .await
+                .unwrap()
+            {
+                Some((room_id, Message::OnlineCount(m))) => received.push((room_id, m.count)),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        received.sort();
+        assert_eq!(received, vec![(1, 1), (2, 2)]);
+        let accounts = pool.accounts();
+        assert!(matches!(
+            accounts[0].status,
+            AccountStatus::RiskControlled { .. }
+        ));
+        assert!(accounts[0].rooms.is_empty());
+        assert_eq!(accounts[1].rooms, vec![1, 2]);
+        assert!(mock.certificates().iter().all(|c| c.uid == 10001));
+
+        // 冷却期内 nav 正常也不恢复; 10001 失效后没有可用账号, 房间保持不变
+        mock.set_logged_out(10001, true);
+        let results = pool.account_checker().check().await;
+        pool.apply_checks(results).unwrap();
+        let accounts = pool.accounts();
+        assert!(matches!(
+            accounts[0].status,
+            AccountStatus::RiskControlled { .. }
+        ));
+        assert_eq!(accounts[1].status, AccountStatus::LoggedOut);
+        assert_eq!(accounts[1].uname, "");
+        assert_eq!(accounts[1].rooms, vec![1, 2]);
+    }
+}
@@ -0,0 +1,496 @@
+use futures_util::task::AtomicWaker;
+use futures_util::Stream;
+use log::error;
+use parse::Message;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+// 队列满时的处理方式
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum OverflowPolicy {
+    // 阻塞读取任务, 直到消费者取走消息
+    #[default]
+    Block,
+    // 丢弃队列中最早的消息
+    DropOldest,
+    // 丢弃新收到的消息
+    DropNewest,
+    // 写入 dir 下该队列的 spill 文件, 内存队列读空后再读回
+    SpillToDisk {
+        dir: PathBuf,
+    },
+}
+
+// 房间连接的累计计数, 重连后不清零
+#[derive(Debug, Default)]
+pub struct QueueCounters {
+    dropped: AtomicU64,
+    spilled: AtomicU64,
+    parse_errors: AtomicU64,
+}
+
+impl QueueCounters {
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn spilled(&self) -> u64 {
+        self.spilled.load(Ordering::Relaxed)
+    }
+
+    pub fn parse_errors(&self) -> u64 {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_parse_errors(&self, count: u64) {
+        self.parse_errors.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn channel(
+    room_id: u64,
+    capacity: usize,
+    policy: OverflowPolicy,
+    counters: Arc<QueueCounters>,
+) -> io::Result<(QueueSender, QueueReceiver)> {
+    // 同一房间重连后的新队列使用新的文件, 旧队列 drop 时不会删除新队列的文件
+    let spill = match &policy {
+        OverflowPolicy::SpillToDisk { dir } => {
+            Some(Arc::new(Mutex::new(SpillFile::new(dir.join(format!(
+                "{}-{}-{}.spill",
+                room_id,
+                std::process::id(),
+                SPILL_SEQ.fetch_add(1, Ordering::Relaxed)
+            ))))))
+        }
+        _ => None,
+    };
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            spilled: 0,
+            loading: false,
+            sender_closed: false,
+            receiver_closed: false,
+        }),
+        capacity: capacity.max(1),
+        policy,
+        spill,
+        counters,
+        rx_waker: AtomicWaker::new(),
+        not_full: Notify::new(),
+    });
+    Ok((
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    ))
+}
+
+static SPILL_SEQ: AtomicU64 = AtomicU64::new(0);
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // 文件读写在 spawn_blocking 中执行, 不持有 state 的锁
+    spill: Option<Arc<Mutex<SpillFile>>>,
+    counters: Arc<QueueCounters>,
+    rx_waker: AtomicWaker,
+    not_full: Notify,
+}
+
+struct State {
+    buffer: VecDeque<Message>,
+    // 已写盘但还未读回内存队列的消息数
+    spilled: usize,
+    // 正在从文件读回消息
+    loading: bool,
+    sender_closed: bool,
+    receiver_closed: bool,
+}
+
+impl Shared {
+    // 队列已满时返回原消息, 由发送端按策略等待或写盘
+    fn try_push(&self, message: Message) -> Result<(), Message> {
+        let mut state = self.state.lock().unwrap();
+        // 一旦开始写盘, 后续消息也写盘, 保证顺序
+        if state.buffer.len() < self.capacity && state.spilled == 0 {
+            state.buffer.push_back(message);
+            return Ok(());
+        }
+        match &self.policy {
+            OverflowPolicy::Block | OverflowPolicy::SpillToDisk { .. } => return Err(message),
+            OverflowPolicy::DropOldest => {
+                state.buffer.pop_front();
+                state.buffer.push_back(message);
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            OverflowPolicy::DropNewest => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    // 返回 (消息, 是否已读完), 内存队列为空时在后台读回写盘的消息
+    fn pop(self: &Arc<Self>) -> (Option<Message>, bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.buffer.is_empty() && state.spilled > 0 && !state.loading {
+            if let Some(spill) = &self.spill {
+                state.loading = true;
+                let limit = state.spilled.min(self.capacity);
+                let spill = spill.clone();
+                let shared = self.clone();
+                tokio::task::spawn_blocking(move || {
+                    let result = spill.lock().unwrap().read(limit);
+                    let mut state = shared.state.lock().unwrap();
+                    state.loading = false;
+                    match result {
+                        Ok(messages) => {
+                            state.spilled -= messages.len();
+                            state.buffer.extend(messages);
+                        }
+                        Err(e) => {
+                            error!("Failed to read spilled messages: {}", e);
+                            shared
+                                .counters
+                                .dropped
+                                .fetch_add(state.spilled as u64, Ordering::Relaxed);
+                            state.spilled = 0;
+                        }
+                    }
+                    drop(state);
+                    shared.rx_waker.wake();
+                });
+            }
+        }
+        let message = state.buffer.pop_front();
+        if message.is_some() {
+            self.not_full.notify_one();
+        }
+        (message, state.sender_closed && state.spilled == 0)
+    }
+}
+
+pub(crate) struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl QueueSender {
+    // 接收端已关闭时返回 Err
+    pub(crate) async fn send(&self, message: Message) -> Result<(), Message> {
+        let mut message = message;
+        loop {
+            if self.shared.state.lock().unwrap().receiver_closed {
+                return Err(message);
+            }
+            match self.shared.try_push(message) {
+                Ok(()) => {
+                    self.shared.rx_waker.wake();
+                    return Ok(());
+                }
+                Err(m) => message = m,
+            }
+            if let Some(spill) = &self.shared.spill {
+                self.spill(spill.clone(), message).await;
+                return Ok(());
+            }
+            self.shared.not_full.notified().await;
+        }
+    }
+
+    // 写入完成后才计入 spilled, 读回时不会读到未写完的行
+    async fn spill(&self, spill: Arc<Mutex<SpillFile>>, message: Message) {
+        let result = tokio::task::spawn_blocking(move || spill.lock().unwrap().write(&message))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match result {
+            Ok(()) => {
+                self.shared.state.lock().unwrap().spilled += 1;
+                self.shared.counters.spilled.fetch_add(1, Ordering::Relaxed);
+                self.shared.rx_waker.wake();
+            }
+            Err(e) => {
+                error!("Failed to spill message: {}", e);
+                self.shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().sender_closed = true;
+        self.shared.rx_waker.wake();
+    }
+}
+
+pub(crate) struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+impl Stream for QueueReceiver {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        if let (Some(message), _) = self.shared.pop() {
+            return Poll::Ready(Some(message));
+        }
+        self.shared.rx_waker.register(cx.waker());
+        // 注册 waker 后再检查一次, 避免丢失唤醒
+        match self.shared.pop() {
+            (Some(message), _) => Poll::Ready(Some(message)),
+            (None, true) => Poll::Ready(None),
+            (None, false) => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_closed = true;
+        self.shared.not_full.notify_one();
+    }
+}
+
+// 写盘文件, 按行存储 json, 第一次写盘时创建, 全部读回后截断
+struct SpillFile {
+    path: PathBuf,
+    files: Option<(File, BufReader<File>)>,
+    written: usize,
+    read: usize,
+}
+
+impl SpillFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            files: None,
+            written: 0,
+            read: 0,
+        }
+    }
+
+    fn write(&mut self, message: &Message) -> io::Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        let (writer, _) = match &mut self.files {
+            Some(files) => files,
+            None => {
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let writer = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(&self.path)?;
+                let reader = BufReader::new(File::open(&self.path)?);
+                self.files.insert((writer, reader))
+            }
+        };
+        writer.write_all(&line)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    // 按顺序读回 limit 条消息, 出错时丢弃文件中剩余的消息
+    fn read(&mut self, limit: usize) -> io::Result<Vec<Message>> {
+        let result = self.read_lines(limit);
+        if result.is_err() || self.read == self.written {
+            self.reset();
+        }
+        result
+    }
+
+    fn read_lines(&mut self, limit: usize) -> io::Result<Vec<Message>> {
+        let Some((_, reader)) = &mut self.files else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "spill file not created",
+            ));
+        };
+        let mut messages = Vec::with_capacity(limit);
+        let mut line = String::new();
+        while messages.len() < limit {
+            line.clear();
+            if self.read == self.written || reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "spill file shorter than expected",
+                ));
+            }
+            messages.push(serde_json::from_str(&line)?);
+            self.read += 1;
+        }
+        Ok(messages)
+    }
+
+    fn reset(&mut self) {
+        self.written = 0;
+        self.read = 0;
+        let Some((writer, reader)) = &mut self.files else {
+            return;
+        };
+        if let Err(e) = writer
+            .set_len(0)
+            .and_then(|_| writer.seek(SeekFrom::Start(0)))
+            .and_then(|_| reader.seek(SeekFrom::Start(0)))
+        {
+            error!("Failed to truncate spill file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if self.files.is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use parse::OnlineCountMessage;
+    use std::time::Duration;
+    use tokio::time;
+
+    fn message(count: u64) -> Message {
+        Message::OnlineCount(OnlineCountMessage {
+            count,
+            timestamp: 0,
+        })
+    }
+
+    fn count(message: Option<Message>) -> u64 {
+        match message {
+            Some(Message::OnlineCount(message)) => message.count,
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    async fn fill(policy: OverflowPolicy) -> (QueueSender, QueueReceiver, Arc<QueueCounters>) {
+        let counters = Arc::new(QueueCounters::default());
+        let (tx, rx) = channel(1, 2, policy, counters.clone()).unwrap();
+        for i in 0..5 {
+            tx.send(message(i)).await.unwrap();
+        }
+        (tx, rx, counters)
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let (tx, mut rx, counters) = fill(OverflowPolicy::DropOldest).await;
+        drop(tx);
+        assert_eq!(count(rx.next().await), 3);
+        assert_eq!(count(rx.next().await), 4);
+        assert!(rx.next().await.is_none());
+        assert_eq!(counters.dropped(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest() {
+        let (tx, mut rx, counters) = fill(OverflowPolicy::DropNewest).await;
+        drop(tx);
+        assert_eq!(count(rx.next().await), 0);
+        assert_eq!(count(rx.next().await), 1);
+        assert!(rx.next().await.is_none());
+        assert_eq!(counters.dropped(), 3);
+    }
+
+    fn spill_files(dir: &std::path::Path) -> Vec<PathBuf> {
+        match fs::read_dir(dir) {
+            Ok(entries) => entries.map(|entry| entry.unwrap().path()).collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    // 后台读回任务结束后文件才随队列一起删除
+    async fn wait_spill_files(dir: &std::path::Path, count: usize) {
+        for _ in 0..100 {
+            if spill_files(dir).len() == count {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(spill_files(dir).len(), count);
+    }
+
+    #[tokio::test]
+    async fn test_spill_to_disk() {
+        let dir = std::env::temp_dir().join(format!("danmu-spill-{}", std::process::id()));
+        let (tx, mut rx, counters) = fill(OverflowPolicy::SpillToDisk { dir: dir.clone() }).await;
+        assert_eq!(counters.spilled(), 3);
+        assert_eq!(spill_files(&dir).len(), 1);
+        // 读回时保持顺序, 写盘期间的新消息排在写盘消息之后
+        assert_eq!(count(rx.next().await), 0);
+        assert_eq!(count(rx.next().await), 1);
+        assert_eq!(count(rx.next().await), 2);
+        tx.send(message(5)).await.unwrap();
+        for i in 3..6 {
+            assert_eq!(count(rx.next().await), i);
+        }
+        drop(tx);
+        assert!(rx.next().await.is_none());
+        assert_eq!(counters.dropped(), 0);
+        drop(rx);
+        wait_spill_files(&dir, 0).await;
+        let _ = fs::remove_dir(dir);
+    }
+
+    #[tokio::test]
+    async fn test_spill_file_per_queue() {
+        let dir = std::env::temp_dir().join(format!("danmu-spill-queue-{}", std::process::id()));
+        let policy = OverflowPolicy::SpillToDisk { dir: dir.clone() };
+        let (old_tx, old_rx, _) = fill(policy.clone()).await;
+        let (new_tx, mut new_rx, _) = fill(policy).await;
+        assert_eq!(spill_files(&dir).len(), 2);
+        // 同一房间的旧队列 drop 时不删除新队列的文件
+        drop(old_tx);
+        drop(old_rx);
+        wait_spill_files(&dir, 1).await;
+        drop(new_tx);
+        for i in 0..5 {
+            assert_eq!(count(new_rx.next().await), i);
+        }
+        assert!(new_rx.next().await.is_none());
+        drop(new_rx);
+        wait_spill_files(&dir, 0).await;
+        let _ = fs::remove_dir(dir);
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let counters = Arc::new(QueueCounters::default());
+        let (tx, mut rx) = channel(1, 1, OverflowPolicy::Block, counters.clone()).unwrap();
+        tx.send(message(0)).await.unwrap();
+        // 队列已满, 发送阻塞直到消费者取走消息
+        assert!(
+            time::timeout(Duration::from_millis(50), tx.send(message(1)))
+                .await
+                .is_err()
+        );
+        let sender = tokio::spawn(async move {
+            tx.send(message(1)).await.unwrap();
+        });
+        assert_eq!(count(rx.next().await), 0);
+        sender.await.unwrap();
+        assert_eq!(count(rx.next().await), 1);
+        assert!(rx.next().await.is_none());
+        assert_eq!(counters.dropped(), 0);
+    }
+}
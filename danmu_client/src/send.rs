@@ -0,0 +1,211 @@
+use crate::danmu::Client;
+use crate::error::SendError;
+use log::{debug, error};
+use serde::Deserialize;
+
+// 未开通粉丝勋章等特权时, 单条弹幕最多 20 字
+const DEFAULT_MAX_LEN: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendOptions {
+    pub color: u32, // 十进制 RGB, 默认白色
+    pub mode: u8,   // 1 滚动, 4 底部, 5 顶部
+    pub fontsize: u32,
+    pub max_len: usize, // 按字符计数
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            color: 0xffffff,
+            mode: 1,
+            fontsize: 25,
+            max_len: DEFAULT_MAX_LEN,
+        }
+    }
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct SendResponse {
+    code: i64,
+    message: String,
+}
+
+impl SendResponse {
+    fn into_result(self) -> Result<(), SendError> {
+        match self.code {
+            // 被屏蔽词过滤时 code 仍为 0, message 为 "f" 或 "k"
+            0 if self.message.is_empty() => Ok(()),
+            0 => Err(SendError::Filtered(self.message)),
+            -101 => Err(SendError::NotLoggedIn),
+            -111 => Err(SendError::CsrfFailed),
+            10030 | 10031 => Err(SendError::RateLimited(self.code)),
+            code => Err(SendError::Api {
+                code,
+                message: self.message,
+            }),
+        }
+    }
+}
+
+impl Client {
+    // 使用 cookie 中的账号发送弹幕, 相邻两次发送至少间隔 send_interval
+    pub async fn send_danmu(
+        &self,
+        room_id: u64,
+        text: &str,
+        options: &SendOptions,
+    ) -> Result<(), SendError> {
+        let len = text.chars().count();
+        if text.trim().is_empty() {
+            return Err(SendError::Empty);
+        }
+        if len > options.max_len {
+            return Err(SendError::TooLong {
+                len,
+                max: options.max_len,
+            });
+        }
+        if self.csrf.is_empty() {
+            return Err(SendError::MissingCsrf);
+        }
+
+        self.send_limiter.wait().await;
+        let rnd = chrono::Local::now().timestamp().to_string();
+        let form = [
+            ("bubble", "0".to_string()),
+            ("msg", text.to_string()),
+            ("color", options.color.to_string()),
+            ("mode", options.mode.to_string()),
+            ("fontsize", options.fontsize.to_string()),
+            ("rnd", rnd),
+            ("roomid", room_id.to_string()),
+            ("csrf", self.csrf.clone()),
+            ("csrf_token", self.csrf.clone()),
+        ];
+        let url = format!("{}/msg/send", self.endpoints.api);
+        let resp = self
+            .http
+            .post(&url)
+            .form(&form)
+            .send()
+            .await?
+            .json::<SendResponse>()
+            .await?;
+        let result = resp.into_result();
+        match &result {
+            Ok(()) => debug!("[room: {}] danmu sent: {}", room_id, text),
+            Err(e) => error!("[room: {}] failed to send danmu: {}", room_id, e),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::danmu::Endpoints;
+    use mock_bili::MockBili;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    const COOKIES: &str = "DedeUserID=10000; buvid3=test-buvid; bili_jct=test-csrf";
+
+    fn mock_client(mock: &MockBili, cookies: &str) -> Client {
+        let mut client = Client::new(1, cookies).unwrap();
+        client.set_endpoints(Endpoints::with_base(&mock.api_base()));
+        client.set_send_interval(Duration::ZERO);
+        client
+    }
+
+    #[tokio::test]
+    async fn test_send_danmu() {
+        let mock = MockBili::start().await.unwrap();
+        let client = mock_client(&mock, COOKIES);
+        let options = SendOptions {
+            color: 0xe33fff,
+            ..Default::default()
+        };
+        client.send_danmu(2, "你好", &options).await.unwrap();
+
+        let sent = mock.sent_danmu();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].roomid, 2);
+        assert_eq!(sent[0].msg, "你好");
+        assert_eq!(sent[0].color, 0xe33fff);
+        assert_eq!(sent[0].csrf, "test-csrf");
+    }
+
+    #[tokio::test]
+    async fn test_send_danmu_validation() {
+        let mock = MockBili::start().await.unwrap();
+        let client = mock_client(&mock, COOKIES);
+        let options = SendOptions::default();
+        assert!(matches!(
+            client.send_danmu(1, " ", &options).await,
+            Err(SendError::Empty)
+        ));
+        // 按字符而不是字节计数
+        assert!(client
+            .send_danmu(1, &"弹".repeat(20), &options)
+            .await
+            .is_ok());
+        assert!(matches!(
+            client.send_danmu(1, &"弹".repeat(21), &options).await,
+            Err(SendError::TooLong { len: 21, max: 20 })
+        ));
+
+        let client = mock_client(&mock, "DedeUserID=10000; buvid3=test-buvid");
+        assert!(matches!(
+            client.send_danmu(1, "hello", &options).await,
+            Err(SendError::MissingCsrf)
+        ));
+        assert_eq!(mock.sent_danmu().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_danmu_errors() {
+        let mock = MockBili::start().await.unwrap();
+        let options = SendOptions::default();
+        let client = mock_client(&mock, COOKIES);
+        mock.set_send_reply(-111, "csrf 校验失败");
+        assert!(matches!(
+            client.send_danmu(1, "hello", &options).await,
+            Err(SendError::CsrfFailed)
+        ));
+        mock.set_send_reply(10030, "msg in 1s");
+        assert!(matches!(
+            client.send_danmu(1, "hello", &options).await,
+            Err(SendError::RateLimited(10030))
+        ));
+        mock.set_send_reply(0, "f");
+        assert!(matches!(
+            client.send_danmu(1, "hello", &options).await,
+            Err(SendError::Filtered(message)) if message == "f"
+        ));
+        mock.set_send_reply(1003, "unknown");
+        assert!(matches!(
+            client.send_danmu(1, "hello", &options).await,
+            Err(SendError::Api { code: 1003, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_interval() {
+        let mock = MockBili::start().await.unwrap();
+        let mut client = mock_client(&mock, COOKIES);
+        client.set_send_interval(Duration::from_millis(200));
+        let options = SendOptions::default();
+        let start = Instant::now();
+        for _ in 0..3 {
+            client
+                .clone()
+                .send_danmu(1, "hello", &options)
+                .await
+                .unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(mock.sent_danmu().len(), 3);
+    }
+}
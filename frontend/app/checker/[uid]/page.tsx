@@ -22,6 +22,22 @@ interface QueryResponseData {
     code: number
     message: string
     data: CheckerData[]
+    blocks: BlockAction[]
+}
+
+interface BlockAction {
+    room_id: number,
+    uid: number,
+    hour: number,
+    timestamp: number,
+    status: string,
+}
+
+const blockStatus: Record<string, string> = {
+    pending: "等待确认",
+    confirmed: "已确认",
+    unconfirmed: "未确认",
+    removed: "已解除",
 }
 
 interface CheckerData {
@@ -91,6 +107,28 @@ const DataTable = ({params}: {params:{uid: number}}) => {
                         </Button>
                     </form>
                 </div>
+                {response?.blocks?.length ? <div className={"flex flex-col mt-8"}>
+                    <Table>
+                        <TableHead>
+                            <TableRow>
+                                <TableHeader>禁言直播间</TableHeader>
+                                <TableHeader>禁言时长</TableHeader>
+                                <TableHeader>操作时间</TableHeader>
+                                <TableHeader>状态</TableHeader>
+                            </TableRow>
+                        </TableHead>
+                        <TableBody>
+                            {response.blocks.map((block: BlockAction, index: number) => {
+                                return <TableRow key={index}>
+                                    <TableCell>{streamerData.find(x => x.room_id == block.room_id)?.nickname || block.room_id}</TableCell>
+                                    <TableCell>{block.hour == -1 ? "永久" : block.hour == 0 ? "本场直播" : `${block.hour} 小时`}</TableCell>
+                                    <TableCell>{getFormatTime(block.timestamp)}</TableCell>
+                                    <TableCell className="text-zinc-500">{blockStatus[block.status] || block.status}</TableCell>
+                                </TableRow>
+                            })}
+                        </TableBody>
+                    </Table>
+                </div> : null}
                 <div className={"flex flex-col mt-8"}>
                     {isLoading ? <Loading/> : null}
                     <Table hidden={isLoading}>
@@ -14,6 +14,7 @@ import {Table, TableBody, TableCell, TableHead, TableHeader, TableRow} from "@/c
 import {Pagination, PaginationList, PaginationNext, PaginationPage, PaginationPrevious} from "@/components/pagination";
 import {DanmuChart} from "@/components/chart/danmu_chart";
 import Link from "next/link";
+import {Text} from "@/components/text";
 
 const statisticsFetcher = async (params: {
     room_id: string,
@@ -221,6 +222,11 @@ export const DataTable = (params: { room_id: string }) => {
                 </form>
             </div>
             <div className={"flex flex-col mt-8"}>
+                {danmuData?.gaps?.map((gap: DataGap, index: number) => (
+                    <Text key={index} className={"text-amber-600"}>
+                        {getFormatTime(gap.start)} - {getFormatTime(gap.end)} 连接断开, 该时段数据不完整
+                    </Text>
+                ))}
                 {isLoading ? <Loading/> : null}
                 <Table hidden={isLoading}>
                     <TableHead>
@@ -302,6 +308,13 @@ export interface DanmuMessageResponse {
     message: string
     count: number
     data: DanmuMessage[]
+    gaps: DataGap[]
+}
+
+export interface DataGap {
+    start: number
+    end: number
+    reason: string
 }
 
 export interface DanmuMessage {
@@ -0,0 +1,482 @@
+pub mod message;
+mod moderation;
+mod passport;
+pub mod proxy;
+pub mod session;
+
+use anyhow::Result;
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use log::{debug, error};
+use parse::Certificate;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+pub use moderation::SilentRecord;
+pub use proxy::MockProxy;
+pub use session::{Session, Step};
+
+// 本地模拟的 B 站直播接口和弹幕服务器, 用于离线测试
+pub struct MockBili {
+    api_addr: SocketAddr,
+    danmu_addr: SocketAddr,
+    state: Arc<MockState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+pub(crate) struct MockState {
+    danmu_port: Mutex<u16>,
+    sessions: Mutex<HashMap<u64, VecDeque<Session>>>,
+    danmu_info_codes: Mutex<HashMap<u64, i64>>,
+    certificates: Mutex<Vec<Certificate>>,
+    heartbeats: Mutex<HashMap<u64, usize>>,
+    sent_danmu: Mutex<Vec<SentDanmu>>,
+    send_reply: Mutex<Option<(i64, String)>>,
+    silent_users: Mutex<HashMap<u64, Vec<SilentRecord>>>,
+    // 账号不是房管的房间
+    unmanaged_rooms: Mutex<HashSet<u64>>,
+    passport: Mutex<passport::PassportState>,
+    // 按 DedeUserID 区分的账号状态
+    risk_controlled_uids: Mutex<HashSet<u64>>,
+    logged_out_uids: Mutex<HashSet<u64>>,
+}
+
+// 收到的发送弹幕请求
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SentDanmu {
+    pub roomid: u64,
+    pub msg: String,
+    pub color: u32,
+    pub mode: u8,
+    pub fontsize: u32,
+    pub csrf: String,
+}
+
+impl MockBili {
+    pub async fn start() -> Result<Self> {
+        let state = Arc::new(MockState::default());
+
+        let danmu_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let danmu_addr = danmu_listener.local_addr()?;
+        *state.danmu_port.lock().unwrap() = danmu_addr.port();
+        let danmu_state = state.clone();
+        let danmu_task = tokio::spawn(async move {
+            while let Ok((socket, _)) = danmu_listener.accept().await {
+                tokio::spawn(serve_danmu(socket, danmu_state.clone()));
+            }
+        });
+
+        let api_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let api_addr = api_listener.local_addr()?;
+        let app = Router::new()
+            .route("/xlive/web-room/v1/index/getDanmuInfo", get(get_danmu_info))
+            .route("/msg/send", post(send_danmu))
+            .route("/x/web-interface/nav", get(nav))
+            .merge(moderation::routes())
+            .merge(passport::routes())
+            .with_state(state.clone());
+        let api_task = tokio::spawn(async move {
+            if let Err(e) = axum::serve(api_listener, app).await {
+                error!("mock api server error: {}", e);
+            }
+        });
+
+        Ok(Self {
+            api_addr,
+            danmu_addr,
+            state,
+            tasks: vec![danmu_task, api_task],
+        })
+    }
+
+    // 替换 https://api.live.bilibili.com
+    pub fn api_base(&self) -> String {
+        format!("http://{}", self.api_addr)
+    }
+
+    pub fn danmu_addr(&self) -> SocketAddr {
+        self.danmu_addr
+    }
+
+    pub fn token(room_id: u64) -> String {
+        format!("mock-token-{}", room_id)
+    }
+
+    // 每次连接依次取出一个 session, 取完后的连接只认证并保持
+    pub fn push_session(&self, room_id: u64, session: Session) {
+        self.state
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(room_id)
+            .or_default()
+            .push_back(session);
+    }
+
+    // getDanmuInfo 返回的 code, 如 -352 模拟风控
+    pub fn set_danmu_info_code(&self, room_id: u64, code: i64) {
+        self.state
+            .danmu_info_codes
+            .lock()
+            .unwrap()
+            .insert(room_id, code);
+    }
+
+    // 该账号请求 getDanmuInfo 时返回 -352
+    pub fn set_risk_controlled(&self, uid: u64, risk_controlled: bool) {
+        let mut uids = self.state.risk_controlled_uids.lock().unwrap();
+        if risk_controlled {
+            uids.insert(uid);
+        } else {
+            uids.remove(&uid);
+        }
+    }
+
+    // 该账号请求 nav 时返回未登录
+    pub fn set_logged_out(&self, uid: u64, logged_out: bool) {
+        let mut uids = self.state.logged_out_uids.lock().unwrap();
+        if logged_out {
+            uids.insert(uid);
+        } else {
+            uids.remove(&uid);
+        }
+    }
+
+    // 之后的发送弹幕请求都返回该 code 和 message, 如 10030 模拟发送过快
+    pub fn set_send_reply(&self, code: i64, message: &str) {
+        *self.state.send_reply.lock().unwrap() = Some((code, message.to_string()));
+    }
+
+    // 发送成功的弹幕, 按请求顺序
+    pub fn sent_danmu(&self) -> Vec<SentDanmu> {
+        self.state.sent_danmu.lock().unwrap().clone()
+    }
+
+    // 账号默认是所有房间的房管
+    pub fn set_manager(&self, room_id: u64, manager: bool) {
+        let mut rooms = self.state.unmanaged_rooms.lock().unwrap();
+        if manager {
+            rooms.remove(&room_id);
+        } else {
+            rooms.insert(room_id);
+        }
+    }
+
+    // 当前被禁言的 uid, 按禁言顺序
+    pub fn silent_users(&self, room_id: u64) -> Vec<u64> {
+        self.state
+            .silent_users
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .map(|users| users.iter().map(|user| user.tuid).collect())
+            .unwrap_or_default()
+    }
+
+    // 扫码登录轮询返回的状态, 默认 86101 未扫码, 0 为登录成功
+    pub fn set_qrcode_status(&self, code: i64) {
+        self.state.passport.lock().unwrap().set_qrcode_status(code);
+    }
+
+    // cookie/info 是否返回需要刷新
+    pub fn set_refresh_needed(&self, refresh_needed: bool) {
+        self.state
+            .passport
+            .lock()
+            .unwrap()
+            .set_refresh_needed(refresh_needed);
+    }
+
+    // 最近一次刷新是否已用新 cookie 确认
+    pub fn refresh_confirmed(&self) -> bool {
+        self.state.passport.lock().unwrap().refresh_confirmed()
+    }
+
+    // 收到的认证包, 按连接顺序
+    pub fn certificates(&self) -> Vec<Certificate> {
+        self.state.certificates.lock().unwrap().clone()
+    }
+
+    pub fn connections(&self, room_id: u64) -> usize {
+        self.state
+            .certificates
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.roomid == room_id)
+            .count()
+    }
+
+    pub fn heartbeats(&self, room_id: u64) -> usize {
+        *self
+            .state
+            .heartbeats
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .unwrap_or(&0)
+    }
+}
+
+impl Drop for MockBili {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DanmuInfoRequest {
+    id: u64,
+}
+
+fn cookie_uid(headers: &HeaderMap) -> Option<u64> {
+    cookie_value(headers, "DedeUserID").and_then(|uid| uid.parse().ok())
+}
+
+async fn get_danmu_info(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Query(req): Query<DanmuInfoRequest>,
+) -> Json<Value> {
+    let risk_controlled = cookie_uid(&headers)
+        .is_some_and(|uid| state.risk_controlled_uids.lock().unwrap().contains(&uid));
+    let code = match risk_controlled {
+        true => -352,
+        false => *state
+            .danmu_info_codes
+            .lock()
+            .unwrap()
+            .get(&req.id)
+            .unwrap_or(&0),
+    };
+    if code != 0 {
+        return Json(json!({
+            "code": code,
+            "message": code.to_string(),
+            "ttl": 1,
+            "data": {"v_voucher": "mock_voucher"},
+        }));
+    }
+    let port = *state.danmu_port.lock().unwrap();
+    Json(json!({
+        "code": 0,
+        "message": "0",
+        "ttl": 1,
+        "data": {
+            "group": "live",
+            "business_id": 0,
+            "refresh_row_factor": 0.125,
+            "refresh_rate": 100,
+            "max_delay": 5000,
+            "token": MockBili::token(req.id),
+            "host_list": [{"host": "127.0.0.1", "port": port, "wss_port": 0, "ws_port": 0}],
+        },
+    }))
+}
+
+async fn nav(State(state): State<Arc<MockState>>, headers: HeaderMap) -> Json<Value> {
+    match cookie_uid(&headers) {
+        Some(uid) if !state.logged_out_uids.lock().unwrap().contains(&uid) => Json(json!({
+            "code": 0,
+            "message": "0",
+            "ttl": 1,
+            "data": {"isLogin": true, "mid": uid, "uname": format!("mock-{}", uid)},
+        })),
+        _ => Json(json!({
+            "code": -101,
+            "message": "账号未登录",
+            "ttl": 1,
+            "data": {"isLogin": false},
+        })),
+    }
+}
+
+async fn send_danmu(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Form(danmu): Form<SentDanmu>,
+) -> Json<Value> {
+    let (code, message) = check_csrf(&headers, &danmu.csrf)
+        .unwrap_or_else(|| state.send_reply.lock().unwrap().clone().unwrap_or_default());
+    if code == 0 && message.is_empty() {
+        state.sent_danmu.lock().unwrap().push(danmu);
+    }
+    Json(json!({
+        "code": code,
+        "data": {},
+        "message": message,
+        "msg": message,
+    }))
+}
+
+pub(crate) fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get("cookie")
+        .and_then(|cookie| cookie.to_str().ok())
+        .and_then(|cookie| {
+            cookie
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+}
+
+// 校验失败时返回错误 code 和 message
+pub(crate) fn check_csrf(headers: &HeaderMap, csrf: &str) -> Option<(i64, String)> {
+    match cookie_value(headers, "bili_jct") {
+        None => Some((-101, "账号未登录".to_string())),
+        Some(bili_jct) if bili_jct != csrf => Some((-111, "csrf 校验失败".to_string())),
+        Some(_) => None,
+    }
+}
+
+async fn read_packet(reader: &mut OwnedReadHalf) -> std::io::Result<(parse::Header, Vec<u8>)> {
+    let mut header_buffer = [0; 16];
+    reader.read_exact(&mut header_buffer).await?;
+    let header = parse::parse_header(&header_buffer);
+    let mut body = vec![0; (header.total_size as usize).saturating_sub(16)];
+    reader.read_exact(&mut body).await?;
+    Ok((header, body))
+}
+
+async fn serve_danmu(socket: TcpStream, state: Arc<MockState>) {
+    let (mut reader, mut writer) = socket.into_split();
+
+    let (header, body) = match read_packet(&mut reader).await {
+        Ok(packet) => packet,
+        Err(e) => {
+            debug!("mock danmu: failed to read auth packet: {}", e);
+            return;
+        }
+    };
+    let certificate = match serde_json::from_slice::<Certificate>(&body) {
+        Ok(certificate) if header.msg_type == 7 => certificate,
+        _ => {
+            debug!("mock danmu: invalid auth packet");
+            return;
+        }
+    };
+    let room_id = certificate.roomid;
+    let code = if certificate.key == MockBili::token(room_id) {
+        0
+    } else {
+        -101
+    };
+    state.certificates.lock().unwrap().push(certificate);
+    let reply = json!({ "code": code }).to_string();
+    if writer
+        .write_all(&parse::build_packet(1, 8, reply.as_bytes()))
+        .await
+        .is_err()
+        || code != 0
+    {
+        return;
+    }
+
+    let session = state
+        .sessions
+        .lock()
+        .unwrap()
+        .get_mut(&room_id)
+        .and_then(|sessions| sessions.pop_front())
+        .unwrap_or_default();
+
+    let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(64);
+    let writer_task = tokio::spawn(async move {
+        while let Some(packet) = out_rx.recv().await {
+            if writer.write_all(&packet).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // 回复心跳, 连接断开时结束
+    let heartbeat_tx = out_tx.clone();
+    let heartbeat_state = state.clone();
+    let mut heartbeat_task = tokio::spawn(async move {
+        while let Ok((header, _)) = read_packet(&mut reader).await {
+            if header.msg_type != 2 {
+                continue;
+            }
+            *heartbeat_state
+                .heartbeats
+                .lock()
+                .unwrap()
+                .entry(room_id)
+                .or_default() += 1;
+            let reply = parse::build_packet(1, 3, &1_u32.to_be_bytes());
+            if heartbeat_tx.send(reply).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    for step in session.steps {
+        match step {
+            Step::Packet(packet) => {
+                if out_tx.send(packet).await.is_err() {
+                    break;
+                }
+            }
+            Step::Sleep(duration) => time::sleep(duration).await,
+            Step::Close => {
+                heartbeat_task.abort();
+                drop(out_tx);
+                let _ = writer_task.await;
+                return;
+            }
+        }
+    }
+    drop(out_tx);
+    let _ = (&mut heartbeat_task).await;
+    writer_task.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_auth_and_session() {
+        let mock = MockBili::start().await.unwrap();
+        mock.push_session(1, Session::new().command(&message::online_count(7)).close());
+
+        let mut socket = TcpStream::connect(mock.danmu_addr()).await.unwrap();
+        let certificate = Certificate {
+            uid: 0,
+            roomid: 1,
+            protover: 3,
+            buvid: String::new(),
+            platform: "web".to_string(),
+            r#type: 2,
+            key: MockBili::token(1),
+        };
+        socket
+            .write_all(&parse::build_auth_packet(&certificate))
+            .await
+            .unwrap();
+        let (mut reader, _writer) = socket.into_split();
+        let (header, body) = read_packet(&mut reader).await.unwrap();
+        assert_eq!(header.msg_type, 8);
+        assert_eq!(body, br#"{"code":0}"#);
+        let (header, _) = read_packet(&mut reader).await.unwrap();
+        assert_eq!(header.msg_type, 5);
+        assert!(read_packet(&mut reader).await.is_err());
+        assert_eq!(mock.connections(1), 1);
+    }
+}
@@ -0,0 +1,59 @@
+use serde_json::json;
+
+// 构造与线上格式一致的消息 json, 只包含 parse 用到的字段
+
+pub fn danmu(uid: u64, username: &str, msg: &str, timestamp_ms: u64) -> String {
+    json!({
+        "cmd": "DANMU_MSG",
+        "info": [[0, 1, 25, 16777215, timestamp_ms], msg, [uid, username]],
+    })
+    .to_string()
+}
+
+pub fn super_chat(uid: u64, username: &str, msg: &str, price: f64, send_time_ms: u64) -> String {
+    json!({
+        "cmd": "SUPER_CHAT_MESSAGE",
+        "data": {
+            "message": msg,
+            "price": price,
+            "uid": uid,
+            "uinfo": {"uid": uid, "base": {"name": username}},
+        },
+        "send_time": send_time_ms,
+    })
+    .to_string()
+}
+
+pub fn enter_room(uid: u64, username: &str, timestamp: u64) -> String {
+    json!({
+        "cmd": "INTERACT_WORD",
+        "data": {
+            "timestamp": timestamp,
+            "uinfo": {"uid": uid, "base": {"name": username}},
+        },
+    })
+    .to_string()
+}
+
+pub fn online_count(count: u64) -> String {
+    json!({
+        "cmd": "ONLINE_RANK_COUNT",
+        "data": {"online_count": count},
+    })
+    .to_string()
+}
+
+pub fn block_user(uid: u64, username: &str, operator: i16, block_expired: i64) -> String {
+    json!({
+        "cmd": "ROOM_BLOCK_MSG",
+        "data": {
+            "block_expired": block_expired,
+            "operator": operator,
+            "uid": uid,
+            "uname": username,
+        },
+        "uid": uid,
+        "uname": username,
+    })
+    .to_string()
+}
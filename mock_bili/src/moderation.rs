@@ -0,0 +1,152 @@
+use crate::{check_csrf, cookie_value, MockState};
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::routing::post;
+use axum::{Form, Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const PAGE_SIZE: usize = 10;
+
+// 禁言列表中的一条记录, 字段与 GetSilentUserList 一致
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SilentRecord {
+    pub id: u64,
+    pub tuid: u64,
+    pub tname: String,
+    pub uid: u64,
+    pub name: String,
+    pub ctime: String,
+    pub is_anchor: i64,
+    pub hour: i64,
+}
+
+#[derive(Deserialize)]
+struct AddForm {
+    room_id: u64,
+    tuid: u64,
+    hour: i64,
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+struct DelForm {
+    roomid: u64,
+    id: u64,
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+struct ListForm {
+    room_id: u64,
+    ps: usize,
+    csrf: String,
+}
+
+pub(crate) fn routes() -> Router<Arc<MockState>> {
+    Router::new()
+        .route("/xlive/web-ucenter/v1/banned/AddSilentUser", post(add))
+        .route("/xlive/web-ucenter/v1/banned/DelSilentUser", post(del))
+        .route("/xlive/web-ucenter/v1/banned/GetSilentUserList", post(list))
+}
+
+// 未登录, csrf 错误或不是房管时返回错误
+fn check(state: &MockState, headers: &HeaderMap, room_id: u64, csrf: &str) -> Option<Value> {
+    if let Some((code, message)) = check_csrf(headers, csrf) {
+        return Some(reply(code, &message, json!({})));
+    }
+    if state.unmanaged_rooms.lock().unwrap().contains(&room_id) {
+        return Some(reply(-403, "非房管", json!({})));
+    }
+    None
+}
+
+fn reply(code: i64, message: &str, data: Value) -> Value {
+    json!({
+        "code": code,
+        "message": message,
+        "ttl": 1,
+        "data": data,
+    })
+}
+
+async fn add(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Form(form): Form<AddForm>,
+) -> Json<Value> {
+    if let Some(error) = check(&state, &headers, form.room_id, &form.csrf) {
+        return Json(error);
+    }
+    let operator = cookie_value(&headers, "DedeUserID")
+        .and_then(|uid| uid.parse().ok())
+        .unwrap_or_default();
+    let mut silent_users = state.silent_users.lock().unwrap();
+    let next_id = silent_users
+        .values()
+        .flatten()
+        .map(|record| record.id)
+        .max()
+        .unwrap_or_default()
+        + 1;
+    let users = silent_users.entry(form.room_id).or_default();
+    if users.iter().any(|record| record.tuid == form.tuid) {
+        return Json(reply(1, "该用户已被禁言", json!({})));
+    }
+    users.push(SilentRecord {
+        id: next_id,
+        tuid: form.tuid,
+        tname: format!("user-{}", form.tuid),
+        uid: operator,
+        name: format!("user-{}", operator),
+        ctime: "2024-07-04 12:00:00".to_string(),
+        is_anchor: 0,
+        hour: form.hour,
+    });
+    Json(reply(0, "", json!({})))
+}
+
+async fn del(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Form(form): Form<DelForm>,
+) -> Json<Value> {
+    if let Some(error) = check(&state, &headers, form.roomid, &form.csrf) {
+        return Json(error);
+    }
+    let mut silent_users = state.silent_users.lock().unwrap();
+    let users = silent_users.entry(form.roomid).or_default();
+    let len = users.len();
+    users.retain(|record| record.id != form.id);
+    if users.len() == len {
+        return Json(reply(1, "禁言记录不存在", json!({})));
+    }
+    Json(reply(0, "", json!({})))
+}
+
+async fn list(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Form(form): Form<ListForm>,
+) -> Json<Value> {
+    if let Some(error) = check(&state, &headers, form.room_id, &form.csrf) {
+        return Json(error);
+    }
+    let silent_users = state.silent_users.lock().unwrap();
+    let users = silent_users.get(&form.room_id).cloned().unwrap_or_default();
+    let page: Vec<_> = users
+        .iter()
+        .skip(form.ps.saturating_sub(1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .collect();
+    Json(reply(
+        0,
+        "",
+        json!({
+            "data": page,
+            "total": users.len(),
+            "total_page": users.len().div_ceil(PAGE_SIZE),
+        }),
+    ))
+}
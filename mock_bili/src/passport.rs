@@ -0,0 +1,237 @@
+use crate::{cookie_value, MockState};
+use axum::extract::{Path, Query, State};
+use axum::http::header::SET_COOKIE;
+use axum::http::HeaderMap;
+use axum::response::{AppendHeaders, Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const UID: u64 = 10000;
+
+// 每次登录或刷新后 generation 加一, 旧的 cookie 和 refresh_token 随之失效
+#[derive(Default)]
+pub(crate) struct PassportState {
+    qrcode_status: Option<i64>,
+    generation: u32,
+    refresh_needed: bool,
+    refresh_confirmed: bool,
+}
+
+impl PassportState {
+    pub(crate) fn set_qrcode_status(&mut self, code: i64) {
+        self.qrcode_status = Some(code);
+    }
+
+    pub(crate) fn set_refresh_needed(&mut self, refresh_needed: bool) {
+        self.refresh_needed = refresh_needed;
+    }
+
+    pub(crate) fn refresh_confirmed(&self) -> bool {
+        self.refresh_confirmed
+    }
+
+    fn logged_in(&self, headers: &HeaderMap) -> bool {
+        self.generation > 0
+            && cookie_value(headers, "SESSDATA")
+                == Some(format!("mock-sessdata-{}", self.generation))
+    }
+}
+
+#[derive(Deserialize)]
+struct CsrfQuery {
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+struct RefreshForm {
+    csrf: String,
+    refresh_csrf: String,
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+struct ConfirmForm {
+    csrf: String,
+    refresh_token: String,
+}
+
+pub(crate) fn routes() -> Router<Arc<MockState>> {
+    Router::new()
+        .route("/x/passport-login/web/qrcode/generate", get(generate))
+        .route("/x/passport-login/web/qrcode/poll", get(poll))
+        .route("/x/frontend/finger/spi", get(spi))
+        .route("/x/passport-login/web/cookie/info", get(cookie_info))
+        .route("/correspond/1/:path", get(correspond))
+        .route("/x/passport-login/web/cookie/refresh", post(refresh))
+        .route(
+            "/x/passport-login/web/confirm/refresh",
+            post(confirm_refresh),
+        )
+}
+
+fn reply(code: i64, message: &str, data: Value) -> Json<Value> {
+    Json(json!({
+        "code": code,
+        "message": message,
+        "ttl": 1,
+        "data": data,
+    }))
+}
+
+fn set_cookies(generation: u32) -> AppendHeaders<[(axum::http::HeaderName, String); 3]> {
+    AppendHeaders([
+        (
+            SET_COOKIE,
+            format!("SESSDATA=mock-sessdata-{}; Path=/; HttpOnly", generation),
+        ),
+        (
+            SET_COOKIE,
+            format!("bili_jct=mock-csrf-{}; Path=/", generation),
+        ),
+        (SET_COOKIE, format!("DedeUserID={}; Path=/", UID)),
+    ])
+}
+
+async fn generate() -> Json<Value> {
+    reply(
+        0,
+        "0",
+        json!({
+            "url": "https://account.bilibili.com/h5/account-h5/auth/scan-web?qrcode_key=mock-qrcode-key",
+            "qrcode_key": "mock-qrcode-key",
+        }),
+    )
+}
+
+async fn poll(State(state): State<Arc<MockState>>) -> Response {
+    let mut passport = state.passport.lock().unwrap();
+    let code = passport.qrcode_status.unwrap_or(86101);
+    if code != 0 {
+        return reply(
+            0,
+            "0",
+            json!({"url": "", "refresh_token": "", "timestamp": 0, "code": code, "message": ""}),
+        )
+        .into_response();
+    }
+    passport.generation += 1;
+    let generation = passport.generation;
+    (
+        set_cookies(generation),
+        reply(
+            0,
+            "0",
+            json!({
+                "url": "https://passport.biligame.com/crossDomain",
+                "refresh_token": format!("mock-refresh-{}", generation),
+                "timestamp": 1720068325513_i64,
+                "code": 0,
+                "message": "",
+            }),
+        ),
+    )
+        .into_response()
+}
+
+async fn spi() -> Json<Value> {
+    reply(0, "ok", json!({"b_3": "mock-buvid3", "b_4": "mock-buvid4"}))
+}
+
+async fn cookie_info(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Query(query): Query<CsrfQuery>,
+) -> Json<Value> {
+    let passport = state.passport.lock().unwrap();
+    if !passport.logged_in(&headers) {
+        return reply(-101, "账号未登录", json!({}));
+    }
+    if cookie_value(&headers, "bili_jct").as_deref() != Some(query.csrf.as_str()) {
+        return reply(-111, "csrf 校验失败", json!({}));
+    }
+    reply(
+        0,
+        "0",
+        json!({"refresh": passport.refresh_needed, "timestamp": 1720068325513_i64}),
+    )
+}
+
+// 无法解密 correspondPath, 只检查长度和格式
+async fn correspond(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Path(path): Path<String>,
+) -> Html<String> {
+    let passport = state.passport.lock().unwrap();
+    if !passport.logged_in(&headers)
+        || path.len() != 256
+        || !path.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Html("<html><body>404</body></html>".to_string());
+    }
+    Html(format!(
+        r#"<html><body><div id="1-name">mock-refresh-csrf-{}</div></body></html>"#,
+        passport.generation
+    ))
+}
+
+async fn refresh(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Form(form): Form<RefreshForm>,
+) -> Response {
+    let mut passport = state.passport.lock().unwrap();
+    let generation = passport.generation;
+    if !passport.logged_in(&headers) {
+        return reply(-101, "账号未登录", json!({})).into_response();
+    }
+    if form.csrf != format!("mock-csrf-{}", generation) {
+        return reply(-111, "csrf 校验失败", json!({})).into_response();
+    }
+    if form.refresh_csrf != format!("mock-refresh-csrf-{}", generation) {
+        return reply(86000, "refresh_csrf 错误", json!({})).into_response();
+    }
+    if form.refresh_token != format!("mock-refresh-{}", generation) {
+        return reply(86095, "refresh_token 错误", json!({})).into_response();
+    }
+    passport.generation += 1;
+    passport.refresh_needed = false;
+    passport.refresh_confirmed = false;
+    let generation = passport.generation;
+    (
+        set_cookies(generation),
+        reply(
+            0,
+            "0",
+            json!({
+                "status": 0,
+                "message": "",
+                "refresh_token": format!("mock-refresh-{}", generation),
+            }),
+        ),
+    )
+        .into_response()
+}
+
+async fn confirm_refresh(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Form(form): Form<ConfirmForm>,
+) -> Json<Value> {
+    let mut passport = state.passport.lock().unwrap();
+    let generation = passport.generation;
+    if !passport.logged_in(&headers) {
+        return reply(-101, "账号未登录", json!({}));
+    }
+    if form.csrf != format!("mock-csrf-{}", generation) {
+        return reply(-111, "csrf 校验失败", json!({}));
+    }
+    if form.refresh_token != format!("mock-refresh-{}", generation - 1) {
+        return reply(-400, "请求错误", json!({}));
+    }
+    passport.refresh_confirmed = true;
+    reply(0, "0", json!(null))
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use log::error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyKind {
+    Http,
+    Socks5,
+}
+
+// 测试用代理, 记录经过代理的目标地址
+pub struct MockProxy {
+    addr: SocketAddr,
+    kind: ProxyKind,
+    targets: Arc<Mutex<Vec<String>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockProxy {
+    // socks5 代理, auth 不为 None 时要求用户名密码认证
+    pub async fn socks5(auth: Option<(&str, &str)>) -> Result<Self> {
+        let auth = auth.map(|(user, pass)| (user.to_string(), pass.to_string()));
+        Self::start(ProxyKind::Socks5, auth).await
+    }
+
+    pub async fn http() -> Result<Self> {
+        Self::start(ProxyKind::Http, None).await
+    }
+
+    async fn start(kind: ProxyKind, auth: Option<(String, String)>) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let task_targets = targets.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                let targets = task_targets.clone();
+                let auth = auth.clone();
+                tokio::spawn(async move {
+                    let result = match kind {
+                        ProxyKind::Socks5 => serve_socks5(stream, auth, targets).await,
+                        ProxyKind::Http => serve_http(stream, targets).await,
+                    };
+                    if let Err(e) = result {
+                        error!("mock proxy error: {:?}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            addr,
+            kind,
+            targets,
+            task,
+        })
+    }
+
+    // 不含认证信息
+    pub fn url(&self) -> String {
+        match self.kind {
+            ProxyKind::Http => format!("http://{}", self.addr),
+            ProxyKind::Socks5 => format!("socks5://{}", self.addr),
+        }
+    }
+
+    // 经过代理的目标地址 host:port, 按连接顺序
+    pub fn targets(&self) -> Vec<String> {
+        self.targets.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve_socks5(
+    mut stream: TcpStream,
+    auth: Option<(String, String)>,
+    targets: Arc<Mutex<Vec<String>>>,
+) -> Result<()> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    match &auth {
+        Some((user, pass)) => {
+            if !methods.contains(&0x02) {
+                stream.write_all(&[0x05, 0xff]).await?;
+                return Err(anyhow!("client does not support password auth"));
+            }
+            stream.write_all(&[0x05, 0x02]).await?;
+            let _version = stream.read_u8().await?;
+            let mut got_user = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut got_user).await?;
+            let mut got_pass = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut got_pass).await?;
+            if got_user != user.as_bytes() || got_pass != pass.as_bytes() {
+                stream.write_all(&[0x01, 0x01]).await?;
+                return Err(anyhow!("socks5 auth failed"));
+            }
+            stream.write_all(&[0x01, 0x00]).await?;
+        }
+        None => stream.write_all(&[0x05, 0x00]).await?,
+    }
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        0x01 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        0x03 => {
+            let mut host = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut host).await?;
+            String::from_utf8(host)?
+        }
+        atyp => return Err(anyhow!("unsupported address type {}", atyp)),
+    };
+    let port = stream.read_u16().await?;
+    let target = format!("{}:{}", host, port);
+    let mut upstream = match TcpStream::connect(&target).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            stream
+                .write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await?;
+            return Err(e.into());
+        }
+    };
+    targets.lock().unwrap().push(target);
+    stream
+        .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0])
+        .await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
+
+async fn serve_http(mut stream: TcpStream, targets: Arc<Mutex<Vec<String>>>) -> Result<()> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8(head)?;
+    let (request_line, rest) = head.split_once("\r\n").unwrap_or((&head, ""));
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    let [method, target, version] = parts[..] else {
+        return Err(anyhow!("invalid request: {}", request_line));
+    };
+    if method == "CONNECT" {
+        let mut upstream = TcpStream::connect(target).await?;
+        targets.lock().unwrap().push(target.to_string());
+        stream
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+        return Ok(());
+    }
+    // 普通代理请求, 目标为 http://host:port/path
+    let without_scheme = target
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("unsupported target: {}", target))?;
+    let (authority, path) = match without_scheme.find('/') {
+        Some(index) => without_scheme.split_at(index),
+        None => (without_scheme, "/"),
+    };
+    let mut upstream = TcpStream::connect(authority).await?;
+    targets.lock().unwrap().push(authority.to_string());
+    upstream
+        .write_all(format!("{} {} {}\r\n{}", method, path, version, rest).as_bytes())
+        .await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use std::io::Write;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum Step {
+    Packet(Vec<u8>), // 原样发送的数据包
+    Sleep(Duration),
+    Close, // 服务器主动断开连接
+}
+
+// 认证成功后服务器依次执行的动作, 执行完且没有 Close 时保持连接
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub steps: Vec<Step>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 多条消息打包成一个 brotli 压缩包 (protocol 3), 与线上一致
+    pub fn messages(mut self, bodies: &[String]) -> Self {
+        self.steps.push(Step::Packet(build_brotli_packet(bodies)));
+        self
+    }
+
+    // 单条未压缩消息 (protocol 0)
+    pub fn command(mut self, body: &str) -> Self {
+        self.steps
+            .push(Step::Packet(parse::build_packet(0, 5, body.as_bytes())));
+        self
+    }
+
+    pub fn packet(mut self, packet: Vec<u8>) -> Self {
+        self.steps.push(Step::Packet(packet));
+        self
+    }
+
+    pub fn sleep(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Sleep(duration));
+        self
+    }
+
+    pub fn close(mut self) -> Self {
+        self.steps.push(Step::Close);
+        self
+    }
+
+    // 从抓包数据回放: 认证回复之后服务器发出的原始字节流, 按 header 中的 total_size 切分
+    pub fn from_capture(data: &[u8]) -> Result<Self> {
+        let mut session = Session::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            if offset + 16 > data.len() {
+                return Err(anyhow!("Incomplete header at offset {}", offset));
+            }
+            let header = parse::parse_header(&data[offset..offset + 16]);
+            let end = offset + header.total_size as usize;
+            if header.total_size < 16 || end > data.len() {
+                return Err(anyhow!("Incomplete packet at offset {}", offset));
+            }
+            session = session.packet(data[offset..end].to_vec());
+            offset = end;
+        }
+        Ok(session)
+    }
+}
+
+pub fn build_brotli_packet(bodies: &[String]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for body in bodies {
+        raw.extend_from_slice(&parse::build_packet(0, 5, body.as_bytes()));
+    }
+    let mut compressed = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+        writer.write_all(&raw).expect("brotli compress to vec");
+    }
+    parse::build_packet(3, 5, &compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message;
+
+    #[test]
+    fn test_brotli_packet_round_trip() {
+        let bodies = vec![
+            message::danmu(10000, "Alice", "hello", 1720068325513),
+            message::online_count(42),
+        ];
+        let packet = build_brotli_packet(&bodies);
+        let header = parse::parse_header(&packet);
+        assert_eq!(header.protocol, 3);
+        let messages = parse::parse_message(header, &packet).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0], parse::Message::Danmu(_)));
+        assert!(matches!(messages[1], parse::Message::OnlineCount(_)));
+    }
+
+    #[test]
+    fn test_from_capture() {
+        let mut capture = build_brotli_packet(&[message::danmu(1, "a", "b", 0)]);
+        capture.extend_from_slice(&parse::build_packet(1, 3, &[0, 0, 0, 1]));
+        let session = Session::from_capture(&capture).unwrap();
+        assert_eq!(session.steps.len(), 2);
+
+        capture.truncate(capture.len() - 1);
+        assert!(Session::from_capture(&capture).is_err());
+    }
+}
@@ -16,12 +16,13 @@ pub enum StatisticsScope {
 }
 
 impl StatisticsScope {
-    pub fn remote_table_name(self, bucket: &str, room_id: i64, timestamp: i64) -> String {
+    // root 为 StorageLocation::root()
+    pub fn remote_table_name(self, root: &str, room_id: i64, timestamp: i64) -> String {
         match self {
             StatisticsScope::Day => {
                 format!(
-                    "s3://{}/statistics/{}/{}_{}.parquet",
-                    bucket,
+                    "{}/statistics/{}/{}_{}.parquet",
+                    root,
                     room_id,
                     "day",
                     get_format_date(timestamp).unwrap(),
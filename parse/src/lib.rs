@@ -6,37 +6,44 @@ use serde_json::Value;
 use std::io::Read;
 use std::str;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DanmuMessage {
     pub uid: u64,
     pub username: String,
     pub msg: String,
     pub timestamp: u64,
+    // 弹幕的 id_str, 旧数据中为空
+    #[serde(default)]
+    pub id: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnterRoomMessage {
     pub uid: u64,
     pub username: String,
     pub timestamp: u64,
+    #[serde(default)]
+    pub id: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OnlineCountMessage {
     pub count: u64,
     pub timestamp: u64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SuperChatMessage {
     pub uid: u64,
     pub username: String,
     pub msg: String,
     pub timestamp: u64,
     pub worth: f64,
+    #[serde(default)]
+    pub id: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockUserMessage {
     pub uid: u64,
     pub username: String,
@@ -46,7 +53,7 @@ pub struct BlockUserMessage {
     pub block_expired: i64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BlockUserEnum {
     Owner,   // 主播
     Manager, // 房管
@@ -73,7 +80,7 @@ impl From<BlockUserEnum> for i16 {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     Danmu(DanmuMessage),
     EnterRoom(EnterRoomMessage),
@@ -83,6 +90,53 @@ pub enum Message {
     Default,
 }
 
+impl Message {
+    // 用于去重的消息 id, 重连或多个 crawler 收到的同一条消息 id 相同
+    // 没有 id 的消息 (旧的 spool 记录) 使用内容哈希
+    pub fn stable_id(&self) -> Option<String> {
+        let (id, uid, timestamp, text) = match self {
+            Message::Danmu(msg) => (&msg.id, msg.uid, msg.timestamp, msg.msg.as_str()),
+            Message::SuperChat(msg) => (&msg.id, msg.uid, msg.timestamp, msg.msg.as_str()),
+            Message::EnterRoom(msg) => (&msg.id, msg.uid, msg.timestamp, ""),
+            _ => return None,
+        };
+        if !id.is_empty() {
+            return Some(id.clone());
+        }
+        Some(fallback_id(uid, timestamp, text))
+    }
+
+    // 消息自身的时间 (秒), 用于按日期分区
+    pub fn timestamp(&self) -> Option<i64> {
+        match self {
+            Message::Danmu(msg) => Some(msg.timestamp as i64),
+            Message::SuperChat(msg) => Some(msg.timestamp as i64),
+            Message::EnterRoom(msg) => Some(msg.timestamp as i64),
+            Message::BlockUser(msg) => Some(msg.timestamp),
+            Message::OnlineCount(msg) => Some(msg.timestamp as i64 / 1000),
+            Message::Default => None,
+        }
+    }
+}
+
+// 没有平台 id 时的消息 id, 解析时和 stable_id 使用相同的秒级时间戳,
+// 同一用户在同一秒内发送的相同内容视为同一条消息
+fn fallback_id(uid: u64, timestamp: u64, text: &str) -> String {
+    content_id(&[&uid.to_string(), &timestamp.to_string(), text])
+}
+
+// FNV-1a, 不随 Rust 版本变化, 可以持久化
+pub fn content_id(parts: &[&str]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.bytes().chain([0xff]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("h-{:016x}", hash)
+}
+
 impl TryFrom<&[u8]> for Message {
     type Error = anyhow::Error;
 
@@ -116,8 +170,10 @@ impl TryFrom<&[u8]> for Message {
     }
 }
 
-fn parse_brotli_packet(_header: Header, packet: &[u8]) -> Result<Vec<Message>> {
+// 返回解析成功的消息和解析失败的消息数
+fn parse_brotli_packet(_header: Header, packet: &[u8]) -> Result<(Vec<Message>, usize)> {
     let mut result = Vec::new();
+    let mut errors = 0;
     let packet = brotli_decode(&packet[16..])?;
 
     let mut offset = 0;
@@ -139,6 +195,7 @@ fn parse_brotli_packet(_header: Header, packet: &[u8]) -> Result<Vec<Message>> {
         match Message::try_from(body) {
             Ok(message) => result.push(message),
             Err(e) => {
+                errors += 1;
                 let mut body_hex_str = String::from("");
                 body_hex_str.extend(body.iter().map(|b| format!("{:02X}", b)));
                 error!(
@@ -150,7 +207,7 @@ fn parse_brotli_packet(_header: Header, packet: &[u8]) -> Result<Vec<Message>> {
             }
         }
     }
-    Ok(result)
+    Ok((result, errors))
 }
 
 fn parse_command_packet(packet: &[u8]) -> Result<Message> {
@@ -158,18 +215,17 @@ fn parse_command_packet(packet: &[u8]) -> Result<Message> {
 }
 
 pub fn parse_message(header: Header, origin_data: &[u8]) -> Result<Vec<Message>> {
-    // debug!("{:?}", header);
-    // for i in 0..origin_data.len() {
-    //     print!("{:02X}", origin_data[i])
-    // }
-    // println!();
+    Ok(parse_packet(header, origin_data)?.0)
+}
 
+// 同 parse_message, 另外返回压缩包中解析失败而被跳过的消息数
+pub fn parse_packet(header: Header, origin_data: &[u8]) -> Result<(Vec<Message>, usize)> {
     // 3 is heartbeat packet
     if header.msg_type == 3 {
-        return Ok(vec![]);
+        return Ok((vec![], 0));
     }
     match header.protocol {
-        1 | 0 => Ok(vec![parse_command_packet(origin_data)?]),
+        1 | 0 => Ok((vec![parse_command_packet(origin_data)?], 0)),
         3 => parse_brotli_packet(header, origin_data),
         _ => Err(anyhow!("Unsupported protocol")),
     }
@@ -209,7 +265,7 @@ pub fn parse_header(data: &[u8]) -> Header {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Certificate {
     pub uid: u64, // 此处 UID 或许可以填 0, B 站游客可看到弹幕流
     pub roomid: u64,
@@ -220,6 +276,22 @@ pub struct Certificate {
     pub key: String, // 从 https://api.live.bilibili.com/xlive/web-room/v1/index/getDanmuInfo?id={room_id}&type=0 获取
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthReply {
+    pub code: i64, // 0 为认证成功
+}
+
+// 认证回复: msg_type 为 8, body 为 {"code":0}
+pub fn parse_auth_reply(header: &Header, packet: &[u8]) -> Result<AuthReply> {
+    if header.msg_type != 8 {
+        return Err(anyhow!("Not an auth reply, msg_type: {}", header.msg_type));
+    }
+    let body = packet
+        .get(header.head_size..header.total_size as usize)
+        .ok_or(anyhow!("Incomplete auth reply"))?;
+    Ok(serde_json::from_slice::<AuthReply>(body)?)
+}
+
 pub fn build_auth_packet(certificate: &Certificate) -> Vec<u8> {
     let body = serde_json::to_vec(certificate).unwrap();
     build_packet(1, 7, &body)
@@ -294,15 +366,23 @@ impl BiliMessage {
             .as_str()
             .ok_or(anyhow!("Failed to get username"))?;
         let msg = danmu[1].as_str().ok_or(anyhow!("Failed to get msg"))?;
-        let timestamp = danmu[0][4]
+        let timestamp_ms = danmu[0][4]
             .as_u64()
-            .ok_or(anyhow!("Failed to get timestamp"))?
-            / 1000;
+            .ok_or(anyhow!("Failed to get timestamp"))?;
+        let timestamp = timestamp_ms / 1000;
+        // info[0][15].extra 中的 id_str, 没有时用 uid, 时间戳和内容的哈希
+        let id = danmu[0][15]["extra"]
+            .as_str()
+            .and_then(|extra| serde_json::from_str::<Value>(extra).ok())
+            .and_then(|extra| extra["id_str"].as_str().map(str::to_string))
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| fallback_id(uid, timestamp, msg));
         Ok(Message::Danmu(DanmuMessage {
             uid,
             username: username.to_string(),
             msg: msg.to_string(),
             timestamp,
+            id,
         }))
     }
 
@@ -314,7 +394,9 @@ impl BiliMessage {
         let user_info = data.uinfo.ok_or(anyhow!("Failed to get uinfo"))?;
         let timestamp = data.timestamp.ok_or(anyhow!("Failed to get timestamp"))?;
 
+        // 进房消息没有平台 id, 同一用户在同一秒内的多次进房只保留一条
         Ok(Message::EnterRoom(EnterRoomMessage {
+            id: fallback_id(user_info.uid, timestamp, ""),
             uid: user_info.uid,
             username: user_info.base.name,
             timestamp,
@@ -340,12 +422,19 @@ impl BiliMessage {
         }
         let data = self.data.ok_or(anyhow!("Failed to get data"))?;
         let user_info = data.uinfo.ok_or(anyhow!("Failed to get uinfo"))?;
+        let msg = data.message.ok_or(anyhow!("Failed to get data"))?;
+        let timestamp = self.send_time.ok_or(anyhow!("Failed to get send_time"))? / 1000;
+        let id = match data.id {
+            Some(id) => format!("sc-{}", id),
+            None => fallback_id(user_info.uid, timestamp, &msg),
+        };
         Ok(Message::SuperChat(SuperChatMessage {
             uid: user_info.uid,
             username: user_info.base.name,
-            msg: data.message.ok_or(anyhow!("Failed to get data"))?,
-            timestamp: self.send_time.ok_or(anyhow!("Failed to get send_time"))? / 1000,
+            msg,
+            timestamp,
             worth: data.price.ok_or(anyhow!("Failed to get worth"))?,
+            id,
         }))
     }
 
@@ -382,6 +471,24 @@ mod test {
         println!("{:?}", message);
     }
 
+    #[test]
+    fn test_parse_auth_reply() {
+        let packet = build_packet(1, 8, br#"{"code":0}"#);
+        let header = parse_header(&packet);
+        assert_eq!(
+            parse_auth_reply(&header, &packet).unwrap(),
+            AuthReply { code: 0 }
+        );
+
+        let packet = build_packet(1, 8, br#"{"code":-101}"#);
+        let header = parse_header(&packet);
+        assert_eq!(parse_auth_reply(&header, &packet).unwrap().code, -101);
+
+        let packet = build_packet(1, 3, &[0, 0, 0, 1]);
+        let header = parse_header(&packet);
+        assert!(parse_auth_reply(&header, &packet).is_err());
+    }
+
     #[test]
     fn test_parse_block_user_message() {
         let data = r#"{"cmd":"ROOM_BLOCK_MSG","data":{"block_expired":2145888000,"dmscore":45,"operator":1,"uid":497782110,"uname":"GGreay"},"uid":497782110,"uname":"GGreay"}"#;
@@ -390,4 +497,59 @@ mod test {
             Err(e) => panic!("{:?}", e),
         };
     }
+
+    #[test]
+    fn test_message_stable_id() {
+        let extra = r#"{\"id_str\":\"5f1b2c3d4e\",\"mode\":0}"#;
+        let data = format!(
+            r#"{{"cmd":"DANMU_MSG","info":[[0,1,25,16777215,1720068325513,0,0,"",0,0,0,"",0,{{}},{{}},{{"extra":"{extra}"}}],"hello",[10000,"Alice"]]}}"#
+        );
+        let message = Message::try_from(data.as_bytes()).unwrap();
+        assert_eq!(message.stable_id().unwrap(), "5f1b2c3d4e");
+
+        // 没有 id_str 时同一条弹幕的哈希相同
+        let data = r#"{"cmd":"DANMU_MSG","info":[[0,1,25,16777215,1720068325513],"hello",[10000,"Alice"]]}"#;
+        let first = Message::try_from(data.as_bytes()).unwrap().stable_id();
+        let second = Message::try_from(data.as_bytes()).unwrap().stable_id();
+        assert_eq!(first, second);
+        assert!(first.unwrap().starts_with("h-"));
+        let other = r#"{"cmd":"DANMU_MSG","info":[[0,1,25,16777215,1720068326513],"hello",[10000,"Alice"]]}"#;
+        assert_ne!(
+            Message::try_from(other.as_bytes()).unwrap().stable_id(),
+            second
+        );
+
+        let data = r#"{"cmd":"SUPER_CHAT_MESSAGE","data":{"id":10007772,"message":"hi","price":30,"uinfo":{"uid":1,"base":{"name":"a"}}},"send_time":1720068325513}"#;
+        let message = Message::try_from(data.as_bytes()).unwrap();
+        assert_eq!(message.stable_id().unwrap(), "sc-10007772");
+
+        // 旧的 spool 记录没有 id 字段
+        let message: Message = serde_json::from_str(
+            r#"{"Danmu":{"uid":10000,"username":"Alice","msg":"hello","timestamp":1720068325}}"#,
+        )
+        .unwrap();
+        assert!(message.stable_id().unwrap().starts_with("h-"));
+        assert_eq!(content_id(&["ab", "c"]), content_id(&["ab", "c"]));
+        assert_ne!(content_id(&["ab", "c"]), content_id(&["a", "bc"]));
+        assert!(Message::Default.stable_id().is_none());
+    }
+
+    #[test]
+    fn test_fallback_id_round_trip() {
+        // 没有平台 id 的消息, 解析时的 id 和去掉 id 后由 stable_id 重新计算的相同
+        let data = [
+            r#"{"cmd":"DANMU_MSG","info":[[0,1,25,16777215,1720068325513],"hello",[10000,"Alice"]]}"#,
+            r#"{"cmd":"SUPER_CHAT_MESSAGE","data":{"message":"hi","price":30,"uinfo":{"uid":1,"base":{"name":"a"}}},"send_time":1720068325513}"#,
+            r#"{"cmd":"INTERACT_WORD","data":{"timestamp":1720068325,"uinfo":{"uid":1,"base":{"name":"a"}}}}"#,
+        ];
+        for data in data {
+            let parsed = Message::try_from(data.as_bytes()).unwrap();
+            let id = parsed.stable_id().unwrap();
+            let mut value = serde_json::to_value(&parsed).unwrap();
+            let (_, fields) = value.as_object_mut().unwrap().iter_mut().next().unwrap();
+            assert_eq!(fields.as_object_mut().unwrap().remove("id").unwrap(), id);
+            let restored: Message = serde_json::from_value(value).unwrap();
+            assert_eq!(restored.stable_id().unwrap(), id);
+        }
+    }
 }
@@ -1,32 +1,35 @@
 use anyhow::Result;
-use duckdb::DuckdbConnectionManager;
+use duckdb::types::Value;
+use duckdb::{params, params_from_iter, Connection, DuckdbConnectionManager, Rows};
 use model::statistics;
-use parse::{BlockUserMessage, DanmuMessage, Message, SuperChatMessage};
+use parse::{BlockUserMessage, DanmuMessage, EnterRoomMessage, Message, SuperChatMessage};
 use r2d2::Pool;
+use utils::location::StorageLocation;
 use utils::utils::{
-    get_every_day_with_start_end, get_local_midnight, get_table_name, init_oss_with_pool,
-    remote_block_user_table_name, MessageType, OssConfig, Pagination,
+    dedup_source, get_enter_table_name, get_every_day_with_start_end, get_gap_table_name,
+    get_local_midnight, get_table_glob, get_table_name, remote_block_user_table_name, Gap,
+    MessageType, Pagination,
 };
 
 #[derive(Clone)]
 pub struct Queryer {
     pool: Pool<DuckdbConnectionManager>,
-    bucket: String,
+    root: String,
 }
 
 impl Queryer {
     pub fn new(pool: Pool<DuckdbConnectionManager>) -> Result<Self> {
-        let oss_config = OssConfig::new()?;
-        init_oss_with_pool(
-            &pool.get()?,
-            oss_config.endpoint.as_str(),
-            oss_config.region.as_str(),
-            oss_config.key.as_str(),
-            oss_config.secret.as_str(),
-        )?;
+        Self::with_location(pool, StorageLocation::from_env()?)
+    }
+
+    pub fn with_location(
+        pool: Pool<DuckdbConnectionManager>,
+        location: StorageLocation,
+    ) -> Result<Self> {
+        location.init_conn(&*pool.get()?)?;
         Ok(Self {
             pool,
-            bucket: oss_config.bucket,
+            root: location.root(),
         })
     }
 
@@ -42,7 +45,8 @@ impl Queryer {
         pagination: Option<Pagination>,
     ) -> Result<Vec<Message>> {
         let conn = self.pool.get()?;
-        let mut query_stmt = conn.prepare(&self.build_stmt(
+        let (stmt, values) = self.build_stmt(
+            &conn,
             "*",
             room_id,
             timestamp,
@@ -51,10 +55,11 @@ impl Queryer {
             username,
             message,
             pagination,
-            Some("timestamp DESC".to_string()),
-        )?)?;
+            Some("timestamp DESC"),
+        )?;
+        let mut query_stmt = conn.prepare(&stmt)?;
 
-        let mut rows = query_stmt.query([])?;
+        let mut rows = query_stmt.query(params_from_iter(values))?;
 
         let mut result = Vec::new();
 
@@ -65,12 +70,19 @@ impl Queryer {
             let msg: String = row.get("msg")?;
             let timestamp: u64 = row.get("timestamp")?;
             let worth: f64 = row.get("worth")?;
+            // 旧数据没有 id 列
+            let id: String = row
+                .get::<_, Option<String>>("id")
+                .ok()
+                .flatten()
+                .unwrap_or_default();
             let message = match message_type {
                 MessageType::Danmu => Message::Danmu(DanmuMessage {
                     uid,
                     username,
                     msg,
                     timestamp,
+                    id,
                 }),
                 MessageType::SuperChat => Message::SuperChat(SuperChatMessage {
                     uid,
@@ -78,6 +90,7 @@ impl Queryer {
                     msg,
                     timestamp,
                     worth,
+                    id,
                 }),
             };
             result.push(message);
@@ -96,7 +109,8 @@ impl Queryer {
         message: Option<String>,
     ) -> Result<usize> {
         let conn = self.pool.get()?;
-        let mut query_stmt = conn.prepare(&self.build_stmt(
+        let (stmt, values) = self.build_stmt(
+            &conn,
             "count(*)",
             room_id,
             timestamp,
@@ -106,16 +120,19 @@ impl Queryer {
             message,
             None,
             None,
-        )?)?;
+        )?;
+        let mut query_stmt = conn.prepare(&stmt)?;
 
-        let mut rows = query_stmt.query([])?;
+        let mut rows = query_stmt.query(params_from_iter(values))?;
         let count: usize = rows.next()?.unwrap().get(0)?;
         Ok(count)
     }
 
+    // 返回语句和按顺序绑定的参数
     #[allow(clippy::too_many_arguments)]
     fn build_stmt(
         &self,
+        conn: &Connection,
         col: &str,
         room_id: i64,
         timestamp: i64,
@@ -124,46 +141,51 @@ impl Queryer {
         username: Option<String>,
         message: Option<String>,
         pagination: Option<Pagination>,
-        order: Option<String>,
-    ) -> Result<String> {
-        let mut contidition = Vec::new();
-        if let Some(username) = username {
-            contidition.push(format!("username = '{}'", username));
-        }
-        if let Some(message) = message {
-            contidition.push(format!("msg LIKE '%{}%'", message));
-        }
-        if let Some(message_type) = message_type {
-            contidition.push(format!("msg_type = '{}'", i8::from(message_type)));
-        }
-        if let Some(uid) = uid {
-            contidition.push(format!("uid = {}", uid));
-        }
-        let where_clause = if contidition.is_empty() {
-            String::from("")
-        } else {
-            format!("WHERE {}", contidition.join(" AND "))
-        };
-        let table = get_table_name(&self.bucket, room_id, timestamp)?;
-
-        let pagination_clause = match pagination {
-            None => String::from(""),
-            Some(pagination) => {
-                format!("LIMIT {} OFFSET {}", pagination.limit, pagination.offset)
-            }
-        };
-        let order_clause = match order {
-            None => String::from(""),
-            Some(order) => {
-                format!("ORDER BY {}", order)
-            }
-        };
-        Ok(format!(
-            "SELECT {} FROM '{}' {} {} {}",
-            col, table, where_clause, order_clause, pagination_clause
+        order: Option<&str>,
+    ) -> Result<(String, Vec<Value>)> {
+        let table = get_table_glob(&get_table_name(&self.root, room_id, timestamp)?);
+        let source = dedup_source(conn, &format!("'{table}'"))?;
+        Ok(select_stmt(
+            col,
+            &source,
+            message_type,
+            uid,
+            username,
+            message,
+            pagination,
+            order,
         ))
     }
 
+    // timestamp 当天 crawler 记录的断线时段, 这些时段内的消息不完整
+    pub fn query_gaps(&self, room_id: i64, timestamp: i64) -> Result<Vec<Gap>> {
+        let table = get_table_glob(&get_gap_table_name(&self.root, room_id, timestamp)?);
+        let conn = self.pool.get()?;
+        let files: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM glob('{table}')"),
+            [],
+            |row| row.get(0),
+        )?;
+        // 当天没有断线
+        if files == 0 {
+            return Ok(vec![]);
+        }
+        let source = dedup_source(&conn, &format!("'{table}'"))?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT timestamp, end_timestamp, reason FROM {source} ORDER BY timestamp"
+        ))?;
+        let gaps = stmt
+            .query_map([], |row| {
+                Ok(Gap {
+                    start: row.get(0)?,
+                    end: row.get(1)?,
+                    reason: row.get(2)?,
+                })
+            })?
+            .collect::<duckdb::Result<Vec<_>>>()?;
+        Ok(gaps)
+    }
+
     pub fn query_statistics_data(
         &self,
         room_id: i64,
@@ -171,7 +193,7 @@ impl Queryer {
     ) -> Result<statistics::StatisticsResult> {
         let timestamp = get_local_midnight(timestamp)?;
         let table =
-            statistics::StatisticsScope::Day.remote_table_name(&self.bucket, room_id, timestamp);
+            statistics::StatisticsScope::Day.remote_table_name(&self.root, room_id, timestamp);
         let conn = self.pool.get()?;
         let result = conn.query_row(
             &format!("SELECT * FROM '{}' WHERE timestamp = ?", table),
@@ -204,8 +226,52 @@ impl Queryer {
         Ok(result)
     }
 
+    // 某个用户在房间 start 到 end 之间的进房记录, 按时间倒序
+    // 未开启进房记录或被抽样丢弃的日期没有数据
+    pub fn query_enter_room_by_uid(
+        &self,
+        room_id: i64,
+        uid: u64,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<EnterRoomMessage>> {
+        let conn = self.pool.get()?;
+        let mut result = vec![];
+        for day in get_every_day_with_start_end(start, end)? {
+            let table = get_table_glob(&get_enter_table_name(&self.root, room_id, day)?);
+            let files: i64 = conn.query_row(
+                &format!("SELECT COUNT(*) FROM glob('{table}')"),
+                [],
+                |row| row.get(0),
+            )?;
+            // 当天没有进房记录文件
+            if files == 0 {
+                continue;
+            }
+            let source = dedup_source(&conn, &format!("'{table}'"))?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT * FROM {source} WHERE uid = ? AND timestamp BETWEEN ? AND ?"
+            ))?;
+            let mut rows = stmt.query(params![uid, start, end])?;
+            while let Some(row) = rows.next()? {
+                result.push(EnterRoomMessage {
+                    uid: row.get("uid")?,
+                    username: row.get("username")?,
+                    timestamp: row.get("timestamp")?,
+                    id: row
+                        .get::<_, Option<String>>("id")
+                        .ok()
+                        .flatten()
+                        .unwrap_or_default(),
+                });
+            }
+        }
+        result.sort_by_key(|b| std::cmp::Reverse(b.timestamp));
+        Ok(result)
+    }
+
     pub fn query_block_user_count(&self) -> Result<usize> {
-        let remote_table = remote_block_user_table_name(self.bucket.as_str());
+        let remote_table = remote_block_user_table_name(self.root.as_str());
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare(format!("SELECT COUNT(*) FROM '{remote_table}'").as_str())?;
         let mut rows = stmt.query([])?;
@@ -217,91 +283,254 @@ impl Queryer {
         &self,
         pagination: Option<Pagination>,
     ) -> Result<Vec<BlockUserMessage>> {
-        let pagination_clause = match pagination {
-            None => String::from(""),
-            Some(pagination) => {
-                format!("LIMIT {} OFFSET {}", pagination.limit, pagination.offset)
-            }
-        };
-        let remote_table = remote_block_user_table_name(self.bucket.as_str());
+        let remote_table = remote_block_user_table_name(self.root.as_str());
+        let (pagination_clause, values) = pagination_clause(pagination);
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT * FROM '{remote_table}' ORDER BY timestamp DESC {pagination_clause}"
+        ))?;
+        let rows = stmt.query(params_from_iter(values))?;
+        block_user_rows(rows)
+    }
+
+    // 某个用户在所有房间的禁言记录
+    pub fn query_block_user_by_uid(&self, uid: u64) -> Result<Vec<BlockUserMessage>> {
+        let remote_table = remote_block_user_table_name(self.root.as_str());
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare(
-            format!(
-                "SELECT * FROM '{}' ORDER BY timestamp DESC {}",
-                remote_table, pagination_clause
-            )
-            .as_str(),
+            format!("SELECT * FROM '{remote_table}' WHERE uid = ? ORDER BY timestamp DESC")
+                .as_str(),
         )?;
-        let mut rows = stmt.query([])?;
-        let mut result = vec![];
-        while let Some(row) = rows.next()? {
-            let uid: u64 = row.get("uid")?;
-            let username: String = row.get("username")?;
-            let operator: i16 = row.get("operator")?;
-            let timestamp = row.get("timestamp")?;
-            let room_id = row.get("room_id")?;
-            let block_expired = row.get("block_expired")?;
-            result.push(BlockUserMessage {
-                uid,
-                username,
-                operator: operator.into(),
-                timestamp,
-                room_id,
-                block_expired,
-            });
-        }
+        let rows = stmt.query(params![uid])?;
+        block_user_rows(rows)
+    }
+}
 
-        Ok(result)
+// source 由配置拼接, 用户输入的条件都通过参数绑定
+#[allow(clippy::too_many_arguments)]
+fn select_stmt(
+    col: &str,
+    source: &str,
+    message_type: Option<MessageType>,
+    uid: Option<u64>,
+    username: Option<String>,
+    message: Option<String>,
+    pagination: Option<Pagination>,
+    order: Option<&str>,
+) -> (String, Vec<Value>) {
+    let mut contidition = Vec::new();
+    let mut values = Vec::new();
+    if let Some(username) = username {
+        contidition.push("username = ?");
+        values.push(Value::Text(username));
+    }
+    if let Some(message) = message {
+        // 按子串匹配, 消息中的 % 和 _ 不作为通配符
+        contidition.push("contains(msg, ?)");
+        values.push(Value::Text(message));
+    }
+    if let Some(message_type) = message_type {
+        contidition.push("msg_type = ?");
+        values.push(Value::TinyInt(i8::from(message_type)));
+    }
+    if let Some(uid) = uid {
+        contidition.push("uid = ?");
+        values.push(Value::UBigInt(uid));
     }
+    let where_clause = if contidition.is_empty() {
+        String::from("")
+    } else {
+        format!("WHERE {}", contidition.join(" AND "))
+    };
+    let order_clause = match order {
+        None => String::from(""),
+        Some(order) => format!("ORDER BY {}", order),
+    };
+    let (pagination_clause, pagination_values) = pagination_clause(pagination);
+    values.extend(pagination_values);
+    (
+        format!(
+            "SELECT {} FROM {} {} {} {}",
+            col, source, where_clause, order_clause, pagination_clause
+        ),
+        values,
+    )
+}
+
+fn pagination_clause(pagination: Option<Pagination>) -> (&'static str, Vec<Value>) {
+    match pagination {
+        None => ("", vec![]),
+        Some(pagination) => (
+            "LIMIT ? OFFSET ?",
+            vec![
+                Value::UBigInt(pagination.limit as u64),
+                Value::UBigInt(pagination.offset as u64),
+            ],
+        ),
+    }
+}
+
+fn block_user_rows(mut rows: Rows) -> Result<Vec<BlockUserMessage>> {
+    let mut result = vec![];
+    while let Some(row) = rows.next()? {
+        let uid: u64 = row.get("uid")?;
+        let username: String = row.get("username")?;
+        let operator: i16 = row.get("operator")?;
+        let timestamp = row.get("timestamp")?;
+        let room_id = row.get("room_id")?;
+        let block_expired = row.get("block_expired")?;
+        result.push(BlockUserMessage {
+            uid,
+            username,
+            operator: operator.into(),
+            timestamp,
+            room_id,
+            block_expired,
+        });
+    }
+    Ok(result)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use dotenv::dotenv;
+    use std::path::PathBuf;
+    use utils::utils::get_part_name;
+
+    #[test]
+    fn test_select_stmt_params() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE danmu (msg_type UTINYINT, uid BIGINT, username TEXT, msg TEXT, timestamp BIGINT, worth FLOAT DEFAULT 0)",
+        )
+        .unwrap();
+        let rows = [
+            (1, "it's me", "hello"),
+            (2, "back\\slash", "100% sure"),
+            (3, "弹幕姬", "a_b"),
+            (4, "other", "axb 100 sure"),
+            (5, "x' OR '1'='1", "你好, 世界"),
+        ];
+        for (i, (uid, username, msg)) in rows.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO danmu VALUES (1, ?, ?, ?, ?, 0)",
+                params![uid, username, msg, i as i64],
+            )
+            .unwrap();
+        }
+        let uids = |username: Option<&str>, message: Option<&str>, pagination| {
+            let (stmt, values) = select_stmt(
+                "uid",
+                "danmu",
+                Some(MessageType::Danmu),
+                None,
+                username.map(str::to_string),
+                message.map(str::to_string),
+                pagination,
+                Some("timestamp"),
+            );
+            conn.prepare(&stmt)
+                .unwrap()
+                .query_map(params_from_iter(values), |row| row.get(0))
+                .unwrap()
+                .collect::<duckdb::Result<Vec<i64>>>()
+                .unwrap()
+        };
+
+        assert_eq!(uids(Some("it's me"), None, None), [1]);
+        assert_eq!(uids(Some("back\\slash"), None, None), [2]);
+        assert_eq!(uids(Some("弹幕姬"), None, None), [3]);
+        // 注入的条件作为普通字符串匹配
+        assert_eq!(uids(Some("x' OR '1'='1"), None, None), [5]);
+        assert!(uids(Some("' OR '1'='1"), None, None).is_empty());
+        // % 和 _ 按字面匹配
+        assert_eq!(uids(None, Some("100%"), None), [2]);
+        assert_eq!(uids(None, Some("a_b"), None), [3]);
+        assert_eq!(uids(None, Some("%"), None), [2]);
+        assert_eq!(uids(None, Some("世界"), None), [5]);
+        assert_eq!(
+            uids(
+                None,
+                None,
+                Some(Pagination {
+                    limit: 2,
+                    offset: 1
+                })
+            ),
+            [2, 3]
+        );
+    }
+
+    // 在本地临时目录写入一个房间当天的弹幕 part 文件
+    fn local_queryer(name: &str, timestamp: i64) -> (PathBuf, Queryer) {
+        let dir = std::env::temp_dir().join(format!("queryer-{}-{}", name, std::process::id()));
+        let location = StorageLocation::local(dir.to_str().unwrap());
+        let table = get_table_name(&location.root(), 1, timestamp).unwrap();
+        location.prepare(&table).unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "CREATE TABLE danmu (msg_type UTINYINT, uid BIGINT, username TEXT, msg TEXT, timestamp BIGINT, worth FLOAT, id TEXT);
+             INSERT INTO danmu VALUES
+                 (1, 1, 'a', 'first', {t0}, 0, 'x'),
+                 (1, 2, 'b', 'second', {t1}, 0, 'y'),
+                 (2, 3, 'c', 'sc', {t2}, 30, 'z');
+             COPY danmu TO '{part1}';
+             DELETE FROM danmu WHERE id = 'x';
+             INSERT INTO danmu VALUES (1, 4, 'd', 'third', {t3}, 0, 'w');
+             COPY danmu TO '{part2}';",
+            t0 = timestamp,
+            t1 = timestamp + 1,
+            t2 = timestamp + 2,
+            t3 = timestamp + 3,
+            part1 = get_part_name(&table, "1"),
+            part2 = get_part_name(&table, "2"),
+        ))
+        .unwrap();
+        let pool = Pool::new(DuckdbConnectionManager::memory().unwrap()).unwrap();
+        (dir, Queryer::with_location(pool, location).unwrap())
+    }
 
     #[test]
-    #[ignore]
     fn test_order() {
-        pretty_env_logger::init();
-        dotenv().ok().unwrap();
-        let manager = DuckdbConnectionManager::memory().unwrap();
-        let pool = Pool::new(manager).unwrap();
-        let query = Queryer::new(pool).unwrap();
-        let room_id = 21533102;
         let timestamp = 1721153475;
+        let (dir, query) = local_queryer("order", timestamp);
         let result = query
             .query(
-                room_id,
+                1,
                 timestamp,
                 Some(MessageType::Danmu),
                 None,
                 None,
                 None,
                 Some(Pagination {
-                    limit: 10,
+                    limit: 2,
                     offset: 0,
                 }),
             )
             .unwrap();
-        for message in result {
-            println!("{:?}", message);
-        }
+        let ids: Vec<String> = result
+            .into_iter()
+            .map(|message| match message {
+                Message::Danmu(msg) => msg.id,
+                _ => panic!("unexpected message"),
+            })
+            .collect();
+        // 按时间倒序, 两个 part 中重复的消息只返回一次
+        assert_eq!(ids, ["w", "y"]);
+        let count = query
+            .query_count(1, timestamp, Some(MessageType::Danmu), None, None, None)
+            .unwrap();
+        assert_eq!(count, 3);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
-    #[ignore]
     fn test_query() {
-        pretty_env_logger::init();
-        dotenv().ok().unwrap();
-        let manager = DuckdbConnectionManager::memory().unwrap();
-        let pool = Pool::new(manager).unwrap();
-        let query = Queryer::new(pool).unwrap();
-        let room_id = 22747736;
         let timestamp = 1720973747;
+        let (dir, query) = local_queryer("query", timestamp);
         let result = query
             .query(
-                room_id,
+                1,
                 timestamp,
                 Some(MessageType::SuperChat),
                 None,
@@ -310,8 +539,98 @@ mod tests {
                 None,
             )
             .unwrap();
-        for message in result {
-            println!("{:?}", message);
+        assert_eq!(result.len(), 1);
+        let Message::SuperChat(msg) = &result[0] else {
+            panic!("unexpected message");
+        };
+        assert_eq!((msg.uid, msg.worth), (3, 30.0));
+        // 当天没有断线记录
+        assert!(query.query_gaps(1, timestamp).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_query_gaps() {
+        let timestamp = 1720973747;
+        let (dir, query) = local_queryer("gaps", timestamp);
+        let table = get_gap_table_name(dir.to_str().unwrap(), 1, timestamp).unwrap();
+        Connection::open_in_memory()
+            .unwrap()
+            .execute_batch(&format!(
+                "CREATE TABLE gap (timestamp BIGINT, end_timestamp BIGINT, reason TEXT);
+                 INSERT INTO gap VALUES ({t1}, {t2}, 'closed'), ({t0}, {t1}, 'timeout');
+                 COPY gap TO '{part}';",
+                t0 = timestamp,
+                t1 = timestamp + 10,
+                t2 = timestamp + 20,
+                part = get_part_name(&table, "1"),
+            ))
+            .unwrap();
+        let gaps = query.query_gaps(1, timestamp).unwrap();
+        assert_eq!(
+            gaps,
+            [
+                Gap {
+                    start: timestamp,
+                    end: timestamp + 10,
+                    reason: "timeout".to_string(),
+                },
+                Gap {
+                    start: timestamp + 10,
+                    end: timestamp + 20,
+                    reason: "closed".to_string(),
+                },
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_query_enter_room_by_uid() {
+        let timestamp = 1720973747;
+        let next_day = timestamp + 2 * 86400;
+        let (dir, query) = local_queryer("enter", timestamp);
+        let root = dir.to_str().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE enter_room (uid BIGINT, username TEXT, timestamp BIGINT, id TEXT)",
+        )
+        .unwrap();
+        // 中间一天没有进房记录文件
+        for (day, rows) in [
+            (
+                timestamp,
+                format!("(1, 'a', {timestamp}, 'x'), (2, 'b', {timestamp}, 'y')"),
+            ),
+            (next_day, format!("(1, 'a', {next_day}, 'z')")),
+        ] {
+            let table = get_enter_table_name(root, 1, day).unwrap();
+            StorageLocation::local(root).prepare(&table).unwrap();
+            conn.execute_batch(&format!(
+                "INSERT INTO enter_room VALUES {rows};
+                 COPY enter_room TO '{part}';
+                 DELETE FROM enter_room",
+                part = get_part_name(&table, "1"),
+            ))
+            .unwrap();
         }
+        let ids = |end: i64| -> Vec<String> {
+            query
+                .query_enter_room_by_uid(1, 1, timestamp, end)
+                .unwrap()
+                .into_iter()
+                .map(|message| message.id)
+                .collect()
+        };
+        assert_eq!(ids(next_day), ["z", "x"]);
+
+        // 文件读取出错时返回错误, 不当作没有记录
+        let table = get_enter_table_name(root, 1, next_day).unwrap();
+        std::fs::write(get_part_name(&table, "2"), "not parquet").unwrap();
+        assert!(query
+            .query_enter_room_by_uid(1, 1, timestamp, next_day)
+            .is_err());
+        assert_eq!(ids(timestamp), ["x"]);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
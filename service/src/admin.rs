@@ -0,0 +1,100 @@
+use crate::error::AppError;
+use crate::{api, AppState};
+use axum::extract::{Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::Router;
+use std::sync::Arc;
+use tracing::info;
+
+// 禁言接口会以房管账号执行操作, 只在本地管理端口提供, 请求需携带 Authorization: Bearer <ADMIN_TOKEN>
+pub fn router(state: AppState, token: String) -> Router {
+    Router::new()
+        .route("/api/block_user", post(api::block_user))
+        .route("/api/unblock_user", post(api::unblock_user))
+        .route("/api/silent_users", get(api::query_silent_users))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            require_token,
+        ))
+        .with_state(state)
+}
+
+// 在调用 Moderator 之前校验令牌
+async fn require_token(
+    State(token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| token_matches(value, &token));
+    if !authorized {
+        info!("admin request without valid token: {}", request.uri());
+        return Err(AppError::Unauthorized);
+    }
+    Ok(next.run(request).await)
+}
+
+// 比较耗时与相同前缀的长度无关
+fn token_matches(value: &str, token: &str) -> bool {
+    value.len() == token.len()
+        && value
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/admin", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::<str>::from("secret"),
+                require_token,
+            ))
+    }
+
+    async fn status(authorization: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().uri("/admin");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        app()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_require_token() {
+        assert_eq!(status(Some("Bearer secret")).await, StatusCode::OK);
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("secret")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer secre")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Some("Bearer secret2")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("abc", "abc"));
+        assert!(!token_matches("abd", "abc"));
+        assert!(!token_matches("", "abc"));
+    }
+}
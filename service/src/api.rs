@@ -1,19 +1,22 @@
 use crate::error::AppError;
 use crate::model::{
-    message_to_checker_response_date, message_vec_to_query_response_data_vec, CheckerRequest,
-    CheckerResponse, DanmuStatisticsRequest, DanmuStatisticsResponse, QueryBlockUserRequest,
-    QueryBlockerResponse, QueryRequest, QueryResponse, QueryStatisticsData, QueryStatisticsRequest,
-    QueryStatisticsResponse,
+    message_to_checker_response_date, message_vec_to_query_response_data_vec, BlockRequest,
+    BlockResponse, CheckerRequest, CheckerResponse, DanmuStatisticsRequest,
+    DanmuStatisticsResponse, QueryBlockUserRequest, QueryBlockerResponse, QueryRequest,
+    QueryResponse, QueryStatisticsData, QueryStatisticsRequest, QueryStatisticsResponse,
+    SilentUsersRequest, SilentUsersResponse, UnblockRequest, UnblockResponse,
 };
+use crate::moderation::Moderator;
 use crate::AppState;
-use axum::extract::rejection::{PathRejection, QueryRejection};
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
 use axum::extract::{Path, Query, State};
 use axum::Json;
 use chrono::Duration;
 use queryer::Queryer;
 use std::sync::Arc;
 use tracing::{debug, info};
-use utils::utils::{get_local_midnight, get_rooms, MessageType, Pagination};
+use utils::rooms::RoomsConfig;
+use utils::utils::{get_local_midnight, MessageType, Pagination};
 
 pub async fn query(
     State(state): State<AppState>,
@@ -31,6 +34,14 @@ pub async fn query(
 
     let req = extract_req(req)?;
     let message_type: MessageType = req.message_type.into();
+    // 没有消息时也返回断线时段, 区分没有弹幕和没有采集到数据
+    let gaps = match storage.query_gaps(room_id, req.timestamp) {
+        Ok(gaps) => gaps.into_iter().map(Into::into).collect(),
+        Err(e) => {
+            info!("query gaps error: {}", e);
+            return Err(AppError::QueryError);
+        }
+    };
     let count = match storage.query_count(
         room_id,
         req.timestamp,
@@ -51,6 +62,7 @@ pub async fn query(
             message: "success".to_string(),
             count: 0,
             data: vec![],
+            gaps,
         }));
     };
     let query_result = match storage.query(
@@ -77,6 +89,7 @@ pub async fn query(
         message: "success".to_string(),
         count,
         data: query_result,
+        gaps,
     }))
 }
 
@@ -86,8 +99,13 @@ pub async fn checker(
 ) -> Result<Json<CheckerResponse>, AppError> {
     let storage = state.queryer;
     let req = extract_req(req)?;
+    // 每次请求重新读取房间配置, 新增房间无需重启服务; 已停用的房间仍查询历史数据
+    let rooms = RoomsConfig::from_env().map_err(|e| {
+        info!("load rooms config error: {}", e);
+        AppError::QueryError
+    })?;
     let mut result = vec![];
-    for room in get_rooms() {
+    for room in rooms.all_room_ids() {
         match storage.query(room, req.timestamp, None, Some(req.uid), None, None, None) {
             Ok(data) => {
                 for message in data {
@@ -100,14 +118,80 @@ pub async fn checker(
             }
         };
     }
-    result.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    result.sort_by_key(|b| std::cmp::Reverse(b.timestamp));
+    let blocks = match &state.moderator {
+        Some(moderator) => match storage.query_block_user_by_uid(req.uid) {
+            Ok(events) => moderator.reconcile(req.uid, &events),
+            Err(e) => {
+                info!("query from db error: {}", e);
+                return Err(AppError::QueryError);
+            }
+        },
+        None => vec![],
+    };
     Ok(Json(CheckerResponse {
         code: 0,
         message: "success".to_string(),
         data: result,
+        blocks,
     }))
 }
 
+pub async fn block_user(
+    State(state): State<AppState>,
+    req: Result<Json<BlockRequest>, JsonRejection>,
+) -> Result<Json<BlockResponse>, AppError> {
+    let moderator = get_moderator(&state)?;
+    let req = extract_json(req)?;
+    let action = moderator
+        .block(req.room_id, req.uid, req.hour)
+        .await
+        .map_err(|e| AppError::ModerationError(e.to_string()))?;
+    state.block_user_cache.invalidate_all();
+    Ok(Json(BlockResponse {
+        code: 0,
+        message: "success".to_string(),
+        data: action,
+    }))
+}
+
+pub async fn unblock_user(
+    State(state): State<AppState>,
+    req: Result<Json<UnblockRequest>, JsonRejection>,
+) -> Result<Json<UnblockResponse>, AppError> {
+    let moderator = get_moderator(&state)?;
+    let req = extract_json(req)?;
+    moderator
+        .unblock(req.room_id, req.uid)
+        .await
+        .map_err(|e| AppError::ModerationError(e.to_string()))?;
+    Ok(Json(UnblockResponse {
+        code: 0,
+        message: "success".to_string(),
+    }))
+}
+
+pub async fn query_silent_users(
+    State(state): State<AppState>,
+    req: Result<Query<SilentUsersRequest>, QueryRejection>,
+) -> Result<Json<SilentUsersResponse>, AppError> {
+    let moderator = get_moderator(&state)?;
+    let req = extract_req(req)?;
+    let data = moderator
+        .silent_users(req.room_id)
+        .await
+        .map_err(|e| AppError::ModerationError(e.to_string()))?;
+    Ok(Json(SilentUsersResponse {
+        code: 0,
+        message: "success".to_string(),
+        data,
+    }))
+}
+
+fn get_moderator(state: &AppState) -> Result<Arc<Moderator>, AppError> {
+    state.moderator.clone().ok_or(AppError::ModerationDisabled)
+}
+
 pub async fn query_statistics(
     State(state): State<AppState>,
     req: Result<Query<QueryStatisticsRequest>, QueryRejection>,
@@ -262,6 +346,19 @@ pub async fn query_danmu_statistics_data_from_db(
     Ok(response)
 }
 
+fn extract_json<T>(req: Result<Json<T>, JsonRejection>) -> Result<T, AppError> {
+    match req {
+        Ok(req) => Ok(req.0),
+        Err(e) => {
+            info!("parse json request error: {}", e);
+            Err(AppError::ParamError(format!(
+                "parse json request error: {}",
+                e
+            )))
+        }
+    }
+}
+
 fn extract_req<T>(req: Result<Query<T>, QueryRejection>) -> Result<T, AppError> {
     match req {
         Ok(req) => Ok(req.0),
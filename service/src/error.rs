@@ -12,6 +12,12 @@ pub enum AppError {
     ParamError(String),
     #[error("query error")]
     QueryError,
+    #[error("moderation disabled")]
+    ModerationDisabled,
+    #[error("moderation error: {0}")]
+    ModerationError(String),
+    #[error("unauthorized")]
+    Unauthorized,
 }
 
 impl IntoResponse for AppError {
@@ -21,6 +27,11 @@ impl IntoResponse for AppError {
             message: self.to_string(),
         });
 
-        (StatusCode::OK, body).into_response()
+        // 管理接口鉴权失败时返回 401, 其它错误沿用 code -1
+        let status = match self {
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            _ => StatusCode::OK,
+        };
+        (status, body).into_response()
     }
 }
@@ -1,6 +1,7 @@
 use crate::api::query_danmu_statistics_data_from_db;
 use crate::error::AppError;
 use crate::model::{DanmuStatisticsResponse, QueryBlockerResponse, QueryStatisticsData};
+use crate::moderation::Moderator;
 use anyhow::Result;
 use axum::http::Method;
 use axum::routing::get;
@@ -17,11 +18,14 @@ use tower_http::trace::TraceLayer;
 use tracing::info;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use utils::utils::{get_local_midnight, get_rooms};
+use utils::rooms::RoomsConfig;
+use utils::utils::get_local_midnight;
 
+mod admin;
 mod api;
 pub mod error;
 pub mod model;
+pub mod moderation;
 
 #[derive(Clone)]
 struct AppState {
@@ -29,6 +33,7 @@ struct AppState {
     statistics_cache: Arc<Cache<(i64, i64), QueryStatisticsData>>,
     block_user_cache: Arc<Cache<(usize, usize), QueryBlockerResponse>>,
     danmu_statistics_cache: Arc<Cache<(i64, i64, i64), DanmuStatisticsResponse>>,
+    moderator: Option<Arc<Moderator>>,
 }
 
 #[tokio::main]
@@ -62,6 +67,7 @@ async fn main() -> Result<()> {
         statistics_cache: Arc::new(statistics_cache),
         block_user_cache: Arc::new(block_user_cache),
         danmu_statistics_cache: Arc::new(danmu_statistics_cache),
+        moderator: Moderator::from_env()?.map(Arc::new),
     };
 
     let cache_state = state.clone();
@@ -79,7 +85,14 @@ async fn main() -> Result<()> {
             }
         };
         let start = end - 30 * 24 * 60 * 60;
-        for room_id in get_rooms() {
+        let rooms = match RoomsConfig::from_env() {
+            Ok(config) => config.room_ids(),
+            Err(e) => {
+                info!("预热缓存错误: {}", e);
+                return;
+            }
+        };
+        for room_id in rooms {
             match query_danmu_statistics_data_from_db(
                 cache_state.queryer.clone(),
                 room_id,
@@ -103,8 +116,8 @@ async fn main() -> Result<()> {
     });
 
     let cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST])
+        // 公开接口只读, 禁言等写操作在本地管理端口提供
+        .allow_methods([Method::GET])
         // allow requests from any origin
         .allow_origin(Any);
 
@@ -116,7 +129,25 @@ async fn main() -> Result<()> {
         .route("/api/danmu_statistics", get(api::query_danmu_statistics))
         .layer(cors)
         .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()))
-        .with_state(state);
+        .with_state(state.clone());
+
+    // 未配置 ADMIN_TOKEN 时不启动管理端口
+    match std::env::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => {
+            let admin_addr =
+                std::env::var("ADMIN_ADDR").unwrap_or_else(|_| "127.0.0.1:8082".to_string());
+            let admin_listener = tokio::net::TcpListener::bind(&admin_addr).await?;
+            info!("Admin listening on http://{}", admin_addr);
+            let admin_app = admin::router(state, token)
+                .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()));
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(admin_listener, admin_app).await {
+                    info!("admin server error: {}", e);
+                }
+            });
+        }
+        _ => info!("ADMIN_TOKEN 未配置, 禁言接口不可用"),
+    }
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
     info!("Listening on http://0.0.0.0:8080");
@@ -1,8 +1,10 @@
 use crate::error::AppError;
+use crate::moderation::BlockAction;
+use danmu_client::SilentUser;
 use model::statistics;
 use parse::{BlockUserMessage, Message};
 use serde::{Deserialize, Serialize};
-use utils::utils::MessageType;
+use utils::utils::{Gap, MessageType};
 
 #[derive(Serialize)]
 pub struct ErrorResponse {
@@ -26,6 +28,25 @@ pub struct QueryResponse {
     pub message: String,
     pub count: usize,
     pub data: Vec<QueryResponseData>,
+    // 当天 crawler 断线的时段, 这些时段内的消息不完整
+    pub gaps: Vec<QueryGap>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryGap {
+    pub start: i64,
+    pub end: i64,
+    pub reason: String,
+}
+
+impl From<Gap> for QueryGap {
+    fn from(gap: Gap) -> Self {
+        QueryGap {
+            start: gap.start,
+            end: gap.end,
+            reason: gap.reason,
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -85,6 +106,8 @@ pub struct CheckerResponse {
     pub code: isize,
     pub message: String,
     pub data: Vec<CheckerResponseData>,
+    // 通过 service 对该用户发起的禁言操作
+    pub blocks: Vec<BlockAction>,
 }
 
 #[derive(Serialize, Debug)]
@@ -182,6 +205,44 @@ impl From<&BlockUserMessage> for QueryBlockerResponseData {
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct BlockRequest {
+    pub room_id: u64,
+    pub uid: u64,
+    pub hour: i64, // -1 永久, 0 本场直播
+}
+
+#[derive(Serialize, Debug)]
+pub struct BlockResponse {
+    pub code: isize,
+    pub message: String,
+    pub data: BlockAction,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnblockRequest {
+    pub room_id: u64,
+    pub uid: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UnblockResponse {
+    pub code: isize,
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SilentUsersRequest {
+    pub room_id: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SilentUsersResponse {
+    pub code: isize,
+    pub message: String,
+    pub data: Vec<SilentUser>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct DanmuStatisticsRequest {
     pub room_id: i64,
@@ -0,0 +1,197 @@
+use anyhow::Result;
+use base64::Engine;
+use chrono::Utc;
+use danmu_client::danmu::Client;
+use danmu_client::error::ModerationError;
+use danmu_client::SilentUser;
+use parse::{BlockUserEnum, BlockUserMessage};
+use serde::Serialize;
+use std::sync::RwLock;
+use tracing::info;
+
+// 弹幕流中的 ROOM_BLOCK_MSG 时间戳为 crawler 收到的时间, 允许少量偏差
+const RECONCILE_SLACK: i64 = 60;
+// 超过该时间仍未在弹幕流中收到禁言消息, 视为未确认
+const RECONCILE_TIMEOUT: i64 = 10 * 60;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockStatus {
+    Pending,     // 等待弹幕流中的禁言消息
+    Confirmed,   // 已收到对应的 ROOM_BLOCK_MSG
+    Unconfirmed, // 超时未收到
+    Removed,     // 已解除禁言
+}
+
+// 通过 service 发起的禁言操作
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlockAction {
+    pub room_id: u64,
+    pub uid: u64,
+    pub hour: i64,
+    pub timestamp: i64,
+    pub status: BlockStatus,
+}
+
+// 使用 BILI_COOKIE 中的房管账号执行禁言, 未配置时 service 不提供禁言接口
+pub struct Moderator {
+    client: Client,
+    actions: RwLock<Vec<BlockAction>>,
+}
+
+impl Moderator {
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(cookies) = std::env::var("BILI_COOKIE") else {
+            info!("BILI_COOKIE 未配置, 禁言功能不可用");
+            return Ok(None);
+        };
+        let cookies =
+            String::from_utf8(base64::engine::general_purpose::STANDARD.decode(cookies)?)?;
+        Ok(Some(Self::new(Client::new(0, &cookies)?)))
+    }
+
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            actions: RwLock::new(vec![]),
+        }
+    }
+
+    pub async fn block(
+        &self,
+        room_id: u64,
+        uid: u64,
+        hour: i64,
+    ) -> Result<BlockAction, ModerationError> {
+        self.client.add_silent_user(room_id, uid, hour).await?;
+        let action = BlockAction {
+            room_id,
+            uid,
+            hour,
+            timestamp: Utc::now().timestamp(),
+            status: BlockStatus::Pending,
+        };
+        self.actions.write().unwrap().push(action.clone());
+        Ok(action)
+    }
+
+    pub async fn unblock(&self, room_id: u64, uid: u64) -> Result<(), ModerationError> {
+        self.client.remove_silent_user(room_id, uid).await?;
+        for action in self.actions.write().unwrap().iter_mut() {
+            if action.room_id == room_id && action.uid == uid {
+                action.status = BlockStatus::Removed;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn silent_users(&self, room_id: u64) -> Result<Vec<SilentUser>, ModerationError> {
+        self.client.list_silent_users(room_id).await
+    }
+
+    // 用弹幕流中记录的禁言消息更新该用户的禁言操作状态
+    pub fn reconcile(&self, uid: u64, events: &[BlockUserMessage]) -> Vec<BlockAction> {
+        let mut actions = self.actions.write().unwrap();
+        reconcile_actions(&mut actions, events, Utc::now().timestamp());
+        actions
+            .iter()
+            .filter(|action| action.uid == uid)
+            .cloned()
+            .collect()
+    }
+}
+
+fn reconcile_actions(actions: &mut [BlockAction], events: &[BlockUserMessage], now: i64) {
+    for action in actions
+        .iter_mut()
+        .filter(|action| action.status != BlockStatus::Removed)
+    {
+        let received = events.iter().any(|event| {
+            event.room_id as u64 == action.room_id
+                && event.uid == action.uid
+                // BILI_COOKIE 可以是房管账号, 也可以是主播本人的账号
+                && matches!(event.operator, BlockUserEnum::Manager | BlockUserEnum::Owner)
+                && event.timestamp >= action.timestamp - RECONCILE_SLACK
+        });
+        if received {
+            action.status = BlockStatus::Confirmed;
+        } else if action.status == BlockStatus::Pending
+            && now - action.timestamp > RECONCILE_TIMEOUT
+        {
+            action.status = BlockStatus::Unconfirmed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(room_id: u64, uid: u64, timestamp: i64) -> BlockAction {
+        BlockAction {
+            room_id,
+            uid,
+            hour: 24,
+            timestamp,
+            status: BlockStatus::Pending,
+        }
+    }
+
+    fn event(room_id: i64, uid: u64, timestamp: i64) -> BlockUserMessage {
+        operator_event(room_id, uid, timestamp, BlockUserEnum::Manager)
+    }
+
+    fn operator_event(
+        room_id: i64,
+        uid: u64,
+        timestamp: i64,
+        operator: BlockUserEnum,
+    ) -> BlockUserMessage {
+        BlockUserMessage {
+            uid,
+            username: "test".to_string(),
+            operator,
+            timestamp,
+            room_id,
+            block_expired: 0,
+        }
+    }
+
+    #[test]
+    fn test_reconcile_actions() {
+        let mut actions = vec![
+            action(1, 100, 1000),
+            action(2, 100, 1000),
+            action(1, 200, 1000),
+        ];
+        let events = vec![
+            event(1, 100, 1003),
+            // 操作之前的旧禁言记录不算
+            event(1, 200, 500),
+        ];
+        reconcile_actions(&mut actions, &events, 1100);
+        assert_eq!(actions[0].status, BlockStatus::Confirmed);
+        assert_eq!(actions[1].status, BlockStatus::Pending);
+        assert_eq!(actions[2].status, BlockStatus::Pending);
+
+        reconcile_actions(&mut actions, &events, 1000 + RECONCILE_TIMEOUT + 1);
+        assert_eq!(actions[0].status, BlockStatus::Confirmed);
+        assert_eq!(actions[1].status, BlockStatus::Unconfirmed);
+
+        // 超时后收到的消息仍然可以确认
+        reconcile_actions(&mut actions, &[event(2, 100, 1900)], 2000);
+        assert_eq!(actions[1].status, BlockStatus::Confirmed);
+    }
+
+    #[test]
+    fn test_reconcile_owner_actions() {
+        let mut actions = vec![action(1, 100, 1000), action(1, 200, 1000)];
+        let events = vec![
+            operator_event(1, 100, 1003, BlockUserEnum::Owner),
+            operator_event(1, 200, 1003, BlockUserEnum::Other),
+        ];
+        reconcile_actions(&mut actions, &events, 1100);
+        assert_eq!(actions[0].status, BlockStatus::Confirmed);
+        assert_eq!(actions[1].status, BlockStatus::Pending);
+    }
+}
@@ -1,9 +1,11 @@
 use anyhow::{anyhow, Result};
 use chrono::{Duration, Local};
-use duckdb::Connection;
+use duckdb::{params, Connection};
 use log::{debug, info};
 use model::statistics::{StatisticsResult, StatisticsScope};
-use utils::utils::{get_local_midnight, get_rooms, get_table_name, MessageType, OssConfig};
+use utils::location::StorageLocation;
+use utils::rooms::RoomsConfig;
+use utils::utils::{dedup_source, get_local_midnight, get_table_glob, get_table_name, MessageType};
 
 fn main() -> Result<()> {
     pretty_env_logger::init_timed();
@@ -18,7 +20,7 @@ fn main() -> Result<()> {
     if scope != "today" && scope != "yesterday" && scope != "someday" {
         return Err(anyhow!("scope must be today or yesterday"));
     }
-    let rooms = get_rooms();
+    let rooms = RoomsConfig::from_env()?.room_ids();
     let mut conn = Connection::open_in_memory()?;
     let mut stats = Statistics::new(&mut conn)?;
     info!("start statistics {}", scope);
@@ -53,18 +55,15 @@ fn main() -> Result<()> {
 }
 
 struct Statistics<'a> {
-    bucket: String,
+    location: StorageLocation,
     conn: &'a mut Connection,
 }
 
 impl<'a> Statistics<'a> {
     fn new(conn: &'a mut Connection) -> Result<Self> {
-        let oss_config = OssConfig::new()?;
-        oss_config.clone().init_oss_with_conn(conn)?;
-        Ok(Self {
-            bucket: oss_config.bucket,
-            conn,
-        })
+        let location = StorageLocation::from_env()?;
+        location.init_conn(conn)?;
+        Ok(Self { location, conn })
     }
 
     pub fn init_table(&self, room_id: i64) -> Result<()> {
@@ -91,27 +90,26 @@ impl<'a> Statistics<'a> {
 
     fn statistics_day(&mut self, timestamp: i64, room_id: i64) -> Result<()> {
         let timestamp = get_local_midnight(timestamp)?;
-        let data_table = get_table_name(&self.bucket, room_id, timestamp)?;
+        let root = self.location.root();
+        let data_table = get_table_glob(&get_table_name(&root, room_id, timestamp)?);
+        let source = dedup_source(self.conn, &format!("'{data_table}'"))?;
         let table = StatisticsScope::Day;
         let local_table = table.local_table_name(room_id);
-        let remote_table = table.remote_table_name(&self.bucket, room_id, timestamp);
+        let remote_table = table.remote_table_name(&root, room_id, timestamp);
 
         let result = self.conn.query_row(
             format!(
                 "SELECT
-                        '{}' AS timestamp,
                         COALESCE(SUM(worth), 0) AS super_chat_worth,
                         COALESCE(COUNT(DISTINCT uid), 0) AS danmu_people,
-                        COALESCE(COUNT(CASE WHEN msg_type = {} THEN 1 END), 0) AS super_chat_total,
+                        COALESCE(COUNT(CASE WHEN msg_type = ? THEN 1 END), 0) AS super_chat_total,
                         COALESCE(COUNT(*), 0) AS danmu_total
                     FROM
-                        '{}'",
-                local_table,
-                i8::from(MessageType::SuperChat),
-                data_table,
+                        {}",
+                source,
             )
             .as_str(),
-            [],
+            params![i8::from(MessageType::SuperChat)],
             |row| {
                 Ok(StatisticsResult {
                     danmu_total: row.get("danmu_total")?,
@@ -123,13 +121,18 @@ impl<'a> Statistics<'a> {
             },
         )?;
         debug!("statistics result: {:?}", result);
-        // start transaction
-        self.conn.execute(
-                format!("INSERT INTO {} (danmu_total, danmu_people, super_chat_total, super_chat_worth, timestamp)
-                        VALUES ({}, {}, {}, {}, {})", local_table, result.danmu_total,result.danmu_people, result.super_chat_total, result.super_chat_worth, timestamp).as_str(),
-                [],
-            )?;
+        let mut appender = self.conn.appender(&local_table)?;
+        appender.append_row(params![
+            timestamp,
+            result.danmu_total,
+            result.danmu_people,
+            result.super_chat_total,
+            result.super_chat_worth,
+        ])?;
+        appender.flush()?;
+        drop(appender);
 
+        self.location.prepare(&remote_table)?;
         self.conn
             .execute(&format!("COPY {local_table} TO '{remote_table}'"), [])?;
         Ok(())
@@ -159,7 +162,7 @@ mod tests {
     fn test_remote_table_name() {
         let now = Utc::now().timestamp();
         let day_midnight = get_local_midnight(now).unwrap();
-        let table_name = StatisticsScope::Day.remote_table_name("test", 1, now);
+        let table_name = StatisticsScope::Day.remote_table_name("s3://test", 1, now);
         assert_eq!(
             table_name,
             format!(
@@ -1 +1,3 @@
+pub mod location;
+pub mod rooms;
 pub mod utils;
@@ -0,0 +1,82 @@
+use crate::utils::OssConfig;
+use anyhow::{anyhow, Result};
+use duckdb::Connection;
+use std::env;
+use std::path::Path;
+
+// 数据存放位置, 由 STORAGE_BACKEND 选择:
+// s3 (默认) 使用 OSS_* 配置的对象存储, local 使用 STORAGE_DIR 目录 (默认为 data)
+// 各表的路径都由 root() 拼接, 例如 {root}/2024-07-15/22747736/danmu.parquet
+#[derive(Clone)]
+pub enum StorageLocation {
+    S3(OssConfig),
+    Local(String),
+}
+
+impl StorageLocation {
+    pub fn from_env() -> Result<Self> {
+        match env::var("STORAGE_BACKEND").as_deref() {
+            Ok("s3") | Err(_) => Ok(StorageLocation::S3(OssConfig::new()?)),
+            Ok("local") => Ok(Self::local(
+                &env::var("STORAGE_DIR").unwrap_or_else(|_| "data".to_string()),
+            )),
+            Ok(backend) => Err(anyhow!("unknown STORAGE_BACKEND: {}", backend)),
+        }
+    }
+
+    pub fn local(dir: &str) -> Self {
+        let dir = dir.trim_end_matches('/');
+        StorageLocation::Local(if dir.is_empty() { "/" } else { dir }.to_string())
+    }
+
+    pub fn root(&self) -> String {
+        match self {
+            StorageLocation::S3(config) => format!("s3://{}", config.bucket),
+            StorageLocation::Local(dir) => dir.clone(),
+        }
+    }
+
+    // 新建连接后调用, s3 需要配置密钥
+    pub fn init_conn(&self, conn: &Connection) -> Result<()> {
+        match self {
+            StorageLocation::S3(config) => config.clone().init_oss_with_conn(conn),
+            StorageLocation::Local(_) => Ok(()),
+        }
+    }
+
+    // 写入文件前调用, duckdb 不会自动创建本地目录
+    pub fn prepare(&self, path: &str) -> Result<()> {
+        if let StorageLocation::Local(_) = self {
+            if let Some(parent) = Path::new(path).parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::get_table_name;
+
+    #[test]
+    fn test_local_location() {
+        let dir = env::temp_dir().join(format!("location-test-{}", std::process::id()));
+        let location = StorageLocation::local(&format!("{}/", dir.display()));
+        assert_eq!(location.root(), dir.display().to_string());
+        assert_eq!(StorageLocation::local("/").root(), "/");
+
+        let table = get_table_name(&location.root(), 1, 1720973747).unwrap();
+        assert_eq!(
+            table,
+            format!("{}/2024-07-15/1/danmu.parquet", dir.display())
+        );
+        location.prepare(&table).unwrap();
+        assert!(dir.join("2024-07-15/1").is_dir());
+        location
+            .init_conn(&Connection::open_in_memory().unwrap())
+            .unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,539 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// 房间配置文件, 例如:
+//
+// # 房间未配置 store 时存储的消息类型
+// store = ["danmu", "super_chat", "block_user"]
+//
+// [[room]]
+// id = 22747736
+// label = "某主播"
+// enabled = true
+// store = ["danmu", "super_chat", "enter_room"]
+// # 进房记录数据量大, 可以按 uid 抽样存储, 默认为 1.0 (全部存储)
+// enter_room_sample = 0.1
+// # 消息写入的目标, 默认为 ["parquet"], 修改后重启房间生效
+// sinks = ["parquet", "stdout", { type = "jsonl", dir = "jsonl" }, { type = "webhook", url = "http://127.0.0.1:9000/danmu" }]
+// # parquet 缓冲的写出阈值, 任一条件满足时写出, 未配置的项使用顶层 flush 或默认值, 修改后重启房间生效
+// flush = { max_rows = 100, max_enter_rows = 1000, max_bytes = 8388608, max_age_secs = 300 }
+//
+// ROOMS_FILE 指定文件路径, 默认为 rooms.toml
+// ROOMS 为逗号分隔的房间号, 设置后只启用其中的房间, 文件中没有的房间使用默认配置
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    Danmu,
+    SuperChat,
+    BlockUser,
+    EnterRoom,
+}
+
+impl FromStr for StoreKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "danmu" => Ok(StoreKind::Danmu),
+            "super_chat" => Ok(StoreKind::SuperChat),
+            "block_user" => Ok(StoreKind::BlockUser),
+            "enter_room" => Ok(StoreKind::EnterRoom),
+            _ => Err(anyhow!("unknown message type: {}", s)),
+        }
+    }
+}
+
+impl Display for StoreKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                StoreKind::Danmu => "danmu",
+                StoreKind::SuperChat => "super_chat",
+                StoreKind::BlockUser => "block_user",
+                StoreKind::EnterRoom => "enter_room",
+            }
+        )
+    }
+}
+
+pub fn default_store() -> BTreeSet<StoreKind> {
+    BTreeSet::from([StoreKind::Danmu, StoreKind::SuperChat, StoreKind::BlockUser])
+}
+
+// 房间消息的写入目标, 配置为字符串或带 type 的内联表
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SinkConfig {
+    // parquet 文件, 写入 STORAGE_BACKEND 配置的位置
+    Parquet,
+    // 每天每个房间一个 jsonl 文件
+    Jsonl {
+        #[serde(default = "default_jsonl_dir")]
+        dir: String,
+    },
+    // 打印到标准输出, 用于调试
+    Stdout,
+    // 逐条 POST 到 url
+    Webhook {
+        url: String,
+    },
+}
+
+pub fn default_sinks() -> Vec<SinkConfig> {
+    vec![SinkConfig::Parquet]
+}
+
+fn default_jsonl_dir() -> String {
+    "jsonl".to_string()
+}
+
+// parquet 缓冲的写出阈值, 行数和字节数超过阈值或最早的消息等待超过 max_age_secs 时写出
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushConfig {
+    // 弹幕和 SC 的行数
+    pub max_rows: u64,
+    // 进房记录数量远多于弹幕, 单独设置阈值
+    pub max_enter_rows: u64,
+    pub max_bytes: u64,
+    pub max_age_secs: u64,
+}
+
+impl Default for FlushConfig {
+    fn default() -> Self {
+        Self {
+            max_rows: 100,
+            max_enter_rows: 1000,
+            max_bytes: 8 * 1024 * 1024,
+            max_age_secs: 5 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomConfig {
+    pub id: u64,
+    pub label: String,
+    pub enabled: bool,
+    pub store: BTreeSet<StoreKind>,
+    pub enter_room_sample: f64,
+    pub sinks: Vec<SinkConfig>,
+    pub flush: FlushConfig,
+}
+
+impl RoomConfig {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            label: String::new(),
+            enabled: true,
+            store: default_store(),
+            enter_room_sample: 1.0,
+            sinks: default_sinks(),
+            flush: FlushConfig::default(),
+        }
+    }
+
+    pub fn stores(&self, kind: StoreKind) -> bool {
+        self.store.contains(&kind)
+    }
+
+    // 按 uid 抽样, 同一用户的进房记录要么全部保留要么全部丢弃
+    pub fn sample_enter(&self, uid: u64) -> bool {
+        if self.enter_room_sample >= 1.0 {
+            return true;
+        }
+        let bucket = (uid.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) % 10000;
+        (bucket as f64) < self.enter_room_sample * 10000.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomsConfig {
+    pub store: BTreeSet<StoreKind>,
+    pub sinks: Vec<SinkConfig>,
+    pub flush: FlushConfig,
+    pub rooms: Vec<RoomConfig>,
+}
+
+impl FromStr for RoomsConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let file: RoomsFile = toml::from_str(s)?;
+        let store = file.store.unwrap_or_else(default_store);
+        let sinks = match file.sinks {
+            Some(sinks) => build_sinks(sinks)?,
+            None => default_sinks(),
+        };
+        let flush = match file.flush {
+            Some(flush) => flush.apply(&FlushConfig::default()),
+            None => FlushConfig::default(),
+        };
+        let mut rooms: Vec<RoomConfig> = Vec::new();
+        for room in file.room {
+            let room = room.build(&store, &sinks, &flush)?;
+            if rooms.iter().any(|r| r.id == room.id) {
+                return Err(anyhow!("duplicate room {}", room.id));
+            }
+            rooms.push(room);
+        }
+        Ok(Self {
+            store,
+            sinks,
+            flush,
+            rooms,
+        })
+    }
+}
+
+impl RoomsConfig {
+    pub fn path() -> PathBuf {
+        env::var("ROOMS_FILE")
+            .unwrap_or_else(|_| "rooms.toml".to_string())
+            .into()
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("读取房间配置 {} 出错: {}", path.display(), e))?;
+        content
+            .parse()
+            .map_err(|e| anyhow!("解析房间配置 {} 出错: {}", path.display(), e))
+    }
+
+    // 读取 ROOMS_FILE 并应用 ROOMS 覆盖
+    pub fn from_env() -> Result<Self> {
+        Self::load(&Self::path())?.with_overrides(env::var("ROOMS").ok().as_deref())
+    }
+
+    // rooms 为逗号分隔的房间号, 只启用其中的房间
+    pub fn with_overrides(mut self, rooms: Option<&str>) -> Result<Self> {
+        let Some(rooms) = rooms else {
+            return Ok(self);
+        };
+        let ids = rooms
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse::<u64>()
+                    .map_err(|_| anyhow!("invalid room id in ROOMS: {}", s))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for room in &mut self.rooms {
+            room.enabled = ids.contains(&room.id);
+        }
+        for id in ids {
+            if !self.rooms.iter().any(|room| room.id == id) {
+                let mut room = RoomConfig::new(id);
+                room.store.clone_from(&self.store);
+                room.sinks.clone_from(&self.sinks);
+                room.flush = self.flush;
+                self.rooms.push(room);
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn enabled(&self) -> impl Iterator<Item = &RoomConfig> {
+        self.rooms.iter().filter(|room| room.enabled)
+    }
+
+    // 启用的房间号
+    pub fn room_ids(&self) -> Vec<i64> {
+        self.enabled().map(|room| room.id as i64).collect()
+    }
+
+    // 包括已停用的房间, 用于查询历史数据
+    pub fn all_room_ids(&self) -> Vec<i64> {
+        self.rooms.iter().map(|room| room.id as i64).collect()
+    }
+}
+
+// 配置文件的内容, 未配置的项在 from_str 中使用顶层配置或默认值
+#[derive(Deserialize)]
+struct RoomsFile {
+    store: Option<BTreeSet<StoreKind>>,
+    sinks: Option<Vec<SinkEntry>>,
+    flush: Option<FlushEntry>,
+    #[serde(default)]
+    room: Vec<RoomEntry>,
+}
+
+#[derive(Deserialize)]
+struct RoomEntry {
+    id: NonZeroU64,
+    #[serde(default)]
+    label: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    store: Option<BTreeSet<StoreKind>>,
+    enter_room_sample: Option<f64>,
+    sinks: Option<Vec<SinkEntry>>,
+    flush: Option<FlushEntry>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl RoomEntry {
+    fn build(
+        self,
+        default_store: &BTreeSet<StoreKind>,
+        default_sinks: &[SinkConfig],
+        default_flush: &FlushConfig,
+    ) -> Result<RoomConfig> {
+        let id = self.id.get();
+        let enter_room_sample = self.enter_room_sample.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&enter_room_sample) {
+            return Err(anyhow!(
+                "room {} enter_room_sample must be between 0 and 1",
+                id
+            ));
+        }
+        let sinks = match self.sinks {
+            Some(sinks) => build_sinks(sinks).map_err(|e| anyhow!("room {}: {}", id, e))?,
+            None => default_sinks.to_vec(),
+        };
+        Ok(RoomConfig {
+            id,
+            label: self.label,
+            enabled: self.enabled,
+            store: self.store.unwrap_or_else(|| default_store.clone()),
+            enter_room_sample,
+            sinks,
+            flush: match self.flush {
+                Some(flush) => flush.apply(default_flush),
+                None => *default_flush,
+            },
+        })
+    }
+}
+
+// sinks 的元素为 sink 名称或带 type 的内联表
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SinkEntry {
+    Name(String),
+    Table(SinkConfig),
+}
+
+fn build_sinks(entries: Vec<SinkEntry>) -> Result<Vec<SinkConfig>> {
+    let mut sinks = Vec::new();
+    for entry in entries {
+        let sink = match entry {
+            SinkEntry::Table(sink) => sink,
+            SinkEntry::Name(name) => match name.as_str() {
+                "parquet" => SinkConfig::Parquet,
+                "stdout" => SinkConfig::Stdout,
+                "jsonl" => SinkConfig::Jsonl {
+                    dir: default_jsonl_dir(),
+                },
+                "webhook" => return Err(anyhow!("webhook sink requires url")),
+                _ => return Err(anyhow!("unknown sink: {}", name)),
+            },
+        };
+        if sinks.contains(&sink) {
+            return Err(anyhow!("duplicate sink: {:?}", sink));
+        }
+        sinks.push(sink);
+    }
+    Ok(sinks)
+}
+
+// flush 为表或内联表, 未配置的项使用上一级的配置
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlushEntry {
+    max_rows: Option<NonZeroU64>,
+    max_enter_rows: Option<NonZeroU64>,
+    max_bytes: Option<NonZeroU64>,
+    max_age_secs: Option<NonZeroU64>,
+}
+
+impl FlushEntry {
+    fn apply(&self, default: &FlushConfig) -> FlushConfig {
+        let or = |value: Option<NonZeroU64>, default: u64| value.map_or(default, NonZeroU64::get);
+        FlushConfig {
+            max_rows: or(self.max_rows, default.max_rows),
+            max_enter_rows: or(self.max_enter_rows, default.max_enter_rows),
+            max_bytes: or(self.max_bytes, default.max_bytes),
+            max_age_secs: or(self.max_age_secs, default.max_age_secs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_rooms_config() {
+        let config: RoomsConfig = r#"
+            store = ["danmu", "block_user"]
+
+            [[room]]
+            id = 1
+            label = "one"
+
+            [[room]]
+            id = 2
+            enabled = false
+            store = ["super_chat", "enter_room"]
+            enter_room_sample = 0.5
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.rooms.len(), 2);
+        assert_eq!(config.rooms[0].label, "one");
+        assert!(config.rooms[0].stores(StoreKind::BlockUser));
+        assert!(!config.rooms[0].stores(StoreKind::SuperChat));
+        assert!(!config.rooms[1].enabled);
+        assert!(config.rooms[1].stores(StoreKind::EnterRoom));
+        assert_eq!(config.rooms[1].enter_room_sample, 0.5);
+        assert_eq!(config.room_ids(), vec![1]);
+        assert_eq!(config.all_room_ids(), vec![1, 2]);
+
+        // 未配置 store 时使用默认值
+        let config: RoomsConfig = "[[room]]\nid = 3".parse().unwrap();
+        assert_eq!(config.rooms[0].store, default_store());
+
+        assert!("[[room]]\nid = 1\n[[room]]\nid = 1"
+            .parse::<RoomsConfig>()
+            .is_err());
+        assert!("[[room]]\nid = \"1\"".parse::<RoomsConfig>().is_err());
+        assert!("[[room]]\nid = 1\nenter_room_sample = 2"
+            .parse::<RoomsConfig>()
+            .is_err());
+        assert!("[[room]]\nid = 1\nstore = [\"gift\"]"
+            .parse::<RoomsConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_sinks() {
+        let config: RoomsConfig = r#"
+            sinks = ["parquet", "stdout"]
+
+            [[room]]
+            id = 1
+
+            [[room]]
+            id = 2
+            sinks = [{ type = "jsonl" }, { type = "webhook", url = "http://localhost/hook" }]
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.rooms[0].sinks,
+            [SinkConfig::Parquet, SinkConfig::Stdout]
+        );
+        assert_eq!(
+            config.rooms[1].sinks,
+            [
+                SinkConfig::Jsonl {
+                    dir: "jsonl".to_string()
+                },
+                SinkConfig::Webhook {
+                    url: "http://localhost/hook".to_string()
+                }
+            ]
+        );
+        // ROOMS 新增的房间使用顶层配置
+        let config = config.with_overrides(Some("3")).unwrap();
+        assert_eq!(config.rooms[2].sinks, config.sinks);
+
+        let config: RoomsConfig = "[[room]]\nid = 3".parse().unwrap();
+        assert_eq!(config.rooms[0].sinks, default_sinks());
+
+        assert!("sinks = [\"kafka\"]".parse::<RoomsConfig>().is_err());
+        assert!("sinks = [\"webhook\"]".parse::<RoomsConfig>().is_err());
+        assert!("sinks = [\"stdout\", \"stdout\"]"
+            .parse::<RoomsConfig>()
+            .is_err());
+        assert!("sinks = [{ type = \"jsonl\", dir = 1 }]"
+            .parse::<RoomsConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_flush() {
+        let config: RoomsConfig = r#"
+            flush = { max_rows = 500, max_age_secs = 60 }
+
+            [[room]]
+            id = 1
+
+            [[room]]
+            id = 2
+            flush = { max_age_secs = 10 }
+        "#
+        .parse()
+        .unwrap();
+        let expected = FlushConfig {
+            max_rows: 500,
+            max_age_secs: 60,
+            ..FlushConfig::default()
+        };
+        assert_eq!(config.rooms[0].flush, expected);
+        // 房间未配置的项使用顶层配置
+        assert_eq!(
+            config.rooms[1].flush,
+            FlushConfig {
+                max_age_secs: 10,
+                ..expected
+            }
+        );
+        let config = config.with_overrides(Some("3")).unwrap();
+        assert_eq!(config.rooms[2].flush, expected);
+
+        let config: RoomsConfig = "[flush]\nmax_bytes = 1024\n[[room]]\nid = 3"
+            .parse()
+            .unwrap();
+        assert_eq!(config.rooms[0].flush.max_bytes, 1024);
+
+        assert!("flush = 1".parse::<RoomsConfig>().is_err());
+        assert!("flush = { max_rows = 0 }".parse::<RoomsConfig>().is_err());
+        assert!("flush = { max_rows = \"1\" }"
+            .parse::<RoomsConfig>()
+            .is_err());
+        assert!("flush = { interval = 1 }".parse::<RoomsConfig>().is_err());
+    }
+
+    #[test]
+    fn test_sample_enter() {
+        let mut room = RoomConfig::new(1);
+        assert!((0..1000).all(|uid| room.sample_enter(uid)));
+        room.enter_room_sample = 0.0;
+        assert!(!(0..1000).any(|uid| room.sample_enter(uid)));
+        room.enter_room_sample = 0.2;
+        let sampled = (0..10000).filter(|uid| room.sample_enter(*uid)).count();
+        assert!((1500..2500).contains(&sampled), "sampled {}", sampled);
+        // 同一 uid 结果稳定
+        assert_eq!(room.sample_enter(12345), room.sample_enter(12345));
+    }
+
+    #[test]
+    fn test_rooms_overrides() {
+        let config: RoomsConfig = "[[room]]\nid = 1\n[[room]]\nid = 2\nlabel = \"two\""
+            .parse()
+            .unwrap();
+        let overridden = config.clone().with_overrides(Some("2, 3")).unwrap();
+        assert_eq!(overridden.room_ids(), vec![2, 3]);
+        assert_eq!(overridden.rooms[1].label, "two");
+        assert_eq!(overridden.all_room_ids(), vec![1, 2, 3]);
+
+        assert_eq!(config.clone().with_overrides(None).unwrap(), config);
+        assert!(config.with_overrides(Some("abc")).is_err());
+    }
+}
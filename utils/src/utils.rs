@@ -1,15 +1,10 @@
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, FixedOffset, Local, TimeZone, Utc};
 use duckdb::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
-use duckdb::{Connection, DuckdbConnectionManager};
-use r2d2::PooledConnection;
+use duckdb::Connection;
 use std::env;
 use std::fmt::{Display, Formatter};
 
-pub fn get_rooms() -> Vec<i64> {
-    vec![22747736, 21533102, 23649609, 14733388]
-}
-
 #[derive(Default)]
 pub struct Pagination {
     pub limit: usize,
@@ -67,20 +62,105 @@ impl Display for MessageType {
     }
 }
 
-pub fn remote_block_user_table_name(bucket: &str) -> String {
-    format!("s3://{bucket}/block/block_user.parquet")
+// root 为 StorageLocation::root(), 如 s3://bucket 或本地目录
+pub fn remote_block_user_table_name(root: &str) -> String {
+    format!("{root}/block/block_user.parquet")
 }
 
 // 获取表名
-pub fn get_table_name(bucket: &str, room_id: i64, timestamp: i64) -> Result<String> {
+pub fn get_table_name(root: &str, room_id: i64, timestamp: i64) -> Result<String> {
+    Ok(format!(
+        "{}/{}/{}/danmu.parquet",
+        root,
+        get_format_date(timestamp)?,
+        room_id
+    ))
+}
+
+// 进房记录单独存储, 数据量大且只按 uid 查询
+pub fn get_enter_table_name(root: &str, room_id: i64, timestamp: i64) -> Result<String> {
+    Ok(format!(
+        "{}/{}/{}/enter.parquet",
+        root,
+        get_format_date(timestamp)?,
+        room_id
+    ))
+}
+
+// 连接断开期间没有采集到数据的时段, 查询时标记为数据不完整
+pub fn get_gap_table_name(root: &str, room_id: i64, timestamp: i64) -> Result<String> {
     Ok(format!(
-        "s3://{}/{}/{}/danmu.parquet",
-        bucket,
+        "{}/{}/{}/gap.parquet",
+        root,
         get_format_date(timestamp)?,
         room_id
     ))
 }
 
+// 数据缺失的时段 [start, end), reason 为连接断开的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gap {
+    pub start: i64,
+    pub end: i64,
+    pub reason: String,
+}
+
+impl Gap {
+    // 跨天的时段按本地日期切分, 每天的部分写入当天的文件
+    pub fn split_by_day(&self) -> Result<Vec<Gap>> {
+        let mut result = vec![];
+        let mut start = self.start;
+        while start < self.end {
+            let next_day = get_local_midnight(get_local_midnight(start)? + 36 * 3600)?;
+            let end = next_day.min(self.end);
+            result.push(Gap {
+                start,
+                end,
+                reason: self.reason.clone(),
+            });
+            start = end;
+        }
+        Ok(result)
+    }
+}
+
+// crawler 每次 flush 写入一个 part 文件, 收盘后由 compact 合并到 table 本身
+// 例如 danmu.parquet 的 part 为 danmu-part-{id}.parquet
+pub fn get_part_name(table: &str, id: &str) -> String {
+    format!("{}-part-{}.parquet", table.trim_end_matches(".parquet"), id)
+}
+
+pub fn get_parts_glob(table: &str) -> String {
+    format!("{}-part-*.parquet", table.trim_end_matches(".parquet"))
+}
+
+// 同时匹配合并后的文件和尚未合并的 part 文件, 查询时使用
+pub fn get_table_glob(table: &str) -> String {
+    format!("{}*.parquet", table.trim_end_matches(".parquet"))
+}
+
+// 读取 parquet 文件并按消息 id 去重, files 为 glob 或文件列表的 SQL 字面量, 如 '{glob}'
+// 多个 crawler 或重连后可能写入同一条消息, 查询, 统计和合并都读取去重后的数据
+pub fn dedup_source(conn: &Connection, files: &str) -> Result<String> {
+    // 加入 id 列之前的文件按列名合并, 缺少的 id 为 NULL
+    let source = format!("read_parquet({files}, union_by_name = true)");
+    let columns = conn
+        .prepare(&format!("DESCRIBE SELECT * FROM {source}"))?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<duckdb::Result<Vec<_>>>()?;
+    Ok(dedup_select(&source, columns.iter().any(|c| c == "id")))
+}
+
+// 没有 id 的旧数据按整行去重
+pub fn dedup_select(source: &str, has_id: bool) -> String {
+    let key = if has_id {
+        "COALESCE(t.id, t::VARCHAR)"
+    } else {
+        "t::VARCHAR"
+    };
+    format!("(SELECT DISTINCT ON ({key}) * FROM {source} t)")
+}
+
 pub fn get_format_date(timestamp: i64) -> Result<String> {
     Ok(Utc
         .timestamp_opt(timestamp, 0)
@@ -135,28 +215,10 @@ pub struct OssConfig {
     pub key: String,
     pub secret: String,
     pub bucket: String,
-}
-
-impl OssConfig {}
-
-pub fn init_oss_with_pool(
-    conn: &PooledConnection<DuckdbConnectionManager>,
-    endpoint: &str,
-    region: &str,
-    key: &str,
-    secret: &str,
-) -> Result<()> {
-    let stmt = format!(
-        "CREATE SECRET (
-                TYPE S3,
-                Endpoint '{endpoint}',
-                KEY_ID '{key}',
-                SECRET '{secret}',
-                REGION '{region}'
-            );",
-    );
-    conn.execute(&stmt, [])?;
-    Ok(())
+    // OSS_URL_STYLE 为 path 时使用 {endpoint}/{bucket} 形式的地址, 例如 MinIO, 默认为 vhost
+    pub path_style: bool,
+    // OSS_USE_SSL 为 false 时使用 http
+    pub use_ssl: bool,
 }
 
 impl OssConfig {
@@ -166,15 +228,35 @@ impl OssConfig {
         let key = env::var("OSS_KEY").map_err(|_| anyhow!("OSS_KEY must be set"))?;
         let secret = env::var("OSS_SECRET").map_err(|_| anyhow!("OSS_SECRET must be set"))?;
         let bucket = env::var("OSS_BUCKET").map_err(|_| anyhow!("OSS_BUCKET must be set"))?;
+        let path_style = match env::var("OSS_URL_STYLE").as_deref() {
+            Ok("path") => true,
+            Ok("vhost") | Err(_) => false,
+            Ok(style) => return Err(anyhow!("OSS_URL_STYLE must be vhost or path: {}", style)),
+        };
+        let use_ssl = match env::var("OSS_USE_SSL").as_deref() {
+            Ok("false") => false,
+            Ok("true") | Err(_) => true,
+            Ok(value) => return Err(anyhow!("OSS_USE_SSL must be true or false: {}", value)),
+        };
         Ok(Self {
             endpoint,
             region,
             key,
             secret,
             bucket,
+            path_style,
+            use_ssl,
         })
     }
 
+    // 不带协议的 endpoint, 和 duckdb 的 ENDPOINT 一致
+    pub fn host(&self) -> &str {
+        self.endpoint
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .trim_end_matches('/')
+    }
+
     pub fn init_oss_with_conn(self, conn: &Connection) -> Result<()> {
         let stmt = format!(
             "CREATE SECRET (
@@ -182,9 +264,16 @@ impl OssConfig {
                 Endpoint '{}',
                 KEY_ID '{}',
                 SECRET '{}',
-                REGION '{}'
+                REGION '{}',
+                URL_STYLE '{}',
+                USE_SSL {}
             );",
-            self.endpoint, self.key, self.secret, self.region,
+            self.host(),
+            self.key,
+            self.secret,
+            self.region,
+            if self.path_style { "path" } else { "vhost" },
+            self.use_ssl,
         );
         conn.execute(&stmt, [])?;
 
@@ -227,16 +316,85 @@ mod test {
         assert!(is_new_day(old_timestamp, new_timestamp).unwrap());
     }
 
+    #[test]
+    fn test_dedup_select() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE old (uid BIGINT, msg TEXT, timestamp BIGINT);
+             INSERT INTO old VALUES (1, 'a', 10), (1, 'a', 10), (1, 'b', 10);
+             CREATE TABLE new (uid BIGINT, msg TEXT, timestamp BIGINT, id TEXT);
+             INSERT INTO new VALUES (1, 'a', 10, 'x'), (1, 'a', 10, 'x'), (2, 'a', 10, 'y'),
+                 (3, 'c', 20, NULL), (3, 'c', 20, NULL), (3, 'd', 20, NULL);",
+        )
+        .unwrap();
+        let count = |source: String| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {source}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count(dedup_select("old", false)), 2);
+        assert_eq!(count(dedup_select("new", true)), 4);
+    }
+
+    #[test]
+    fn test_gap_split_by_day() {
+        // 2024-07-14 23:00:00 到 2024-07-16 01:00:00 (UTC+8)
+        let gap = Gap {
+            start: 1720969200,
+            end: 1721062800,
+            reason: "closed".to_string(),
+        };
+        let days: Vec<(i64, i64)> = gap
+            .split_by_day()
+            .unwrap()
+            .iter()
+            .map(|gap| (gap.start, gap.end))
+            .collect();
+        assert_eq!(
+            days,
+            vec![
+                (1720969200, 1720972800),
+                (1720972800, 1721059200),
+                (1721059200, 1721062800),
+            ]
+        );
+        assert_eq!(
+            get_gap_table_name("s3://bilibili", 1, 1720972800).unwrap(),
+            "s3://bilibili/2024-07-15/1/gap.parquet"
+        );
+        // 空的时段不写入
+        assert!(Gap {
+            end: gap.start,
+            ..gap
+        }
+        .split_by_day()
+        .unwrap()
+        .is_empty());
+    }
+
     #[test]
     fn test_get_table_name() {
-        let bucket_name = "bilibili";
+        let root = "s3://bilibili";
         let room_id = 123456789;
         let timestamp = 1720973747;
 
-        let table_name = get_table_name(bucket_name, room_id, timestamp).unwrap();
+        let table_name = get_table_name(root, room_id, timestamp).unwrap();
         assert_eq!(
             table_name,
             "s3://bilibili/2024-07-15/123456789/danmu.parquet"
         );
+        assert_eq!(
+            get_part_name(&table_name, "1-2"),
+            "s3://bilibili/2024-07-15/123456789/danmu-part-1-2.parquet"
+        );
+        assert_eq!(
+            get_parts_glob(&table_name),
+            "s3://bilibili/2024-07-15/123456789/danmu-part-*.parquet"
+        );
+        assert_eq!(
+            get_table_glob(&table_name),
+            "s3://bilibili/2024-07-15/123456789/danmu*.parquet"
+        );
     }
 }